@@ -0,0 +1,305 @@
+use notify::Watcher;
+
+use crate::configdb;
+use crate::server;
+use crate::http3;
+
+/// the contexts of the certificate store by listener address
+type CertificateLists = std::collections::HashMap<String, Vec<(configdb::Certificate, openssl::ssl::SslContext)>>;
+
+lazy_static::lazy_static! {
+    #[allow(non_upper_case_globals)]
+    static ref CERTIFICATE_LISTS: std::sync::Arc<std::sync::Mutex<CertificateLists>> = std::sync::Arc::new(std::sync::Mutex::new(CertificateLists::new()));
+}
+
+pub fn hostname_matches(pattern: &str, hostname: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let hostname = hostname.to_ascii_lowercase();
+
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => {
+            // a wildcard covers exactly one label, "*.example.com" does not match "example.com" nor "a.b.example.com"
+            match hostname.split_once('.') {
+                Some((label, rest)) => {
+                    return !label.is_empty() && rest == suffix;
+                },
+                None => {
+                    return false;
+                }
+            }
+        },
+        None => {
+            return pattern == hostname;
+        }
+    }
+}
+
+/// exact hostnames take precedence over wildcards
+fn select_entry<'a, Context>(entries: &'a [(configdb::Certificate, Context)], hostname: &str) -> Option<&'a (configdb::Certificate, Context)> {
+    let exact = entries.iter().find(|entry| !entry.0.hostname.starts_with("*.") && hostname_matches(&entry.0.hostname, hostname));
+
+    return exact.or_else(|| entries.iter().find(|entry| entry.0.hostname.starts_with("*.") && hostname_matches(&entry.0.hostname, hostname)));
+}
+
+fn find_entry<Hostname: AsRef<str>>(listener_config: &configdb::Listener, hostname: Hostname) -> Option<(configdb::Certificate, openssl::ssl::SslContext)> {
+    match CERTIFICATE_LISTS.lock() {
+        Ok(certificate_lists) => {
+            let certificate_list = certificate_lists.get(&server::listener_address(listener_config))?;
+            return select_entry(certificate_list, hostname.as_ref()).cloned();
+        },
+        Err(err) => {
+            eprintln!("internal error, failed to lock CERTIFICATE_LISTS, error: {}; aborting", err.to_string());
+            std::process::abort();
+        }
+    }
+}
+
+pub fn find_certificate<Hostname: AsRef<str>>(listener_config: &configdb::Listener, hostname: Hostname) -> Option<openssl::ssl::SslContext> {
+    return find_entry(listener_config, hostname).map(|certificate| certificate.1);
+}
+
+/// the certificate and key files serving the hostname, for TLS stacks that cannot use the OpenSSL contexts
+pub fn find_certificate_files<Hostname: AsRef<str>>(listener_config: &configdb::Listener, hostname: Hostname) -> Option<configdb::Certificate> {
+    return find_entry(listener_config, hostname).map(|certificate| certificate.0);
+}
+
+/// the certificates of the store, read from their .yaml files; other files such as the temporary ones written
+/// during an ACME renewal are skipped
+fn read_certificates() -> Vec<configdb::Certificate> {
+    let mut result: Vec<configdb::Certificate> = Vec::new();
+
+    match std::fs::read_dir(configdb::CERTIFICATES_DIRNAME) {
+        Ok(dir) => {
+            for file in dir.flatten() {
+                if let Some(filename) = file.file_name().to_str() {
+                    if !filename.ends_with(".yaml") {
+                        continue;
+                    }
+
+                    let filename = format!("{}/{}", configdb::CERTIFICATES_DIRNAME, filename);
+
+                    match std::fs::read_to_string(&filename) {
+                        Ok(content) => {
+                            match serde_yaml::from_str::<configdb::Certificate>(&content) {
+                                Ok(object) => {
+                                    result.push(object);
+                                },
+                                Err(err) => {
+                                    eprintln!("failed to deserialize {}, error: {}", &filename, err.to_string());
+                                }
+                            }
+                        },
+                        Err(err) => {
+                            eprintln!("failed to access {}, error: {}", &filename, err.to_string());
+                        }
+                    }
+                }
+            }
+        },
+        Err(err) => {
+            eprintln!("failed to enumerate the folder {}, error: {}; the certificate store is empty", configdb::CERTIFICATES_DIRNAME, err.to_string());
+        }
+    }
+
+    return result;
+}
+
+/// every HTTPS listener gets contexts of its own, built with its TLS policy and client certificate settings; the
+/// store is built before taking the lock so handshakes looking up a certificate are not held up meanwhile
+fn load_certificates(general_config: &configdb::General) {
+    println!("loading certificates");
+
+    let certificates = read_certificates();
+    let mut new_certificate_lists: CertificateLists = CertificateLists::new();
+
+    for listener_config in general_config.listeners.iter().filter(|listener_config| server::is_https_proxy(listener_config)) {
+        let listener_general_config = server::create_listener_config(general_config, listener_config);
+        let mut certificate_list: Vec<(configdb::Certificate, openssl::ssl::SslContext)> = Vec::new();
+
+        for certificate in certificates.iter() {
+            match server::create_ssl_context(&listener_general_config, &certificate.ssl_certificate, &certificate.ssl_certificate_key) {
+                Ok(ssl_context) => {
+                    certificate_list.push((certificate.clone(), ssl_context));
+                },
+                Err(err) => {
+                    eprintln!("failed to load the certificate for {} on {}, error: {}", &certificate.hostname, server::listener_address(listener_config), err.to_string());
+                }
+            }
+        }
+
+        new_certificate_lists.insert(server::listener_address(listener_config), certificate_list);
+    }
+
+    match CERTIFICATE_LISTS.lock() {
+        Ok(mut certificate_lists) => {
+            *certificate_lists = new_certificate_lists;
+        },
+        Err(err) => {
+            eprintln!("internal error, failed to lock CERTIFICATE_LISTS, error: {}; aborting", err.to_string());
+            std::process::abort();
+        }
+    }
+}
+
+fn certificate_files(general_config: &configdb::General) -> Vec<std::path::PathBuf> {
+    let mut result: Vec<std::path::PathBuf> = vec![
+        std::path::PathBuf::from(&general_config.ssl_certificate),
+        std::path::PathBuf::from(&general_config.ssl_certificate_key),
+    ];
+
+    for tls in general_config.listeners.iter().filter_map(|listener_config| listener_config.tls.as_ref()) {
+        for path in [&tls.ssl_certificate, &tls.ssl_certificate_key] {
+            if !path.is_empty() {
+                result.push(std::path::PathBuf::from(path));
+            }
+        }
+    }
+
+    match CERTIFICATE_LISTS.lock() {
+        Ok(certificate_lists) => {
+            for certificate in certificate_lists.values().flatten() {
+                result.push(std::path::PathBuf::from(&certificate.0.ssl_certificate));
+                result.push(std::path::PathBuf::from(&certificate.0.ssl_certificate_key));
+            }
+        },
+        Err(err) => {
+            eprintln!("internal error, failed to lock CERTIFICATE_LISTS, error: {}; aborting", err.to_string());
+            std::process::abort();
+        }
+    }
+
+    // the parent folders are watched rather than the files, renewal jobs usually replace the files instead of rewriting them
+    result.iter().filter_map(|path| {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => { parent.to_path_buf() },
+            _ => { std::path::PathBuf::from(".") }
+        };
+
+        match (std::fs::canonicalize(parent), path.file_name()) {
+            (Ok(parent), Some(filename)) => {
+                Some(parent.join(filename))
+            },
+            _ => {
+                None
+            }
+        }
+    }).collect()
+}
+
+/// watches the certificate store and every certificate file it or the general config references, the store
+/// and the SSL layer are rebuilt when one of them changes
+pub fn folder_watch(general_config: configdb::General) {
+    let (event_sender, event_receiver) = std::sync::mpsc::channel::<notify::Result<notify::Event>>();
+
+    let mut watcher = match notify::recommended_watcher(event_sender) {
+        Ok(watcher) => {
+            watcher
+        },
+        Err(err) => {
+            eprintln!("failed to monitor the folder {} for update events, error: {}; aborting", configdb::CERTIFICATES_DIRNAME, err.to_string());
+            std::process::abort();
+        }
+    };
+
+    // without the folder only the certificate files of the general config and the listeners are watched
+    let certificates_dirname = match std::fs::canonicalize(configdb::CERTIFICATES_DIRNAME) {
+        Ok(dirname) => {
+            if let Err(err) = watcher.watch(&dirname, notify::RecursiveMode::Recursive) {
+                eprintln!("failed to monitor the folder {} for update events, error: {}; aborting", configdb::CERTIFICATES_DIRNAME, err.to_string());
+                std::process::abort();
+            }
+
+            dirname
+        },
+        Err(err) => {
+            eprintln!("failed to access the folder {}, error: {}; the certificate store is not watched", configdb::CERTIFICATES_DIRNAME, err.to_string());
+            std::path::PathBuf::from(configdb::CERTIFICATES_DIRNAME)
+        }
+    };
+
+    let mut watched_folders: Vec<std::path::PathBuf> = Vec::new();
+
+    loop {
+        let files = certificate_files(&general_config);
+
+        for file in files.iter() {
+            if let Some(parent) = file.parent() {
+                if !watched_folders.iter().any(|folder| folder == parent) {
+                    if let Err(err) = watcher.watch(parent, notify::RecursiveMode::NonRecursive) {
+                        eprintln!("failed to monitor the folder {} for update events, error: {}", parent.display(), err.to_string());
+                    }
+
+                    watched_folders.push(parent.to_path_buf());
+                }
+            }
+        }
+
+        match event_receiver.recv() {
+            Ok(Ok(event)) => {
+                let relevant = event.paths.iter().any(|path| {
+                    path.starts_with(&certificates_dirname) || files.contains(path)
+                });
+
+                if !relevant {
+                    continue;
+                }
+
+                // a renewal rewrites the certificate and the key one after another, let it settle before reloading
+                std::thread::sleep(std::time::Duration::from_millis(500));
+                while event_receiver.try_recv().is_ok() { }
+
+                println!("certificate files changed, reloading the SSL layer");
+                load_certificates(&general_config);
+                http3::clear_certificate_cache();
+
+                if let Err(err) = server::reload_ssl_server(&general_config) {
+                    eprintln!("failed to reload the SSL layer, error: {}; keeping the previous certificates", err.to_string());
+                }
+            },
+            Ok(Err(err)) => {
+                eprintln!("failed to monitor the folder {} for update events, error: {}; aborting", configdb::CERTIFICATES_DIRNAME, err.to_string());
+                std::process::abort();
+            },
+            Err(err) => {
+                eprintln!("failed to monitor the folder {} for update events, error: {}; aborting", configdb::CERTIFICATES_DIRNAME, err.to_string());
+                std::process::abort();
+            }
+        }
+    }
+}
+
+pub fn initialize(general_config: &configdb::General) {
+    load_certificates(general_config);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn certificate(hostname: &str) -> (configdb::Certificate, ()) {
+        return (configdb::Certificate { hostname: hostname.to_string(), ..Default::default() }, ());
+    }
+
+    #[test]
+    fn exact_hostname_matches_case_insensitively() {
+        assert!(hostname_matches("www.example.com", "WWW.Example.com"));
+        assert!(!hostname_matches("www.example.com", "example.com"));
+    }
+
+    #[test]
+    fn wildcard_covers_a_single_label() {
+        assert!(hostname_matches("*.example.com", "a.example.com"));
+        assert!(!hostname_matches("*.example.com", "example.com"));
+        assert!(!hostname_matches("*.example.com", "a.b.example.com"));
+        assert!(!hostname_matches("*.example.com", ".example.com"));
+    }
+
+    #[test]
+    fn exact_hostname_wins_over_wildcard() {
+        let entries = vec![certificate("*.example.com"), certificate("www.example.com")];
+
+        assert_eq!(select_entry(&entries, "www.example.com").map(|entry| entry.0.hostname.as_str()), Some("www.example.com"));
+        assert_eq!(select_entry(&entries, "api.example.com").map(|entry| entry.0.hostname.as_str()), Some("*.example.com"));
+        assert!(select_entry(&entries, "example.org").is_none());
+    }
+}
@@ -1,27 +1,126 @@
+use tokio::io::AsyncWriteExt;
+
 use crate::configdb;
 use crate::edge_server;
 use crate::location_rule;
 use crate::server;
 use crate::ip_rule;
 use crate::http1;
+use crate::acme;
+use crate::client_hello;
+use crate::http2;
+use crate::response;
+use crate::websocket;
+use crate::header_policy;
+use crate::csrf;
+use crate::forwarded;
+use crate::client_ip;
+use crate::proxy_protocol;
+use crate::rewrite;
+use crate::location_response;
+
+const FORBIDDEN_RESPONSE: &[u8] = b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
 
-async fn connect_to_https_edge_server<Address: AsRef<str> + tokio::net::ToSocketAddrs + std::fmt::Display>(address: Address, resolved_name: &str) -> Result<server::TcpClient, std::io::Error> {
-    match openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls()) {
-        Ok(mut ssl_builder) => {
+fn create_edge_ssl_connector(edge_info: &configdb::Edge) -> Result<openssl::ssl::SslConnector, openssl::error::ErrorStack> {
+    let mut ssl_builder = openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls())?;
+
+    match edge_info.ssl_verify {
+        configdb::EdgeSslVerify::None => {
             ssl_builder.set_verify(openssl::ssl::SslVerifyMode::NONE); // accept self-signed certificates
+        },
+        _ => {
+            ssl_builder.set_verify(openssl::ssl::SslVerifyMode::PEER);
+
+            // a custom bundle replaces the system store rather than extending it
+            if !edge_info.ssl_ca_bundle.is_empty() {
+                let mut cert_store = openssl::x509::store::X509StoreBuilder::new()?;
+                cert_store.add_lookup(openssl::x509::store::X509Lookup::file())?.load_cert_file(&edge_info.ssl_ca_bundle, openssl::ssl::SslFiletype::PEM)?;
+                ssl_builder.set_cert_store(cert_store.build());
+            }
+        }
+    }
+
+    if edge_info.http2 {
+        ssl_builder.set_alpn_protos(b"\x02h2")?;
+    }
 
-            let ssl_connector = ssl_builder.build();
+    if !edge_info.ssl_client_certificate.is_empty() {
+        ssl_builder.set_certificate_chain_file(&edge_info.ssl_client_certificate)?;
+        ssl_builder.set_private_key_file(&edge_info.ssl_client_certificate_key, openssl::ssl::SslFiletype::PEM)?;
+        ssl_builder.check_private_key()?;
+    }
 
+    return Ok(ssl_builder.build());
+}
+
+/// compares the SHA-256 digest of the edge's SubjectPublicKeyInfo, base64 encoded as in HPKP, with the configured pins
+fn check_spki_pins(ssl: &openssl::ssl::SslRef, edge_info: &configdb::Edge) -> Result<(), std::io::Error> {
+    if edge_info.ssl_spki_pins.is_empty() {
+        return Ok(());
+    }
+
+    let spki = match ssl.peer_certificate() {
+        Some(certificate) => {
+            match certificate.public_key() {
+                Ok(public_key) => {
+                    match public_key.public_key_to_der() {
+                        Ok(spki) => { spki },
+                        Err(err) => {
+                            return Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+                        }
+                    }
+                },
+                Err(err) => {
+                    return Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+                }
+            }
+        },
+        None => {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, "the edge server did not present a certificate"));
+        }
+    };
+
+    match openssl::hash::hash(openssl::hash::MessageDigest::sha256(), &spki) {
+        Ok(digest) => {
+            let pin = openssl::base64::encode_block(&digest);
+
+            if edge_info.ssl_spki_pins.contains(&pin) {
+                return Ok(());
+            }
+
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("the public key of the edge server does not match any pin, its pin is {}", pin)));
+        },
+        Err(err) => {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+        }
+    }
+}
+
+async fn connect_to_https_edge_server<Address: AsRef<str> + tokio::net::ToSocketAddrs + std::fmt::Display>(address: Address, edge_info: &configdb::Edge, proxy_header: &[u8]) -> Result<server::TcpClient, std::io::Error> {
+    match create_edge_ssl_connector(edge_info) {
+        Ok(ssl_connector) => {
             match ssl_connector.configure() {
-                Ok(ssl_config) => {
-                    match ssl_config.into_ssl(resolved_name) {
+                Ok(mut ssl_config) => {
+                    if !matches!(edge_info.ssl_verify, configdb::EdgeSslVerify::ChainAndHostname) {
+                        ssl_config.set_verify_hostname(false);
+                    }
+
+                    match ssl_config.into_ssl(&edge_info.resolve_name) {
                         Ok(ssl) => {
                             match tokio::net::TcpStream::connect(&address).await {
-                                Ok(conn) => {
+                                Ok(mut conn) => {
+                                    // the PROXY protocol header precedes the TLS handshake
+                                    conn.write_all(proxy_header).await?;
+
                                     match tokio_openssl::SslStream::new(ssl, conn) {
                                         Ok(mut conn_ssl) => {
                                             match tokio_openssl::SslStream::connect(std::pin::Pin::new(&mut conn_ssl)).await {
                                                 Ok(_) => {
+                                                    if let Err(err) = check_spki_pins(conn_ssl.ssl(), edge_info) {
+                                                        eprintln!("SSL error from {}, error: {}", address, err.to_string());
+                                                        return Err(err);
+                                                    }
+
                                                     return Ok(server::TcpClient::Https(conn_ssl));
                                                 },
                                                 Err(err) => {
@@ -61,9 +160,10 @@ async fn connect_to_https_edge_server<Address: AsRef<str> + tokio::net::ToSocket
     };
 }
 
-async fn connect_to_http_edge_server<Address: AsRef<str> + tokio::net::ToSocketAddrs>(address: Address) -> Result<server::TcpClient, std::io::Error> {
+async fn connect_to_http_edge_server<Address: AsRef<str> + tokio::net::ToSocketAddrs>(address: Address, proxy_header: &[u8]) -> Result<server::TcpClient, std::io::Error> {
     match tokio::net::TcpStream::connect(address).await {
-        Ok(conn) => {
+        Ok(mut conn) => {
+            conn.write_all(proxy_header).await?;
             return Ok(server::TcpClient::Http(conn));
         },
         Err(err) => {
@@ -72,35 +172,129 @@ async fn connect_to_http_edge_server<Address: AsRef<str> + tokio::net::ToSocketA
     };
 }
 
-async fn procedure(mut conn: server::TcpClient, connaddr: String, edge_info: &configdb::Edge, ip_rule: &Option<configdb::IpRule>, general_config: &configdb::General) {
-    let edgeaddr = format!("{}:{}", edge_info.destination, edge_info.destination_port);
+/// applies the IP and location rules to a parsed request, whatever protocol carried it; None when the request
+/// must be dropped, otherwise whether the location rule bypasses the protection
+pub fn evaluate_request(request: &http1::Http, connaddr: &str, client_identity: &Option<server::ClientIdentity>, tls_fingerprint: &Option<client_hello::TlsFingerprint>, ip_rule: &Option<configdb::IpRule>, general_config: &configdb::General) -> Option<bool> {
+    let mut bypass = false;
 
-    let mut edge_conn = match edge_info.https {
-        true => {
-            let result = match connect_to_https_edge_server(&edgeaddr, &edge_info.resolve_name).await {
-                Ok(edge_conn) => {
-                    edge_conn
-                },
-                Err(err) => {
-                    eprintln!("failed to connect to edge server {}, error: {}", &edgeaddr, err.to_string());
-                    return;
+    if let Some(ip_rule) = ip_rule {
+        if ip_rule.blacklisted_locations.contains(&request.location) {
+            println!("dropping connection with {}, blocked by rule", connaddr);
+            return None;
+        }
+
+        if !ip_rule.whitelist_location.contains(&request.location) {
+            println!("dropping connection with {}, blocked by rule", connaddr);
+            return None;
+        }
+    }
+
+    if let Some(location_rule) = location_rule::get_location_rule(&request.method, &request.location) {
+        if location_rule.bypass == true {
+            bypass = true;
+        }
+
+        if !location_rule::is_client_identity_allowed(&location_rule, client_identity) {
+            println!("dropping connection with {}, client certificate rejected by rule", connaddr);
+            return None;
+        }
+
+        if !client_hello::is_fingerprint_allowed(&location_rule.allowed_tls_fingerprints, &location_rule.denied_tls_fingerprints, tls_fingerprint) {
+            println!("dropping connection with {}, TLS fingerprint rejected by rule", connaddr);
+            return None;
+        }
+
+        match location_rule.ingress {
+            configdb::RuleGress::GenericRule => {
+                if matches!(general_config.ingress, configdb::GenericRuleGress::Deny) {
+                    println!("dropping connection with {}, blocked by rule", connaddr);
+                    return None;
                 }
-            };
+            },
+            configdb::RuleGress::Deny => {
+                println!("dropping connection with {}, blocked by rule", connaddr);
+                return None;
+            },
+            _ => {}
+        }
+    }
+
+    if let Some(cookie_name) = header_policy::find_tampered_cookie(request) {
+        println!("dropping connection with {}, cookie {} failed the verification", connaddr, cookie_name);
+        return None;
+    }
+
+    return Some(bypass);
+}
+
+/// the address of the client behind trusted proxies and the IP rule of that address, None when the rule or the
+/// limit rate drops the request
+pub fn resolve_client(request: &http1::Http, connaddr: &str, peer_ip: std::net::IpAddr, ip_rule: &Option<configdb::IpRule>, general_config: &configdb::General) -> Option<(std::net::IpAddr, Option<configdb::IpRule>)> {
+    let client_ip = client_ip::resolve_client_ip(request, peer_ip, general_config);
+
+    let client_ip_rule = match client_ip == peer_ip {
+        true => { ip_rule.clone() },
+        false => {
+            let client_ip_rule = ip_rule::get_ip_rule(client_ip.to_string());
+
+            // the TLS fingerprint is the proxy's, only the client's address is known
+            if !evaluate_connection(&format!("{} (client {})", connaddr, client_ip), &client_ip_rule, &None, general_config) {
+                return None;
+            }
+
+            client_ip_rule
+        }
+    };
+
+    if ip_rule::exceeds_limit_rate(&client_ip_rule, client_ip) {
+        println!("dropping connection with {}, client {} exceeded its limit rate", connaddr, client_ip);
+        return None;
+    }
+
+    return Some((client_ip, client_ip_rule));
+}
+
+/// opens a connection to the edge server, through TLS when the edge expects it
+pub async fn connect_to_edge_server(edge_info: &configdb::Edge) -> Result<server::TcpClient, std::io::Error> {
+    return connect_to_edge_server_with_header(edge_info, &[]).await;
+}
+
+async fn connect_to_edge_server_with_header(edge_info: &configdb::Edge, proxy_header: &[u8]) -> Result<server::TcpClient, std::io::Error> {
+    let edgeaddr = format!("{}:{}", edge_info.destination, edge_info.destination_port);
 
-            result
+    match edge_info.https {
+        true => {
+            return connect_to_https_edge_server(&edgeaddr, edge_info, proxy_header).await;
         },
         false => {
-            let result = match connect_to_http_edge_server(&edgeaddr).await {
-                Ok(edge_conn) => {
-                    edge_conn
-                },
-                Err(err) => {
-                    eprintln!("failed to connect to edge server {}, error: {}", &edgeaddr, err.to_string());
-                    return;
-                }
-            };
+            return connect_to_http_edge_server(&edgeaddr, proxy_header).await;
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments, reason = "the connection, its client and its listener are all needed to relay the requests")]
+async fn procedure(mut conn: server::TcpClient, connaddr: std::net::SocketAddr, client_identity: &Option<server::ClientIdentity>, tls_fingerprint: &Option<client_hello::TlsFingerprint>, edge_info: &configdb::Edge, ip_rule: &Option<configdb::IpRule>, listener_config: &configdb::Listener, general_config: &configdb::General) {
+    let client_ip = connaddr.ip();
+    let edgeaddr = format!("{}:{}", edge_info.destination, edge_info.destination_port);
 
-            result
+    // the edge server is told the address the client connected to, the listener's
+    let proxy_header = match conn.local_addr() {
+        Ok(local_addr) => { proxy_protocol::create_header(edge_info.proxy_protocol, connaddr, local_addr) },
+        Err(err) => {
+            eprintln!("failed to get the local address of the connection with {}, error: {}", connaddr, err.to_string());
+            return;
+        }
+    };
+
+    let connaddr = connaddr.to_string();
+
+    let mut edge_conn = match connect_to_edge_server_with_header(edge_info, &proxy_header).await {
+        Ok(edge_conn) => {
+            edge_conn
+        },
+        Err(err) => {
+            eprintln!("failed to connect to edge server {}, error: {}", &edgeaddr, err.to_string());
+            return;
         }
     };
 
@@ -111,8 +305,11 @@ async fn procedure(mut conn: server::TcpClient, connaddr: String, edge_info: &co
     let mut conn_request_idx: usize = 0;
     let mut conn_request_body_state = false;
     let mut conn_request_bypass = false;
+    let mut conn_request_form: Option<csrf::PendingForm> = None;
     const CONN_REQUEST_STORAGE_HARD_LIMIT: usize = 128 * 1024;
 
+    let mut response_inspector = response::ResponseInspector::new(&connaddr, listener_config, general_config);
+
     loop {
         tokio::select! {
             conn_read = conn.read(&mut conn_mtu_block) => {
@@ -122,94 +319,222 @@ async fn procedure(mut conn: server::TcpClient, connaddr: String, edge_info: &co
                         return;
                     },
                     Ok(len) => {
-                        if conn_request_body_state == false {
-                            if conn_request_storage.len() + len > CONN_REQUEST_STORAGE_HARD_LIMIT {
+                        // a block may end a request body and start the next request, it is split at the boundaries
+                        let mut conn_block: Vec<u8> = Vec::new();
+                        let mut conn_pending = conn_mtu_block[..len].to_vec();
+
+                        while !conn_pending.is_empty() {
+                            if conn_request_body_state {
+                                let body_length = (conn_request_body_size - conn_request_idx).min(conn_pending.len());
+
+                                match &mut conn_request_form {
+                                    Some(form) => {
+                                        form.body.extend(conn_pending.drain(..body_length));
+                                    },
+                                    None => {
+                                        conn_block.extend(conn_pending.drain(..body_length));
+                                    }
+                                }
+
+                                conn_request_idx = conn_request_idx + body_length;
+
+                                if conn_request_bypass {
+                                    // TODO
+                                } else {
+                                    // TODO
+                                }
+
+                                if conn_request_idx >= conn_request_body_size {
+                                    conn_request_body_state = false;
+                                    conn_request_body_size = 0;
+                                    conn_request_idx = 0;
+                                    conn_request_bypass = false;
+
+                                    // the form was held back until its whole body could be searched for the token
+                                    if let Some(form) = conn_request_form.take() {
+                                        if !form.has_token() {
+                                            println!("dropping connection with {}, CSRF check failed: the form token is missing or wrong", &connaddr);
+                                            let _ = conn.write_all(FORBIDDEN_RESPONSE).await;
+                                            return;
+                                        }
+
+                                        conn_block.extend(form.head);
+                                        conn_block.extend(form.body);
+                                    }
+                                }
+
+                                continue;
+                            }
+
+                            if conn_request_storage.len() + conn_pending.len() > CONN_REQUEST_STORAGE_HARD_LIMIT {
                                 println!("hard limit on request header reached, dropping connection with {}", &connaddr);
                                 return;
-                            } 
+                            }
 
-                            conn_request_storage.append(&mut conn_mtu_block[..len].to_vec());
+                            conn_request_storage.append(&mut conn_pending);
 
-                            let header_length = conn_request_storage.windows(4).position(|a| a == b"\r\n\r\n");
+                            let header_length = match conn_request_storage.windows(4).position(|a| a == b"\r\n\r\n") {
+                                Some(header_length) => { header_length },
+                                None => { break; }
+                            };
 
-                            if let Some(header_length) = header_length { 
-                                match http1::parse(conn_request_storage[..header_length].to_vec()) {                                    
-                                    Ok(object) => {
-                                        if let Some(content_length) = object.properties.get("Content-Length") {
-                                            match content_length.parse() {
-                                                Ok(content_length) => {
-                                                    conn_request_body_state = true;
-                                                    conn_request_body_size = content_length;
+                            let identity_encoding;
+                            let cookie_policy;
+                            let forwarded_headers;
+                            let request_rewrite;
+                            let mut form: Option<csrf::PendingForm> = None;
 
-                                                    if (header_length + 4) < len {
-                                                        conn_request_idx = len - (header_length + 4);
-                                                    }
-                                                },
-                                                Err(err) => {
-                                                    eprintln!("corrupted request from client {}, error: {}; dropping the connection", &connaddr, err.to_string());
-                                                    return;
-                                                }
-                                            }
+                            match http1::parse(conn_request_storage[..header_length].to_vec()) {
+                                Ok(object) => {
+                                    if let Some((token, response)) = acme::create_http01_response(&object.location) {
+                                        println!("answering the ACME challenge {} for {}", token, &connaddr);
+
+                                        if let Err(err) = conn.write_all(&response).await {
+                                            eprintln!("failed to answer the ACME challenge for {}, error: {}", &connaddr, err.to_string());
                                         }
 
-                                        if let Some(ip_rule) = ip_rule {
-                                            if ip_rule.blacklisted_locations.contains(&object.location) {
-                                                println!("dropping connection with {}, blocked by rule", &connaddr);
-                                                return;
-                                            }
+                                        return;
+                                    }
 
-                                            if !ip_rule.whitelist_location.contains(&object.location) {
-                                                println!("dropping connection with {}, blocked by rule", &connaddr);
+                                    if let Some(content_length) = http1::find_property(&object.properties, "content-length") {
+                                        match content_length.parse() {
+                                            Ok(content_length) => {
+                                                conn_request_body_state = content_length > 0;
+                                                conn_request_body_size = content_length;
+                                                conn_request_idx = 0;
+                                            },
+                                            Err(err) => {
+                                                eprintln!("corrupted request from client {}, error: {}; dropping the connection", &connaddr, err.to_string());
                                                 return;
                                             }
                                         }
+                                    }
+
+                                    let (request_client_ip, request_ip_rule) = match resolve_client(&object, &connaddr, client_ip, ip_rule, general_config) {
+                                        Some(client) => { client },
+                                        None => {
+                                            return;
+                                        }
+                                    };
+
+                                    match evaluate_request(&object, &connaddr, client_identity, tls_fingerprint, &request_ip_rule, general_config) {
+                                        Some(bypass) => {
+                                            conn_request_bypass = bypass;
+                                        },
+                                        None => {
+                                            return;
+                                        }
+                                    }
+
+                                    // redirects and static pages of the location rule are answered without the edge server
+                                    if let Some(location_response) = location_response::get_location_response(&object, matches!(conn, server::TcpClient::Https(_))) {
+                                        println!("{} {} {} answered with {} by the location rule", &connaddr, &object.method, &object.location, location_response.response.status);
+
+                                        if let Err(err) = conn.write_all(&location_response::to_http1(&location_response, &object)).await {
+                                            eprintln!("failed to write to {}, error: {}", &connaddr, err.to_string());
+                                        }
+
+                                        return;
+                                    }
+
+                                    forwarded_headers = forwarded::create_forwarded_headers(&object, &request_client_ip, matches!(conn, server::TcpClient::Https(_)), general_config);
+                                    request_rewrite = rewrite::get_request_rewrite(&object);
+
+                                    // the connection leaves HTTP behind once upgraded, its frames are inspected from now on
+                                    if websocket::is_upgrade(&object) {
+                                        match websocket::evaluate_upgrade(&object, &connaddr, general_config) {
+                                            Some(policy) => {
+                                                let client_pending = conn_request_storage[header_length + 4..].to_vec();
+                                                let mut request_head = conn_request_storage[..header_length + 4].to_vec();
+                                                if let Some(request_rewrite) = &request_rewrite {
+                                                    request_head = rewrite::rewrite_request_head(&request_head, request_rewrite);
+                                                }
+
+                                                request_head = forwarded::rewrite_request_head(&request_head, &forwarded_headers);
+
+                                                // the upgrade carries the session cookies too, the edge server gets them restored
+                                                if let Some(cookie_policy) = &header_policy::get_response_policy(&object).cookie_policy {
+                                                    request_head = header_policy::restore_request_cookies(&request_head, cookie_policy);
+                                                }
 
-                                        if let Some(location_rule) = location_rule::get_location_rule(object.method, object.location) {
-                                            if location_rule.bypass == true { 
-                                                conn_request_bypass = true;
+                                                websocket::handler(conn, edge_conn, &request_head, client_pending, policy, &connaddr, &edgeaddr).await;
+                                            },
+                                            None => {
+                                                let _ = conn.write_all(FORBIDDEN_RESPONSE).await;
                                             }
+                                        }
 
-                                            match location_rule.ingress {
-                                                configdb::RuleGress::GenericRule => {
-                                                    if matches!(general_config.ingress, configdb::GenericRuleGress::Deny) {
-                                                        println!("dropping connection with {}, blocked by rule", &connaddr);
-                                                        return;
-                                                    }
-                                                },
-                                                configdb::RuleGress::Deny => {
-                                                    println!("dropping connection with {}, blocked by rule", &connaddr);
+                                        return;
+                                    }
+
+                                    let response_policy = header_policy::get_response_policy(&object);
+
+                                    if let Some(csrf_policy) = &response_policy.csrf {
+                                        match csrf::check_request(&object, csrf_policy) {
+                                            csrf::Verdict::Allowed => {},
+                                            csrf::Verdict::Denied(reason) => {
+                                                println!("dropping connection with {}, CSRF check failed: {}", &connaddr, reason);
+                                                let _ = conn.write_all(FORBIDDEN_RESPONSE).await;
+                                                return;
+                                            },
+                                            csrf::Verdict::FormToken(token) => {
+                                                if conn_request_body_size == 0 || conn_request_body_size > csrf::FORM_BODY_HARD_LIMIT {
+                                                    println!("dropping connection with {}, CSRF check failed: no token header and no form body to look for it", &connaddr);
+                                                    let _ = conn.write_all(FORBIDDEN_RESPONSE).await;
                                                     return;
-                                                },
-                                                _ => {}
+                                                }
+
+                                                form = Some(csrf::PendingForm::new(Vec::new(), csrf_policy, token));
                                             }
                                         }
-                                    },
-                                    Err(err) => {
-                                        eprintln!("processing the request from {} failed, error: {}", &connaddr, err.to_string());
-                                        return;
                                     }
+
+                                    cookie_policy = response_policy.cookie_policy.clone();
+                                    identity_encoding = response_inspector.push_request(&object, response_policy);
+                                },
+                                Err(err) => {
+                                    eprintln!("processing the request from {} failed, error: {}", &connaddr, err.to_string());
+                                    return;
                                 }
+                            }
+
+                            // the head goes to the edge server only once it has been evaluated as a whole
+                            let mut request_head: Vec<u8> = conn_request_storage.drain(..header_length + 4).collect();
+
+                            if let Some(request_rewrite) = &request_rewrite {
+                                request_head = rewrite::rewrite_request_head(&request_head, request_rewrite);
+                            }
+
+                            // the edge server learns who the client is from the WAF, never from the client itself
+                            request_head = forwarded::rewrite_request_head(&request_head, &forwarded_headers);
 
-                                conn_request_storage.clear();
+                            // cookies renamed on their way to the client are renamed back for the edge server
+                            if let Some(cookie_policy) = &cookie_policy {
+                                request_head = header_policy::restore_request_cookies(&request_head, cookie_policy);
                             }
-                        } else {
-                            conn_request_idx = conn_request_idx + len;
 
-                            if conn_request_bypass {
-                                // TODO
-                            } else {
-                                // TODO
+                            // the response body is scanned, it must not come back compressed
+                            if identity_encoding {
+                                request_head = http1::remove_header(&request_head, "accept-encoding");
                             }
 
-                            if conn_request_idx >= conn_request_body_size {
-                                conn_request_body_state = false;
-                                conn_request_body_size = 0;
-                                conn_request_idx = 0;
-                                conn_request_bypass = false;
+                            match form {
+                                Some(mut form) => {
+                                    form.head = request_head;
+                                    conn_request_form = Some(form);
+                                },
+                                None => {
+                                    conn_block.extend(request_head);
+                                }
                             }
+                            conn_pending = std::mem::take(&mut conn_request_storage);
                         }
 
-                        match edge_conn.write(&conn_mtu_block[..len]).await {
+                        if conn_block.is_empty() {
+                            continue;
+                        }
+
+                        match edge_conn.write_all(&conn_block).await {
                             Ok(_) => { },
                             Err(err) => {
                                 eprintln!("failed to move data from client {} to edge server {}, error: {}; closing the connection", &connaddr, &edgeaddr, err.to_string());
@@ -226,11 +551,31 @@ async fn procedure(mut conn: server::TcpClient, connaddr: String, edge_info: &co
             edge_read = edge_conn.read(&mut edge_mtu_block) => {
                 match edge_read {
                     Ok(0) => {
+                        let edge_block = response_inspector.finish();
+                        if !edge_block.is_empty() {
+                            if let Err(err) = conn.write_all(&edge_block).await {
+                                eprintln!("failed to move data from edge {} to client {}, error: {}; closing the connection", &edgeaddr, &connaddr, err.to_string());
+                                return;
+                            }
+                        }
+
                         println!("edge {} closed the connection", &edgeaddr);
                         return;
                     },
                     Ok(len) => {
-                        match conn.write(&edge_mtu_block[..len]).await {
+                        let edge_block = match response_inspector.process(&edge_mtu_block[..len]) {
+                            Ok(edge_block) => { edge_block },
+                            Err(err) => {
+                                eprintln!("corrupted response from edge server {}, error: {}; closing the connection", &edgeaddr, err.to_string());
+                                return;
+                            }
+                        };
+
+                        if edge_block.is_empty() {
+                            continue;
+                        }
+
+                        match conn.write_all(&edge_block).await {
                             Ok(_) => { },
                             Err(err) => {
                                 eprintln!("failed to move data from edge {} to client {}, error: {}; closing the connection", &edgeaddr, &connaddr, err.to_string());
@@ -248,30 +593,59 @@ async fn procedure(mut conn: server::TcpClient, connaddr: String, edge_info: &co
     }
 }
 
-pub async fn handler(conn: server::TcpClient, connaddr: std::net::SocketAddr, general_config: configdb::General) {
-    let connaddr_friendly = connaddr.to_string();
-    let ip_rule = ip_rule::get_ip_rule(connaddr.ip().to_string());
-
-    if let Some(ip_rule) = ip_rule.clone() {
+/// applies the IP rule of the client and the generic ingress policy to a new connection, whatever listener accepted it
+pub fn evaluate_connection(connaddr_friendly: &str, ip_rule: &Option<configdb::IpRule>, tls_fingerprint: &Option<client_hello::TlsFingerprint>, general_config: &configdb::General) -> bool {
+    if let Some(ip_rule) = ip_rule {
         if matches!(ip_rule.ingress, configdb::RuleGress::Deny) {
             println!("dropping connection with {connaddr_friendly}, blocked by rule");
-            return;
+            return false;
         }
-        
+
         if matches!(general_config.ingress, configdb::GenericRuleGress::Deny) && !matches!(ip_rule.ingress, configdb::RuleGress::Allow) {
             println!("dropping connection with {connaddr_friendly}, blocked by rule");
-            return;
+            return false;
+        }
+
+        if !client_hello::is_fingerprint_allowed(&ip_rule.allowed_tls_fingerprints, &ip_rule.denied_tls_fingerprints, tls_fingerprint) {
+            println!("dropping connection with {connaddr_friendly}, TLS fingerprint rejected by rule");
+            return false;
         }
     } else if matches!(general_config.ingress, configdb::GenericRuleGress::Deny) {
         println!("dropping connection with {connaddr_friendly}, blocked by rule");
+        return false;
+    }
+
+    return true;
+}
+
+pub async fn handler(conn: server::TcpClient, connaddr: std::net::SocketAddr, client_identity: Option<server::ClientIdentity>, tls_fingerprint: Option<client_hello::TlsFingerprint>, listener_config: configdb::Listener, general_config: configdb::General) {
+    let connaddr_friendly = connaddr.to_string();
+    let ip_rule = ip_rule::get_ip_rule(connaddr.ip().to_string());
+
+    if !evaluate_connection(&connaddr_friendly, &ip_rule, &tls_fingerprint, &general_config) {
         return;
     }
 
     println!("new connection {connaddr_friendly}");
 
-    match edge_server::find_edge_server() {
+    if let Some(tls_fingerprint) = &tls_fingerprint {
+        println!("client {} TLS fingerprint ja3 {}, ja4 {}", connaddr_friendly, tls_fingerprint.ja3, tls_fingerprint.ja4);
+    }
+
+    if let Some(client_identity) = &client_identity {
+        println!("client {} presented the certificate '{}', alternative names {:?}, fingerprint {}", connaddr_friendly, client_identity.subject, client_identity.sans, client_identity.fingerprint);
+    }
+
+    // every HTTP/2 stream picks its own edge server
+    if http2::is_http2(&conn, &general_config).await {
+        http2::handler(conn, connaddr, client_identity, tls_fingerprint, ip_rule, listener_config, general_config).await;
+        println!("the connection with {}, closed", connaddr_friendly.clone());
+        return;
+    }
+
+    match edge_server::find_edge_server(false, &listener_config.edges) {
         Some(edge_info) => {
-            procedure(conn, connaddr_friendly.clone(), &edge_info, &ip_rule, &general_config).await;
+            procedure(conn, connaddr, &client_identity, &tls_fingerprint, &edge_info, &ip_rule, &listener_config, &general_config).await;
             edge_server::decrement_conn_count(edge_info.destination);
             println!("the connection with {}, closed", connaddr_friendly.clone());
         },
@@ -0,0 +1,484 @@
+const TLS_RECORD_HEADER_LENGTH: usize = 5;
+const TLS_RECORD_HARD_LIMIT: usize = 16384 + TLS_RECORD_HEADER_LENGTH;
+const TLS_CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const TLS_HANDSHAKE_CLIENT_HELLO: u8 = 1;
+/// a client that connects and says nothing gets no fingerprint, its handshake is left to fail on its own
+const CLIENT_HELLO_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+const TLS_EXTENSION_SERVER_NAME: u16 = 0;
+const TLS_EXTENSION_SUPPORTED_GROUPS: u16 = 10;
+const TLS_EXTENSION_EC_POINT_FORMATS: u16 = 11;
+const TLS_EXTENSION_SIGNATURE_ALGORITHMS: u16 = 13;
+const TLS_EXTENSION_ALPN: u16 = 16;
+const TLS_EXTENSION_SUPPORTED_VERSIONS: u16 = 43;
+
+#[derive(Clone, Default, Debug)]
+pub struct ClientHello {
+    pub servername: Option<String>,
+    pub alpn_protocols: Vec<Vec<u8>>,
+    pub version: u16,
+    pub cipher_suites: Vec<u16>,
+    pub extensions: Vec<u16>,
+    pub supported_groups: Vec<u16>,
+    pub ec_point_formats: Vec<u8>,
+    pub signature_algorithms: Vec<u16>,
+    pub supported_versions: Vec<u16>,
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct TlsFingerprint {
+    pub ja3: String,
+    pub ja4: String,
+}
+
+struct Reader<'a> {
+    block: &'a [u8],
+    idx: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.idx + len > self.block.len() {
+            return None;
+        }
+
+        let result = &self.block[self.idx..self.idx + len];
+        self.idx += len;
+
+        return Some(result);
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        return self.bytes(1).map(|value| value[0]);
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        return self.bytes(2).map(|value| u16::from_be_bytes([value[0], value[1]]));
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        return self.bytes(3).map(|value| ((value[0] as usize) << 16) | ((value[1] as usize) << 8) | (value[2] as usize));
+    }
+
+    fn vector_u8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()?;
+        return self.bytes(len as usize);
+    }
+
+    fn vector_u16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()?;
+        return self.bytes(len as usize);
+    }
+
+    fn is_empty(&self) -> bool {
+        return self.idx >= self.block.len();
+    }
+
+    fn list_u16(&mut self) -> Option<Vec<u16>> {
+        let mut result: Vec<u16> = Vec::new();
+
+        while !self.is_empty() {
+            result.push(self.u16()?);
+        }
+
+        return Some(result);
+    }
+}
+
+/// GREASE values (RFC 8701) are random per client and left out of the fingerprints
+fn is_grease(value: u16) -> bool {
+    return (value & 0x0f0f) == 0x0a0a && (value >> 8) == (value & 0xff);
+}
+
+fn join_decimal(values: &[u16]) -> String {
+    return values.iter().filter(|value| !is_grease(**value)).map(|value| value.to_string()).collect::<Vec<String>>().join("-");
+}
+
+fn join_hex(values: &[u16]) -> String {
+    return values.iter().map(|value| format!("{:04x}", value)).collect::<Vec<String>>().join(",");
+}
+
+/// the first 12 hex digits of the sha256 of the input, as truncated by JA4
+fn truncated_sha256(input: &str) -> String {
+    if input.is_empty() {
+        return String::from("000000000000");
+    }
+
+    match openssl::hash::hash(openssl::hash::MessageDigest::sha256(), input.as_bytes()) {
+        Ok(digest) => {
+            return digest.iter().take(6).map(|byte| format!("{:02x}", byte)).collect::<String>();
+        },
+        Err(_) => {
+            return String::from("000000000000");
+        }
+    }
+}
+
+impl ClientHello {
+    pub fn offers_alpn_protocol(&self, protocol: &[u8]) -> bool {
+        return self.alpn_protocols.iter().any(|offered| offered.as_slice() == protocol);
+    }
+
+    /// md5 of "version,ciphers,extensions,groups,point formats" in the order offered by the client
+    pub fn ja3(&self) -> String {
+        let ja3_string = format!("{},{},{},{},{}",
+            self.version,
+            join_decimal(&self.cipher_suites),
+            join_decimal(&self.extensions),
+            join_decimal(&self.supported_groups),
+            self.ec_point_formats.iter().map(|value| value.to_string()).collect::<Vec<String>>().join("-"));
+
+        match openssl::hash::hash(openssl::hash::MessageDigest::md5(), ja3_string.as_bytes()) {
+            Ok(digest) => {
+                return digest.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+            },
+            Err(_) => {
+                return String::new();
+            }
+        }
+    }
+
+    /// "t13d1516h2_8daaf6152771_e5627efa2ab1", the cipher and extension lists are sorted so the reordering
+    /// done by recent browsers does not change the fingerprint
+    pub fn ja4(&self) -> String {
+        let version = self.supported_versions.iter().copied().filter(|value| !is_grease(*value)).max().unwrap_or(self.version);
+        let version = match version {
+            0x0304 => { "13" },
+            0x0303 => { "12" },
+            0x0302 => { "11" },
+            0x0301 => { "10" },
+            0x0300 => { "s3" },
+            _ => { "00" }
+        };
+
+        let servername = match self.servername {
+            Some(_) => { 'd' },
+            None => { 'i' }
+        };
+
+        let mut cipher_suites: Vec<u16> = self.cipher_suites.iter().copied().filter(|value| !is_grease(*value)).collect();
+        let mut extensions: Vec<u16> = self.extensions.iter().copied().filter(|value| !is_grease(*value)).collect();
+
+        let alpn = match self.alpn_protocols.first() {
+            Some(protocol) if !protocol.is_empty() => {
+                let first = protocol[0];
+                let last = protocol[protocol.len() - 1];
+
+                if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+                    format!("{}{}", first as char, last as char)
+                } else {
+                    let first = format!("{:02x}", first);
+                    let last = format!("{:02x}", last);
+                    format!("{}{}", &first[..1], &last[1..])
+                }
+            },
+            _ => { String::from("00") }
+        };
+
+        let prefix = format!("t{}{}{:02}{:02}{}", version, servername, cipher_suites.len().min(99), extensions.len().min(99), alpn);
+
+        cipher_suites.sort();
+        extensions.retain(|value| *value != TLS_EXTENSION_SERVER_NAME && *value != TLS_EXTENSION_ALPN);
+        extensions.sort();
+
+        let mut extensions_string = join_hex(&extensions);
+        if !extensions_string.is_empty() && !self.signature_algorithms.is_empty() {
+            extensions_string = format!("{}_{}", extensions_string, join_hex(&self.signature_algorithms));
+        }
+
+        return format!("{}_{}_{}", prefix, truncated_sha256(&join_hex(&cipher_suites)), truncated_sha256(&extensions_string));
+    }
+
+    pub fn fingerprint(&self) -> TlsFingerprint {
+        return TlsFingerprint { ja3: self.ja3(), ja4: self.ja4() };
+    }
+}
+
+impl TlsFingerprint {
+    /// a rule entry names either the JA3 or the JA4 fingerprint
+    pub fn matches(&self, value: &str) -> bool {
+        return value.eq_ignore_ascii_case(&self.ja3) || value.eq_ignore_ascii_case(&self.ja4);
+    }
+}
+
+/// a client is refused when its fingerprint is denied, or when an allow-list exists and does not name it
+pub fn is_fingerprint_allowed(allowed: &[String], denied: &[String], fingerprint: &Option<TlsFingerprint>) -> bool {
+    match fingerprint {
+        Some(fingerprint) => {
+            if denied.iter().any(|value| fingerprint.matches(value)) {
+                return false;
+            }
+
+            return allowed.is_empty() || allowed.iter().any(|value| fingerprint.matches(value));
+        },
+        None => {
+            return allowed.is_empty();
+        }
+    }
+}
+
+fn parse_extension(result: &mut ClientHello, extension_type: u16, extension: &[u8]) -> Option<()> {
+    let mut reader = Reader { block: extension, idx: 0 };
+
+    match extension_type {
+        TLS_EXTENSION_SERVER_NAME => {
+            let mut names = Reader { block: reader.vector_u16()?, idx: 0 };
+
+            while !names.is_empty() {
+                let name_type = names.u8()?;
+                let name = names.vector_u16()?;
+
+                if name_type == 0 {
+                    result.servername = Some(String::from_utf8_lossy(name).to_string());
+                }
+            }
+        },
+        TLS_EXTENSION_ALPN => {
+            let mut protocols = Reader { block: reader.vector_u16()?, idx: 0 };
+
+            while !protocols.is_empty() {
+                result.alpn_protocols.push(protocols.vector_u8()?.to_vec());
+            }
+        },
+        TLS_EXTENSION_SUPPORTED_GROUPS => {
+            result.supported_groups = Reader { block: reader.vector_u16()?, idx: 0 }.list_u16()?;
+        },
+        TLS_EXTENSION_EC_POINT_FORMATS => {
+            result.ec_point_formats = reader.vector_u8()?.to_vec();
+        },
+        TLS_EXTENSION_SIGNATURE_ALGORITHMS => {
+            result.signature_algorithms = Reader { block: reader.vector_u16()?, idx: 0 }.list_u16()?;
+        },
+        TLS_EXTENSION_SUPPORTED_VERSIONS => {
+            result.supported_versions = Reader { block: reader.vector_u8()?, idx: 0 }.list_u16()?;
+        },
+        _ => {}
+    }
+
+    return Some(());
+}
+
+/// parses the ClientHello carried by the first TLS record sent by a client
+pub fn parse(record: &[u8]) -> Option<ClientHello> {
+    let mut reader = Reader { block: record, idx: 0 };
+    let mut result = ClientHello::default();
+
+    if reader.u8()? != TLS_CONTENT_TYPE_HANDSHAKE {
+        return None;
+    }
+
+    reader.u16()?; // record version
+    let mut handshake = Reader { block: reader.vector_u16()?, idx: 0 };
+
+    if handshake.u8()? != TLS_HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+
+    let handshake_length = handshake.u24()?;
+    // a ClientHello fragmented over several records is parsed as far as the first record goes
+    let mut hello = Reader { block: handshake.bytes(handshake_length.min(handshake.block.len() - handshake.idx))?, idx: 0 };
+
+    result.version = hello.u16()?;
+    hello.bytes(32)?; // random
+    hello.vector_u8()?; // session id
+    result.cipher_suites = Reader { block: hello.vector_u16()?, idx: 0 }.list_u16()?;
+    hello.vector_u8()?; // compression methods
+
+    if hello.is_empty() {
+        return Some(result);
+    }
+
+    let mut extensions = Reader { block: hello.vector_u16()?, idx: 0 };
+
+    while !extensions.is_empty() {
+        let extension_type = extensions.u16()?;
+        let extension = extensions.vector_u16()?;
+
+        result.extensions.push(extension_type);
+        parse_extension(&mut result, extension_type, extension)?;
+    }
+
+    return Some(result);
+}
+
+/// reads the ClientHello without consuming it, the TLS handshake still sees the untouched stream
+pub async fn peek(conn: &tokio::net::TcpStream) -> Option<ClientHello> {
+    return tokio::time::timeout(CLIENT_HELLO_TIMEOUT, peek_record(conn)).await.ok()?;
+}
+
+async fn peek_record(conn: &tokio::net::TcpStream) -> Option<ClientHello> {
+    let mut block = vec![0_u8; TLS_RECORD_HARD_LIMIT];
+
+    for _ in 0..50 {
+        let len = match conn.peek(&mut block).await {
+            Ok(0) => {
+                return None;
+            },
+            Ok(len) => { len },
+            Err(_) => {
+                return None;
+            }
+        };
+
+        if len >= TLS_RECORD_HEADER_LENGTH {
+            let record_length = TLS_RECORD_HEADER_LENGTH + u16::from_be_bytes([block[3], block[4]]) as usize;
+
+            if len >= record_length || len == block.len() {
+                return parse(&block[..len.min(record_length)]);
+            }
+        }
+
+        // peek returns immediately while data is pending, wait for the rest of the record to arrive
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+
+    return None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the JA3 of this ClientHello is that of Chrome, its JA4 the example of the JA4 specification; the digests
+    /// were computed with md5sum and sha256sum from the JA3 string and the JA4 lists
+    const FIXTURE_JA3_STRING: &str = "771,4865-4866-4867-49195-49199-49196-49200-52393-52392-49171-49172-156-157-47-53,0-23-65281-10-11-35-16-5-13-18-51-45-43-27-17513-21,29-23-24,0";
+    const FIXTURE_JA3: &str = "cd08e31494f9531f560d64c695473da9";
+    const FIXTURE_JA4: &str = "t13d1516h2_8daaf6152771_e5627efa2ab1";
+
+    fn u16_list(values: &[u16]) -> Vec<u8> {
+        let mut result = ((values.len() * 2) as u16).to_be_bytes().to_vec();
+        result.extend(values.iter().flat_map(|value| value.to_be_bytes()));
+        return result;
+    }
+
+    fn extension(extension_type: u16, body: &[u8]) -> Vec<u8> {
+        let mut result = extension_type.to_be_bytes().to_vec();
+        result.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        result.extend_from_slice(body);
+        return result;
+    }
+
+    fn fixture_extensions(grease: bool) -> Vec<u8> {
+        let mut server_name = vec![0];
+        server_name.extend_from_slice(&11_u16.to_be_bytes());
+        server_name.extend_from_slice(b"example.com");
+        let mut server_name_list = (server_name.len() as u16).to_be_bytes().to_vec();
+        server_name_list.extend(server_name);
+
+        let alpn: Vec<u8> = [&[0, 12, 2][..], b"h2", &[8], b"http/1.1"].concat();
+        let groups = match grease {
+            true => { u16_list(&[0x3a3a, 0x001d, 0x0017, 0x0018]) },
+            false => { u16_list(&[0x001d, 0x0017, 0x0018]) }
+        };
+        let versions = match grease {
+            true => { [&[6][..], &[0x4a, 0x4a, 0x03, 0x04, 0x03, 0x03]].concat() },
+            false => { vec![4, 0x03, 0x04, 0x03, 0x03] }
+        };
+
+        let mut result: Vec<u8> = Vec::new();
+        if grease {
+            result.extend(extension(0x1a1a, b""));
+        }
+
+        result.extend(extension(TLS_EXTENSION_SERVER_NAME, &server_name_list));
+        result.extend(extension(0x0017, b""));
+        result.extend(extension(0xff01, &[0]));
+        result.extend(extension(TLS_EXTENSION_SUPPORTED_GROUPS, &groups));
+        result.extend(extension(TLS_EXTENSION_EC_POINT_FORMATS, &[1, 0]));
+        result.extend(extension(0x0023, b""));
+        result.extend(extension(TLS_EXTENSION_ALPN, &alpn));
+        result.extend(extension(0x0005, &[1, 0, 0, 0, 0]));
+        result.extend(extension(TLS_EXTENSION_SIGNATURE_ALGORITHMS, &u16_list(&[0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601])));
+        result.extend(extension(0x0012, b""));
+        result.extend(extension(0x0033, b""));
+        result.extend(extension(0x002d, &[1, 1]));
+        result.extend(extension(TLS_EXTENSION_SUPPORTED_VERSIONS, &versions));
+        result.extend(extension(0x001b, &[2, 0, 2]));
+        result.extend(extension(0x4469, b""));
+        result.extend(extension(0x0015, &[0; 8]));
+
+        if grease {
+            result.extend(extension(0x2a2a, &[0]));
+        }
+
+        return result;
+    }
+
+    /// a Chrome-like ClientHello in a single record, with or without its GREASE values
+    fn fixture_record(grease: bool) -> Vec<u8> {
+        let mut cipher_suites = vec![0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc013, 0xc014, 0x009c, 0x009d, 0x002f, 0x0035];
+        if grease {
+            cipher_suites.insert(0, 0x0a0a);
+        }
+
+        let extensions = fixture_extensions(grease);
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0x42; 32]);
+        hello.push(32);
+        hello.extend_from_slice(&[0x24; 32]);
+        hello.extend(u16_list(&cipher_suites));
+        hello.extend_from_slice(&[1, 0]);
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend(extensions);
+
+        let mut handshake = vec![TLS_HANDSHAKE_CLIENT_HELLO];
+        handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(hello);
+
+        let mut record = vec![TLS_CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+        return record;
+    }
+
+    #[test]
+    fn fixture_gives_the_known_ja3_and_ja4() {
+        let client_hello = parse(&fixture_record(true)).unwrap();
+
+        assert_eq!(client_hello.servername.as_deref(), Some("example.com"));
+        assert!(client_hello.offers_alpn_protocol(b"h2"));
+        assert_eq!(client_hello.ja3(), FIXTURE_JA3);
+        assert_eq!(client_hello.ja4(), FIXTURE_JA4);
+
+        let digest = openssl::hash::hash(openssl::hash::MessageDigest::md5(), FIXTURE_JA3_STRING.as_bytes()).unwrap();
+        assert_eq!(digest.iter().map(|byte| format!("{:02x}", byte)).collect::<String>(), FIXTURE_JA3);
+    }
+
+    #[test]
+    fn grease_values_do_not_change_the_fingerprints() {
+        assert!(is_grease(0x0a0a) && is_grease(0xfafa));
+        assert!(!is_grease(0x0a1a) && !is_grease(0x1301));
+
+        let with_grease = parse(&fixture_record(true)).unwrap();
+        let without_grease = parse(&fixture_record(false)).unwrap();
+
+        assert_ne!(with_grease.cipher_suites.len(), without_grease.cipher_suites.len());
+        assert_eq!(with_grease.ja3(), without_grease.ja3());
+        assert_eq!(with_grease.ja4(), without_grease.ja4());
+    }
+
+    #[test]
+    fn truncated_records_are_not_parsed() {
+        let record = fixture_record(true);
+
+        for len in 0..record.len() {
+            assert!(parse(&record[..len]).is_none(), "parsed a record cut at {} of {} bytes", len, record.len());
+        }
+
+        let mut not_handshake = record.clone();
+        not_handshake[0] = 23;
+        assert!(parse(&not_handshake).is_none());
+    }
+
+    #[test]
+    fn fingerprint_rules_name_either_fingerprint() {
+        let fingerprint = Some(parse(&fixture_record(true)).unwrap().fingerprint());
+
+        assert!(is_fingerprint_allowed(&[FIXTURE_JA4.to_uppercase()], &[], &fingerprint));
+        assert!(!is_fingerprint_allowed(&[], &[FIXTURE_JA3.to_string()], &fingerprint));
+        assert!(!is_fingerprint_allowed(&[String::from("t13d0000h2_000000000000_000000000000")], &[], &fingerprint));
+        assert!(!is_fingerprint_allowed(&[FIXTURE_JA4.to_string()], &[], &None));
+        assert!(is_fingerprint_allowed(&[], &[FIXTURE_JA4.to_string()], &None));
+    }
+}
@@ -0,0 +1,216 @@
+use crate::configdb;
+use crate::http1;
+
+/// "address/prefix" or a single address
+fn ip_matches_cidr(ip: &std::net::IpAddr, cidr: &str) -> bool {
+    let (network, prefix) = match cidr.trim().split_once('/') {
+        Some((network, prefix)) => {
+            // a malformed prefix matches nothing rather than the single address
+            match prefix.parse::<u32>() {
+                Ok(prefix) => { (network, Some(prefix)) },
+                Err(_) => { return false; }
+            }
+        },
+        None => { (cidr.trim(), None) }
+    };
+
+    let network = match network.parse::<std::net::IpAddr>() {
+        Ok(network) => { network },
+        Err(_) => { return false; }
+    };
+
+    match (ip, network) {
+        (std::net::IpAddr::V4(ip), std::net::IpAddr::V4(network)) => {
+            let prefix = prefix.unwrap_or(32).min(32);
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            return u32::from(*ip) & mask == u32::from(network) & mask;
+        },
+        (std::net::IpAddr::V6(ip), std::net::IpAddr::V6(network)) => {
+            let prefix = prefix.unwrap_or(128).min(128);
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            return u128::from(*ip) & mask == u128::from(network) & mask;
+        },
+        (std::net::IpAddr::V6(ip), std::net::IpAddr::V4(_)) => {
+            // a dual-stack listener sees IPv4 clients as mapped addresses
+            match ip.to_ipv4_mapped() {
+                Some(ip) => { return ip_matches_cidr(&std::net::IpAddr::V4(ip), cidr); },
+                None => { return false; }
+            }
+        },
+        _ => {
+            return false;
+        }
+    }
+}
+
+/// a dual-stack socket reports IPv4 peers as mapped IPv6 addresses, the rules know them by their IPv4 address
+pub fn to_canonical(addr: std::net::SocketAddr) -> std::net::SocketAddr {
+    return std::net::SocketAddr::new(addr.ip().to_canonical(), addr.port());
+}
+
+pub fn is_trusted_proxy(ip: &std::net::IpAddr, general_config: &configdb::General) -> bool {
+    return general_config.trusted_proxies.iter().any(|cidr| ip_matches_cidr(ip, cidr));
+}
+
+/// a hop of X-Forwarded-For or the for= node of Forwarded, with or without a port; obfuscated and "unknown"
+/// nodes are not addresses
+fn parse_hop(hop: &str) -> Option<std::net::IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+
+    if let Ok(ip) = hop.parse::<std::net::IpAddr>() {
+        return Some(ip);
+    }
+
+    if let Ok(addr) = hop.parse::<std::net::SocketAddr>() {
+        return Some(addr.ip());
+    }
+
+    // "[2001:db8::1]" without a port
+    return hop.strip_prefix('[')?.strip_suffix(']')?.parse::<std::net::IpAddr>().ok();
+}
+
+/// the hops from the client to the last proxy, in the order they were added
+fn get_hops(object: &http1::Http, general_config: &configdb::General) -> Vec<String> {
+    match general_config.client_ip_header {
+        configdb::ClientIpHeader::XForwardedFor => {
+            match http1::find_property(&object.properties, "x-forwarded-for") {
+                Some(value) => { return value.split(',').map(|hop| hop.trim().to_string()).collect(); },
+                None => { return Vec::new(); }
+            }
+        },
+        configdb::ClientIpHeader::Forwarded => {
+            let value = match http1::find_property(&object.properties, "forwarded") {
+                Some(value) => { value },
+                None => { return Vec::new(); }
+            };
+
+            // an element without a for= parameter still is a hop, it cannot be skipped over
+            return value.split(',').map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (name, value) = pair.split_once('=')?;
+                    match name.trim().eq_ignore_ascii_case("for") {
+                        true => { Some(value.trim().to_string()) },
+                        false => { None }
+                    }
+                }).unwrap_or_default()
+            }).collect();
+        },
+        configdb::ClientIpHeader::CfConnectingIp => {
+            match http1::find_property(&object.properties, "cf-connecting-ip") {
+                Some(value) => { return vec![value.trim().to_string()]; },
+                None => { return Vec::new(); }
+            }
+        }
+    }
+}
+
+/// the address the request comes from; a trusted proxy tells it in the configured header, whose hops are
+/// walked from the right until one is not a trusted proxy itself
+pub fn resolve_client_ip(object: &http1::Http, peer_ip: std::net::IpAddr, general_config: &configdb::General) -> std::net::IpAddr {
+    if !is_trusted_proxy(&peer_ip, general_config) {
+        return peer_ip;
+    }
+
+    let mut result = peer_ip;
+
+    for hop in get_hops(object, general_config).iter().rev() {
+        // whatever stands left of a hop that is not an address cannot be trusted
+        result = match parse_hop(hop) {
+            Some(ip) => { ip.to_canonical() },
+            None => { return result; }
+        };
+
+        if !is_trusted_proxy(&result, general_config) {
+            return result;
+        }
+    }
+
+    return result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> std::net::IpAddr {
+        return value.parse::<std::net::IpAddr>().unwrap();
+    }
+
+    fn request(name: &str, value: &str) -> http1::Http {
+        return http1::Http { properties: [(name.to_string(), value.to_string())].into_iter().collect(), ..Default::default() };
+    }
+
+    fn general_config(client_ip_header: configdb::ClientIpHeader) -> configdb::General {
+        return configdb::General { trusted_proxies: vec![String::from("10.0.0.0/8"), String::from("2001:db8::/32")], client_ip_header, ..Default::default() };
+    }
+
+    #[test]
+    fn walks_the_forwarded_for_hops_from_the_right() {
+        let general_config = general_config(configdb::ClientIpHeader::XForwardedFor);
+        let object = request("X-Forwarded-For", "198.51.100.1, 203.0.113.7, 10.1.1.1, 10.2.2.2");
+
+        assert_eq!(resolve_client_ip(&object, ip("10.0.0.1"), &general_config), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn ignores_the_header_of_an_untrusted_peer() {
+        let general_config = general_config(configdb::ClientIpHeader::XForwardedFor);
+        let object = request("X-Forwarded-For", "203.0.113.7");
+
+        assert_eq!(resolve_client_ip(&object, ip("192.0.2.1"), &general_config), ip("192.0.2.1"));
+    }
+
+    #[test]
+    fn stops_at_a_malformed_hop() {
+        let general_config = general_config(configdb::ClientIpHeader::XForwardedFor);
+
+        // the forged address left of the garbage is not reached, the last trusted proxy is the client
+        let object = request("X-Forwarded-For", "198.51.100.1, not-an-ip, 10.1.1.1");
+        assert_eq!(resolve_client_ip(&object, ip("10.0.0.1"), &general_config), ip("10.1.1.1"));
+
+        let object = request("X-Forwarded-For", "");
+        assert_eq!(resolve_client_ip(&object, ip("10.0.0.1"), &general_config), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn reads_the_forwarded_header_with_ports_and_brackets() {
+        let general_config = general_config(configdb::ClientIpHeader::Forwarded);
+        let object = request("Forwarded", "for=192.0.2.60;proto=http, for=\"[2001:db8:cafe::17]:4711\"");
+
+        assert_eq!(resolve_client_ip(&object, ip("10.0.0.1"), &general_config), ip("192.0.2.60"));
+
+        let object = request("Forwarded", "for=198.51.100.9:8080;by=10.0.0.1");
+        assert_eq!(resolve_client_ip(&object, ip("10.0.0.1"), &general_config), ip("198.51.100.9"));
+    }
+
+    #[test]
+    fn matches_ipv4_mapped_ipv6_addresses_as_ipv4() {
+        assert!(ip_matches_cidr(&ip("::ffff:10.1.2.3"), "10.0.0.0/8"));
+        assert!(!ip_matches_cidr(&ip("::ffff:192.0.2.1"), "10.0.0.0/8"));
+        assert_eq!(to_canonical("[::ffff:192.0.2.1]:443".parse().unwrap()), "192.0.2.1:443".parse().unwrap());
+
+        let general_config = general_config(configdb::ClientIpHeader::XForwardedFor);
+        let object = request("X-Forwarded-For", "::ffff:203.0.113.7");
+        assert_eq!(resolve_client_ip(&object, ip("::ffff:10.0.0.1"), &general_config), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn matches_the_cidr_prefix_bounds() {
+        assert!(ip_matches_cidr(&ip("203.0.113.7"), "0.0.0.0/0"));
+        assert!(ip_matches_cidr(&ip("2001:db8::1"), "::/0"));
+        assert!(ip_matches_cidr(&ip("203.0.113.7"), "203.0.113.7/32"));
+        assert!(!ip_matches_cidr(&ip("203.0.113.8"), "203.0.113.7/32"));
+        assert!(ip_matches_cidr(&ip("2001:db8::1"), "2001:db8::1/128"));
+        assert!(!ip_matches_cidr(&ip("2001:db8::2"), "2001:db8::1/128"));
+        assert!(ip_matches_cidr(&ip("203.0.113.7"), " 203.0.113.7 "));
+    }
+
+    #[test]
+    fn refuses_malformed_cidrs() {
+        assert!(!ip_matches_cidr(&ip("203.0.113.7"), "203.0.113.7/abc"));
+        assert!(!ip_matches_cidr(&ip("203.0.113.7"), "203.0.113.7/"));
+        assert!(!ip_matches_cidr(&ip("203.0.113.7"), "203.0.113/24"));
+        assert!(!ip_matches_cidr(&ip("203.0.113.7"), ""));
+        assert!(!ip_matches_cidr(&ip("2001:db8::1"), "10.0.0.0/8"));
+    }
+}
@@ -4,6 +4,11 @@ pub const GENERAL_CONFIG_FILENAME: &str = "appdata/general.yaml";
 pub const EDGE_SERVER_DIRNAME: &str = "appdata/edges/";
 pub const IP_RULES_DIRNAME: &str = "appdata/ip-rules/";
 pub const LOCATION_RULES_DIRNAME: &str = "appdata/locations-rules/";
+pub const CERTIFICATES_DIRNAME: &str = "appdata/certs/";
+pub const ACME_CONFIG_FILENAME: &str = "appdata/acme.yaml";
+pub const ACME_DIRNAME: &str = "appdata/acme/";
+pub const GRPC_RULES_DIRNAME: &str = "appdata/grpc-rules/";
+pub const HOST_RULES_DIRNAME: &str = "appdata/host-rules/";
 
 #[derive(Debug, Default, Deserialize, Serialize, Clone)]
 pub enum RuleGress {
@@ -20,12 +25,368 @@ pub enum GenericRuleGress {
     Deny,
 }
 
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub enum ClientCertificate {
+    #[default]
+    None,
+    Optional,
+    Required,
+}
+
+/// what happens to a response leaking data found by the dlp detectors of the location rule
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum DlpAction {
+    #[default]
+    Log,
+    Mask,
+    Block,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum HeaderInjection {
+    /// the edge server's own value wins
+    #[default]
+    Missing,
+    Always,
+}
+
+/// hardening of the response headers, an empty value leaves the header alone
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct SecurityHeaders {
+    #[serde(default)]
+    pub injection: HeaderInjection,
+    #[serde(default)]
+    pub strict_transport_security: String,
+    #[serde(default)]
+    pub content_security_policy: String,
+    #[serde(default)]
+    pub x_content_type_options: String,
+    #[serde(default)]
+    pub x_frame_options: String,
+    #[serde(default)]
+    pub referrer_policy: String,
+    #[serde(default)]
+    pub permissions_policy: String,
+    #[serde(default)]
+    pub strip_server_headers: bool,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum CookieProtection {
+    #[default]
+    None,
+    Signed,
+    Encrypted,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct RenamedCookie {
+    pub name: String,
+    pub renamed_to: String,
+}
+
+/// hardening of the cookies set by the edge server; renamed and prefixed cookies get their original name back
+/// on the way to the edge server, so renaming belongs to the host rule rather than to a single location
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct CookiePolicy {
+    /// the names of the cookies hardened, all of them when empty
+    #[serde(default)]
+    pub cookies: Vec<String>,
+    #[serde(default)]
+    pub secure: bool,
+    #[serde(default)]
+    pub http_only: bool,
+    /// Strict, Lax or None, an empty value leaves the attribute alone
+    #[serde(default)]
+    pub same_site: String,
+    /// "__Host-" or "__Secure-", the attributes the prefix requires are enforced along with it
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default)]
+    pub renamed_cookies: Vec<RenamedCookie>,
+    /// the cookies signed or encrypted by the WAF, a request carrying one that fails verification is dropped
+    #[serde(default)]
+    pub protected_cookies: Vec<String>,
+    #[serde(default)]
+    pub protection: CookieProtection,
+    /// the first key protects the cookies set from now on, the others are still accepted while keys rotate
+    #[serde(default)]
+    pub protection_keys: Vec<String>,
+    /// the violations are logged but the cookies are passed as they are
+    #[serde(default)]
+    pub log_only: bool,
+}
+
+/// protection against cross-site request forgery with a token given in a cookie and sent back in a header or a
+/// form field; the empty names default to waf_csrf and X-CSRF-Token. HTTP/2 and HTTP/3 requests must send the
+/// header, their forms are neither injected nor looked into
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct CsrfPolicy {
+    /// the methods checked, POST, PUT, DELETE and PATCH when empty
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub cookie_name: String,
+    #[serde(default)]
+    pub field_name: String,
+    #[serde(default)]
+    pub header_name: String,
+    /// the token is added to the forms posted from the HTML pages
+    #[serde(default)]
+    pub inject_forms: bool,
+    /// origins other than the host itself allowed to send requests, "https://app.example.com"
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct HostRule {
+    pub hostname: String,
+    #[serde(default)]
+    pub security_headers: Option<SecurityHeaders>,
+    #[serde(default)]
+    pub cookie_policy: Option<CookiePolicy>,
+    #[serde(default)]
+    pub csrf: Option<CsrfPolicy>,
+}
+
 #[derive(Debug, Default, Deserialize, Serialize, Clone)]
 pub struct LocationRule {
     pub method: String,
     pub location: String,
     pub bypass: bool,
     pub ingress: RuleGress,
+    #[serde(default)]
+    pub client_subjects: Vec<String>,
+    #[serde(default)]
+    pub client_sans: Vec<String>,
+    #[serde(default)]
+    pub client_fingerprints: Vec<String>,
+    /// JA3 or JA4 values; no fingerprint is taken over HTTP/3, a non-empty list refuses its clients
+    #[serde(default)]
+    pub allowed_tls_fingerprints: Vec<String>,
+    #[serde(default)]
+    pub denied_tls_fingerprints: Vec<String>,
+    #[serde(default)]
+    pub websocket_allowed_origins: Vec<String>,
+    #[serde(default)]
+    pub websocket_max_message_size: usize,
+    #[serde(default)]
+    pub websocket_messages_per_second: usize,
+    #[serde(default)]
+    pub websocket_detectors: Vec<String>,
+    #[serde(default)]
+    pub websocket_denied_patterns: Vec<String>,
+    #[serde(default)]
+    pub response_detectors: Vec<String>,
+    #[serde(default)]
+    pub response_denied_patterns: Vec<String>,
+    #[serde(default)]
+    pub dlp_detectors: Vec<String>,
+    #[serde(default)]
+    pub dlp_action: DlpAction,
+    #[serde(default)]
+    pub security_headers: Option<SecurityHeaders>,
+    #[serde(default)]
+    pub cookie_policy: Option<CookiePolicy>,
+    #[serde(default)]
+    pub csrf: Option<CsrfPolicy>,
+    #[serde(default)]
+    pub location_match: LocationMatch,
+    #[serde(default)]
+    pub rewrite: Option<RequestRewrite>,
+    #[serde(default)]
+    pub redirect: Option<Redirect>,
+    #[serde(default)]
+    pub static_response: Option<StaticResponse>,
+}
+
+fn default_redirect_status() -> u16 {
+    return 302;
+}
+
+fn default_static_response_status() -> u16 {
+    return 200;
+}
+
+/// answers the request with a redirect instead of forwarding it
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct Redirect {
+    /// 301, 302, 307 or 308
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+    /// the target, {scheme}, {host}, {hostname}, {uri}, {path} and {query} stand for those of the request
+    pub location: String,
+}
+
+/// answers the request with a fixed response instead of forwarding it
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct StaticResponse {
+    #[serde(default = "default_static_response_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: Vec<HeaderEntry>,
+    #[serde(default)]
+    pub body_file: String,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum LocationMatch {
+    /// the whole location, query included
+    #[default]
+    Exact,
+    /// the locations under the rule's, an exact rule or a longer prefix wins over it
+    Prefix,
+}
+
+/// a header added to the request or replacing the client's
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct HeaderEntry {
+    pub name: String,
+    pub value: String,
+}
+
+/// changes made to the request on its way to the edge server, the rules and policies see it as the client sent it
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct RequestRewrite {
+    /// regular expression matched against the path, the query is left out
+    #[serde(default)]
+    pub path_pattern: String,
+    /// the path replacing the match, $1 or ${name} stand for the captures
+    #[serde(default)]
+    pub path_replacement: String,
+    #[serde(default)]
+    pub host: String,
+    #[serde(default)]
+    pub add_headers: Vec<HeaderEntry>,
+    #[serde(default)]
+    pub replace_headers: Vec<HeaderEntry>,
+    #[serde(default)]
+    pub remove_headers: Vec<String>,
+    /// "*" strips the whole query
+    #[serde(default)]
+    pub strip_query_parameters: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct GrpcFieldRule {
+    pub field: String,
+    #[serde(default)]
+    pub max_length: usize,
+    #[serde(default)]
+    pub denied_values: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct GrpcRule {
+    pub service: String,
+    pub method: String,
+    pub ingress: RuleGress,
+    #[serde(default)]
+    pub max_message_size: usize,
+    #[serde(default)]
+    pub descriptor_set: String,
+    #[serde(default)]
+    pub field_rules: Vec<GrpcFieldRule>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum ListenerMode {
+    /// the requests go to the edge servers, as on the main listener
+    #[default]
+    Proxy,
+    /// plain HTTP answering the ACME HTTP-01 challenges and redirecting everything else to HTTPS
+    RedirectToHttps,
+}
+
+/// TLS settings of a single listener, the empty ones are those of the general config
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct ListenerTls {
+    #[serde(default)]
+    pub ssl_certificate: String,
+    #[serde(default)]
+    pub ssl_certificate_key: String,
+    #[serde(default)]
+    pub tls_preset: Option<TlsPreset>,
+    #[serde(default)]
+    pub tls_min_version: String,
+    #[serde(default)]
+    pub tls_max_version: String,
+    #[serde(default)]
+    pub tls_ciphers: String,
+    #[serde(default)]
+    pub tls_ciphersuites: String,
+    #[serde(default)]
+    pub tls_groups: String,
+    #[serde(default)]
+    pub tls_disable_session_tickets: Option<bool>,
+    /// seconds between two session ticket key rotations of the listener, 0 keeps its keys
+    #[serde(default)]
+    pub tls_session_ticket_rotation: Option<u64>,
+    #[serde(default)]
+    pub client_certificate: Option<ClientCertificate>,
+    #[serde(default)]
+    pub client_ca_bundle: String,
+    #[serde(default)]
+    pub client_crl: String,
+    #[serde(default)]
+    pub alpn_protocols: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct Listener {
+    /// shown in the logs, the bind address when unset
+    #[serde(default)]
+    pub name: String,
+    /// "::" accepts IPv4 clients too unless ipv6_only is set
+    pub listen_address: String,
+    pub listen_port: u16,
+    #[serde(default)]
+    pub ipv6_only: bool,
+    #[serde(default)]
+    pub https: bool,
+    #[serde(default)]
+    pub tls: Option<ListenerTls>,
+    #[serde(default)]
+    pub mode: ListenerMode,
+    /// the port the redirects point to, 443 when unset
+    #[serde(default)]
+    pub https_port: u16,
+    /// the general maximum_connections when unset
+    #[serde(default)]
+    pub maximum_connections: usize,
+    /// the general ingress when unset
+    #[serde(default)]
+    pub ingress: Option<GenericRuleGress>,
+    /// destinations of the edge servers the requests may go to, all of them when empty
+    #[serde(default)]
+    pub edges: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum ProxyProtocol {
+    #[default]
+    Disabled,
+    Optional,
+    Required,
+}
+
+/// the PROXY protocol version sent to the edge server
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum EdgeProxyProtocol {
+    #[default]
+    None,
+    V1,
+    V2,
+}
+
+/// the header a trusted proxy names the client in
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub enum ClientIpHeader {
+    #[default]
+    XForwardedFor,
+    Forwarded,
+    CfConnectingIp,
 }
 
 #[derive(Debug, Default, Deserialize, Serialize, Clone)]
@@ -33,9 +394,23 @@ pub struct IpRule {
     pub ip: String,
     pub ingress: RuleGress,
     pub bypass_protection: bool,
+    /// requests per second, 0 leaves the client unlimited
     pub limit_rate: usize,
     pub blacklisted_locations: Vec<String>,
     pub whitelist_location: Vec<String>,
+    /// JA3 or JA4 values; no fingerprint is taken over HTTP/3, a non-empty list refuses its clients
+    #[serde(default)]
+    pub allowed_tls_fingerprints: Vec<String>,
+    #[serde(default)]
+    pub denied_tls_fingerprints: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub enum EdgeSslVerify {
+    #[default]
+    None,
+    Chain,
+    ChainAndHostname,
 }
 
 #[derive(Debug, Default, Deserialize, Serialize, Clone)]
@@ -47,15 +422,120 @@ pub struct Edge {
     pub conn_count: usize,
     pub requests_per_second: usize,
     pub https: bool,
+    #[serde(default)]
+    pub ssl_verify: EdgeSslVerify,
+    #[serde(default)]
+    pub ssl_ca_bundle: String,
+    #[serde(default)]
+    pub ssl_spki_pins: Vec<String>,
+    #[serde(default)]
+    pub http2: bool,
+    #[serde(default)]
+    pub http2_connections: usize,
+    #[serde(default)]
+    pub ssl_client_certificate: String,
+    #[serde(default)]
+    pub ssl_client_certificate_key: String,
+    /// sent on the connections opened for a single HTTP/1 client, the pooled HTTP/2 edge connections carry many
+    #[serde(default)]
+    pub proxy_protocol: EdgeProxyProtocol,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct Certificate {
+    pub hostname: String,
+    pub ssl_certificate: String,
+    pub ssl_certificate_key: String,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub enum AcmeChallenge {
+    #[default]
+    Http01,
+    TlsAlpn01,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct Acme {
+    pub directory_url: String,
+    #[serde(default)]
+    pub contact_email: String,
+    pub hostnames: Vec<String>,
+    #[serde(default)]
+    pub challenge: AcmeChallenge,
+    #[serde(default)]
+    pub ca_bundle: String,
+    pub renew_before_days: u32,
+    pub check_interval: u64,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub enum TlsPreset {
+    Modern,
+    #[default]
+    Intermediate,
+    Legacy,
 }
 
 #[derive(Debug, Default, Deserialize, Serialize, Clone)]
 pub struct General {
-    pub listen_address: String,
-    pub listen_port: u16,
+    pub listeners: Vec<Listener>,
     pub maximum_connections: usize,
-    pub https: bool,
+    #[serde(default)]
     pub ssl_certificate: String,
+    #[serde(default)]
     pub ssl_certificate_key: String,
     pub ingress: GenericRuleGress,
+    #[serde(default)]
+    pub client_certificate: ClientCertificate,
+    #[serde(default)]
+    pub client_ca_bundle: String,
+    #[serde(default)]
+    pub client_crl: String,
+    #[serde(default)]
+    pub tls_preset: TlsPreset,
+    #[serde(default)]
+    pub tls_min_version: String,
+    #[serde(default)]
+    pub tls_max_version: String,
+    #[serde(default)]
+    pub tls_ciphers: String,
+    #[serde(default)]
+    pub tls_ciphersuites: String,
+    #[serde(default)]
+    pub tls_groups: String,
+    #[serde(default)]
+    pub tls_disable_session_tickets: bool,
+    #[serde(default)]
+    pub tls_session_ticket_rotation: u64,
+    #[serde(default)]
+    pub alpn_protocols: Vec<String>,
+    #[serde(default)]
+    pub h2c: bool,
+    #[serde(default)]
+    pub http2_max_concurrent_streams: u32,
+    #[serde(default)]
+    pub grpc_max_message_size: usize,
+    #[serde(default)]
+    pub http3: bool,
+    #[serde(default)]
+    pub http3_port: u16,
+    #[serde(default)]
+    pub http3_alt_svc_max_age: u64,
+    #[serde(default)]
+    pub websocket_allowed_origins: Vec<String>,
+    #[serde(default)]
+    pub websocket_max_frame_size: usize,
+    #[serde(default)]
+    pub websocket_max_message_size: usize,
+    #[serde(default)]
+    pub forwarded_headers: bool,
+    /// addresses or CIDRs of the proxies in front of the WAF
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    #[serde(default)]
+    pub client_ip_header: ClientIpHeader,
+    /// PROXY protocol headers are only read from the trusted proxies
+    #[serde(default)]
+    pub proxy_protocol: ProxyProtocol,
 }
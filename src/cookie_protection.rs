@@ -0,0 +1,224 @@
+use crate::configdb;
+
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+/// an HMAC-SHA256
+const SIGNATURE_LENGTH: usize = 32;
+
+fn to_io_error(err: openssl::error::ErrorStack) -> std::io::Error {
+    return std::io::Error::new(std::io::ErrorKind::Other, err.to_string());
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    let key = openssl::pkey::PKey::hmac(key).map_err(to_io_error)?;
+    let mut signer = openssl::sign::Signer::new(openssl::hash::MessageDigest::sha256(), &key).map_err(to_io_error)?;
+    signer.update(data).map_err(to_io_error)?;
+
+    return signer.sign_to_vec().map_err(to_io_error);
+}
+
+/// signing and encryption get keys of their own out of the configured secret
+fn derive_key(secret: &str, protection: configdb::CookieProtection) -> Result<Vec<u8>, std::io::Error> {
+    let purpose: &[u8] = match protection {
+        configdb::CookieProtection::Encrypted => { b"cookie encryption" },
+        _ => { b"cookie signature" }
+    };
+
+    return hmac_sha256(secret.as_bytes(), purpose);
+}
+
+fn sign(secret: &str, name: &str, value: &str) -> Result<Vec<u8>, std::io::Error> {
+    let key = derive_key(secret, configdb::CookieProtection::Signed)?;
+
+    // the name is part of the signature, a signed value cannot be moved to another cookie
+    return hmac_sha256(&key, format!("{}={}", name, value).as_bytes());
+}
+
+fn encrypt(secret: &str, name: &str, value: &str) -> Result<Vec<u8>, std::io::Error> {
+    let key = derive_key(secret, configdb::CookieProtection::Encrypted)?;
+    let mut nonce = [0_u8; NONCE_LENGTH];
+    let mut tag = [0_u8; TAG_LENGTH];
+
+    openssl::rand::rand_bytes(&mut nonce).map_err(to_io_error)?;
+    let ciphertext = openssl::symm::encrypt_aead(openssl::symm::Cipher::aes_256_gcm(), &key, Some(&nonce), name.as_bytes(), value.as_bytes(), &mut tag).map_err(to_io_error)?;
+
+    let mut result = nonce.to_vec();
+    result.extend_from_slice(&ciphertext);
+    result.extend_from_slice(&tag);
+    return Ok(result);
+}
+
+fn decrypt(secret: &str, name: &str, sealed: &[u8]) -> Option<String> {
+    if sealed.len() < NONCE_LENGTH + TAG_LENGTH {
+        return None;
+    }
+
+    let key = derive_key(secret, configdb::CookieProtection::Encrypted).ok()?;
+    let (nonce, rest) = sealed.split_at(NONCE_LENGTH);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LENGTH);
+    let plaintext = openssl::symm::decrypt_aead(openssl::symm::Cipher::aes_256_gcm(), &key, Some(nonce), name.as_bytes(), ciphertext, tag).ok()?;
+
+    return String::from_utf8(plaintext).ok();
+}
+
+/// whether the cookie is signed or encrypted on its way to the client
+pub fn is_protected(cookie_policy: &configdb::CookiePolicy, name: &str) -> bool {
+    return cookie_policy.protection != configdb::CookieProtection::None && !cookie_policy.log_only && !cookie_policy.protection_keys.is_empty()
+        && cookie_policy.protected_cookies.iter().any(|cookie| cookie == name);
+}
+
+/// the value the client gets, protected with the first key
+pub fn seal(cookie_policy: &configdb::CookiePolicy, name: &str, value: &str) -> Result<String, std::io::Error> {
+    let secret = match cookie_policy.protection_keys.first() {
+        Some(secret) => { secret },
+        None => {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, "no key to protect the cookie with"));
+        }
+    };
+
+    match cookie_policy.protection {
+        configdb::CookieProtection::Signed => {
+            let signature = sign(secret, name, value)?;
+            return Ok(format!("{}.{}", value, openssl::base64::encode_block(&signature)));
+        },
+        configdb::CookieProtection::Encrypted => {
+            return Ok(openssl::base64::encode_block(&encrypt(secret, name, value)?));
+        },
+        configdb::CookieProtection::None => {
+            return Ok(value.to_string());
+        }
+    }
+}
+
+/// whether the value has the form seal gives it under the policy, whichever key it was sealed with
+pub fn looks_sealed(cookie_policy: &configdb::CookiePolicy, sealed: &str) -> bool {
+    match cookie_policy.protection {
+        configdb::CookieProtection::Signed => {
+            match sealed.rsplit_once('.').and_then(|(_, signature)| openssl::base64::decode_block(signature).ok()) {
+                Some(signature) => { return signature.len() == SIGNATURE_LENGTH; },
+                None => { return false; }
+            }
+        },
+        configdb::CookieProtection::Encrypted => {
+            match openssl::base64::decode_block(sealed) {
+                Ok(sealed) => { return sealed.len() >= NONCE_LENGTH + TAG_LENGTH; },
+                Err(_) => { return false; }
+            }
+        },
+        configdb::CookieProtection::None => {
+            return false;
+        }
+    }
+}
+
+/// the value the edge server set, when any of the keys verifies it; the keys still listed after a rotation keep
+/// the cookies issued before it valid
+pub fn open(cookie_policy: &configdb::CookiePolicy, name: &str, sealed: &str) -> Option<String> {
+    match cookie_policy.protection {
+        configdb::CookieProtection::Signed => {
+            let (value, signature) = sealed.rsplit_once('.')?;
+            let signature = openssl::base64::decode_block(signature).ok()?;
+
+            for secret in cookie_policy.protection_keys.iter() {
+                if let Ok(expected) = sign(secret, name, value) {
+                    if expected.len() == signature.len() && openssl::memcmp::eq(&expected, &signature) {
+                        return Some(value.to_string());
+                    }
+                }
+            }
+
+            return None;
+        },
+        configdb::CookieProtection::Encrypted => {
+            let sealed = openssl::base64::decode_block(sealed).ok()?;
+            return cookie_policy.protection_keys.iter().find_map(|secret| decrypt(secret, name, &sealed));
+        },
+        configdb::CookieProtection::None => {
+            return Some(sealed.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_policy(protection: configdb::CookieProtection, protection_keys: &[&str]) -> configdb::CookiePolicy {
+        return configdb::CookiePolicy {
+            protected_cookies: vec!["session".to_string()],
+            protection,
+            protection_keys: protection_keys.iter().map(|key| key.to_string()).collect(),
+            ..Default::default()
+        };
+    }
+
+    #[test]
+    fn signed_cookies_open_to_their_value() {
+        let cookie_policy = create_policy(configdb::CookieProtection::Signed, &["first"]);
+        let sealed = seal(&cookie_policy, "session", "abc=1").unwrap();
+
+        assert!(sealed.starts_with("abc=1."));
+        assert_eq!(open(&cookie_policy, "session", &sealed).as_deref(), Some("abc=1"));
+    }
+
+    #[test]
+    fn encrypted_cookies_open_to_their_value() {
+        let cookie_policy = create_policy(configdb::CookieProtection::Encrypted, &["first"]);
+        let sealed = seal(&cookie_policy, "session", "abc").unwrap();
+
+        assert!(!sealed.contains("abc"));
+        assert_ne!(sealed, seal(&cookie_policy, "session", "abc").unwrap());
+        assert_eq!(open(&cookie_policy, "session", &sealed).as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn tampered_signed_cookies_do_not_open() {
+        let cookie_policy = create_policy(configdb::CookieProtection::Signed, &["first"]);
+        let sealed = seal(&cookie_policy, "session", "user").unwrap();
+        let (_, signature) = sealed.rsplit_once('.').unwrap();
+
+        assert_eq!(open(&cookie_policy, "session", &format!("admin.{}", signature)), None);
+        assert_eq!(open(&cookie_policy, "other", &sealed), None);
+        assert_eq!(open(&cookie_policy, "session", "user"), None);
+        assert_eq!(open(&cookie_policy, "session", "user.!!"), None);
+        assert_eq!(open(&create_policy(configdb::CookieProtection::Signed, &["second"]), "session", &sealed), None);
+    }
+
+    #[test]
+    fn tampered_encrypted_cookies_do_not_open() {
+        let cookie_policy = create_policy(configdb::CookieProtection::Encrypted, &["first"]);
+        let sealed = seal(&cookie_policy, "session", "abc").unwrap();
+
+        let mut bytes = openssl::base64::decode_block(&sealed).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+
+        assert_eq!(open(&cookie_policy, "session", &openssl::base64::encode_block(&bytes)), None);
+        assert_eq!(open(&cookie_policy, "other", &sealed), None);
+        assert_eq!(open(&cookie_policy, "session", "c2hvcnQ="), None);
+        assert_eq!(open(&cookie_policy, "session", "not base64"), None);
+    }
+
+    #[test]
+    fn cookies_sealed_before_a_key_rotation_still_open() {
+        for protection in [configdb::CookieProtection::Signed, configdb::CookieProtection::Encrypted] {
+            let sealed = seal(&create_policy(protection, &["old"]), "session", "abc").unwrap();
+            let rotated = create_policy(protection, &["new", "old"]);
+
+            assert_eq!(open(&rotated, "session", &sealed).as_deref(), Some("abc"));
+            assert_eq!(open(&create_policy(protection, &["new"]), "session", &sealed), None);
+        }
+    }
+
+    #[test]
+    fn only_listed_cookies_with_keys_are_protected() {
+        assert!(is_protected(&create_policy(configdb::CookieProtection::Signed, &["first"]), "session"));
+        assert!(!is_protected(&create_policy(configdb::CookieProtection::Signed, &["first"]), "other"));
+        assert!(!is_protected(&create_policy(configdb::CookieProtection::Signed, &[]), "session"));
+        assert!(!is_protected(&create_policy(configdb::CookieProtection::None, &["first"]), "session"));
+
+        let mut cookie_policy = create_policy(configdb::CookieProtection::Signed, &["first"]);
+        cookie_policy.log_only = true;
+        assert!(!is_protected(&cookie_policy, "session"));
+    }
+}
@@ -0,0 +1,415 @@
+use crate::configdb;
+use crate::header_policy;
+use crate::http1;
+
+const DEFAULT_COOKIE_NAME: &str = "waf_csrf";
+const DEFAULT_FIELD_NAME: &str = "waf_csrf";
+const DEFAULT_HEADER_NAME: &str = "x-csrf-token";
+const DEFAULT_METHODS: [&str; 4] = ["POST", "PUT", "DELETE", "PATCH"];
+const TOKEN_LENGTH: usize = 32;
+/// a form whose body is larger cannot be held back to look for its token
+pub const FORM_BODY_HARD_LIMIT: usize = 1024 * 1024;
+/// a tag cut by the end of a block is held back until its end, unless it is longer than this
+const TAG_HARD_LIMIT: usize = 4096;
+
+pub enum Verdict {
+    Allowed,
+    Denied(String),
+    /// the token is not in the headers, the form in the body must carry it
+    FormToken(String),
+}
+
+/// a form request held back until its body shows the token
+pub struct PendingForm {
+    pub head: Vec<u8>,
+    pub body: Vec<u8>,
+    field_name: String,
+    token: String,
+}
+
+fn cookie_name(csrf_policy: &configdb::CsrfPolicy) -> &str {
+    match csrf_policy.cookie_name.is_empty() {
+        true => { return DEFAULT_COOKIE_NAME; },
+        false => { return &csrf_policy.cookie_name; }
+    }
+}
+
+fn field_name(csrf_policy: &configdb::CsrfPolicy) -> &str {
+    match csrf_policy.field_name.is_empty() {
+        true => { return DEFAULT_FIELD_NAME; },
+        false => { return &csrf_policy.field_name; }
+    }
+}
+
+fn header_name(csrf_policy: &configdb::CsrfPolicy) -> &str {
+    match csrf_policy.header_name.is_empty() {
+        true => { return DEFAULT_HEADER_NAME; },
+        false => { return &csrf_policy.header_name; }
+    }
+}
+
+fn is_checked(csrf_policy: &configdb::CsrfPolicy, method: &str) -> bool {
+    match csrf_policy.methods.is_empty() {
+        true => { return DEFAULT_METHODS.contains(&method); },
+        false => { return csrf_policy.methods.iter().any(|checked| checked.eq_ignore_ascii_case(method)); }
+    }
+}
+
+fn is_token(token: &str) -> bool {
+    return token.len() == TOKEN_LENGTH * 2 && token.chars().all(|c| c.is_ascii_hexdigit());
+}
+
+fn is_same_token(token: &str, expected: &str) -> bool {
+    return token.len() == expected.len() && openssl::memcmp::eq(token.as_bytes(), expected.as_bytes());
+}
+
+pub fn create_token() -> String {
+    let mut token = [0_u8; TOKEN_LENGTH];
+
+    if let Err(err) = openssl::rand::rand_bytes(&mut token) {
+        eprintln!("internal error, failed to generate a CSRF token, error: {}; aborting", err.to_string());
+        std::process::abort();
+    }
+
+    return token.iter().map(|byte| format!("{:02x}", byte)).collect();
+}
+
+/// the token the client was given, from its cookie
+pub fn find_cookie_token(object: &http1::Http, csrf_policy: &configdb::CsrfPolicy) -> Option<String> {
+    let cookie = http1::find_property(&object.properties, "cookie")?;
+    let cookie_name = cookie_name(csrf_policy);
+
+    for pair in cookie.split(';') {
+        if let Some((name, value)) = pair.split_once('=') {
+            if name.trim() == cookie_name && is_token(value.trim()) {
+                return Some(value.trim().to_string());
+            }
+        }
+    }
+
+    return None;
+}
+
+/// scripts read the cookie to send the token back in a header, it cannot be HttpOnly
+pub fn create_set_cookie(csrf_policy: &configdb::CsrfPolicy, token: &str) -> String {
+    return format!("{}={}; Path=/; SameSite=Strict", cookie_name(csrf_policy), token);
+}
+
+/// "scheme://host[:port]" of an Origin or Referer value
+fn get_origin(url: &str) -> Option<String> {
+    let (scheme, rest) = url.trim().split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+
+    if authority.is_empty() {
+        return None;
+    }
+
+    return Some(format!("{}://{}", scheme.to_ascii_lowercase(), authority.to_ascii_lowercase()));
+}
+
+/// the host of the request itself, or one of the origins the policy trusts
+fn is_origin_allowed(object: &http1::Http, csrf_policy: &configdb::CsrfPolicy, origin: &str) -> bool {
+    let origin = match get_origin(origin) {
+        Some(origin) => { origin },
+        None => { return false; }
+    };
+
+    if let Some(host) = http1::find_property(&object.properties, "host") {
+        if origin.split_once("://").map(|(_, authority)| authority) == Some(host.trim().to_ascii_lowercase().as_str()) {
+            return true;
+        }
+    }
+
+    return csrf_policy.allowed_origins.iter().any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(&origin));
+}
+
+pub fn check_request(object: &http1::Http, csrf_policy: &configdb::CsrfPolicy) -> Verdict {
+    if !is_checked(csrf_policy, &object.method) {
+        return Verdict::Allowed;
+    }
+
+    // browsers send the Origin of cross-site requests, older ones only the Referer
+    match http1::find_property(&object.properties, "origin") {
+        Some(origin) => {
+            if !is_origin_allowed(object, csrf_policy, origin) {
+                return Verdict::Denied(format!("origin {} is not allowed", origin));
+            }
+        },
+        None => {
+            if let Some(referer) = http1::find_property(&object.properties, "referer") {
+                if !is_origin_allowed(object, csrf_policy, referer) {
+                    return Verdict::Denied(format!("referer {} is not allowed", referer));
+                }
+            }
+        }
+    }
+
+    let token = match find_cookie_token(object, csrf_policy) {
+        Some(token) => { token },
+        None => {
+            return Verdict::Denied(String::from("the token cookie is missing"));
+        }
+    };
+
+    match http1::find_property(&object.properties, header_name(csrf_policy)) {
+        Some(header_token) => {
+            match is_same_token(header_token.trim(), &token) {
+                true => { return Verdict::Allowed; },
+                false => { return Verdict::Denied(String::from("the token does not match the cookie")); }
+            }
+        },
+        None => {
+            return Verdict::FormToken(token);
+        }
+    }
+}
+
+/// HTTP/2 and HTTP/3 request bodies are streamed to the edge server, a form token cannot be looked for; the
+/// token has to come in the header, whatever the Origin or Referer says
+pub fn check_streamed_request(object: &http1::Http) -> Result<(), String> {
+    let csrf_policy = match header_policy::get_response_policy(object).csrf {
+        Some(csrf_policy) => { csrf_policy },
+        None => { return Ok(()); }
+    };
+
+    match check_request(object, &csrf_policy) {
+        Verdict::Allowed => {
+            return Ok(());
+        },
+        Verdict::Denied(reason) => {
+            return Err(reason);
+        },
+        Verdict::FormToken(_) => {
+            return Err(String::from("the token header is missing, forms are not looked into on HTTP/2 and HTTP/3"));
+        }
+    }
+}
+
+impl PendingForm {
+    pub fn new(head: Vec<u8>, csrf_policy: &configdb::CsrfPolicy, token: String) -> PendingForm {
+        return PendingForm { head, body: Vec::new(), field_name: field_name(csrf_policy).to_string(), token };
+    }
+
+    /// the token is a field of an urlencoded or a multipart form
+    pub fn has_token(&self) -> bool {
+        let body = String::from_utf8_lossy(&self.body);
+
+        for pair in body.split('&') {
+            if let Some((name, value)) = pair.split_once('=') {
+                if name.trim() == self.field_name && is_same_token(value.trim(), &self.token) {
+                    return true;
+                }
+            }
+        }
+
+        let part_name = format!("name=\"{}\"", self.field_name);
+        let mut from: usize = 0;
+
+        while let Some(position) = body[from..].find(&part_name) {
+            let start = from + position;
+
+            if let Some(value_start) = body[start..].find("\r\n\r\n") {
+                let value = &body[start + value_start + 4..];
+
+                if value.get(..self.token.len()).is_some_and(|value| is_same_token(value, &self.token)) {
+                    return true;
+                }
+            }
+
+            from = start + part_name.len();
+        }
+
+        return false;
+    }
+}
+
+/// adds the token as a hidden field to the forms posted from an HTML page
+pub struct FormInjector {
+    hidden_input: Vec<u8>,
+    pending: Vec<u8>,
+}
+
+/// the method attribute of the tag, "data-method" and other names ending the same are not it
+fn is_post_form(tag: &str) -> bool {
+    let tag = tag.to_ascii_lowercase();
+
+    for (position, _) in tag.match_indices("method") {
+        let is_attribute_name = tag[..position].ends_with(|c: char| c.is_ascii_whitespace() || c == '"' || c == '\'');
+        if !is_attribute_name {
+            continue;
+        }
+
+        match tag[position + "method".len()..].trim_start().strip_prefix('=') {
+            Some(value) => {
+                return value.trim_start().trim_start_matches(['"', '\'']).starts_with("post");
+            },
+            None => {
+                continue;
+            }
+        }
+    }
+
+    return false;
+}
+
+impl FormInjector {
+    pub fn new(csrf_policy: &configdb::CsrfPolicy, token: &str) -> FormInjector {
+        let hidden_input = format!("<input type=\"hidden\" name=\"{}\" value=\"{}\">", field_name(csrf_policy), token);
+        return FormInjector { hidden_input: hidden_input.into_bytes(), pending: Vec::new() };
+    }
+
+    pub fn process(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut text = std::mem::take(&mut self.pending);
+        text.extend_from_slice(data);
+
+        // a tag cut by the end of the block waits for the next one
+        if let Some(tag_start) = text.iter().rposition(|byte| *byte == b'<') {
+            if !text[tag_start..].contains(&b'>') && text.len() - tag_start < TAG_HARD_LIMIT {
+                self.pending = text.split_off(tag_start);
+            }
+        }
+
+        let mut result: Vec<u8> = Vec::with_capacity(text.len());
+        let mut from: usize = 0;
+
+        while let Some(position) = text[from..].windows(5).position(|window| window.eq_ignore_ascii_case(b"<form")) {
+            let start = from + position;
+
+            let end = match text[start..].iter().position(|byte| *byte == b'>') {
+                Some(end) => { start + end + 1 },
+                None => { break; }
+            };
+
+            result.extend_from_slice(&text[from..end]);
+
+            let is_form_tag = text.get(start + 5).is_some_and(|byte| byte.is_ascii_whitespace() || *byte == b'>');
+            if is_form_tag && is_post_form(&String::from_utf8_lossy(&text[start..end])) {
+                result.extend_from_slice(&self.hidden_input);
+            }
+
+            from = end;
+        }
+
+        result.extend_from_slice(&text[from..]);
+        return result;
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        return std::mem::take(&mut self.pending);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+
+    fn request(method: &str, properties: &[(&str, &str)]) -> http1::Http {
+        let properties = properties.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        return http1::Http { location: String::from("/transfer"), method: method.to_string(), properties };
+    }
+
+    fn denied_reason(verdict: Verdict) -> Option<String> {
+        match verdict {
+            Verdict::Denied(reason) => { return Some(reason); },
+            _ => { return None; }
+        }
+    }
+
+    fn pending_form(body: &str) -> PendingForm {
+        let mut pending_form = PendingForm::new(Vec::new(), &configdb::CsrfPolicy::default(), TOKEN.to_string());
+        pending_form.body = body.as_bytes().to_vec();
+        return pending_form;
+    }
+
+    #[test]
+    fn allows_the_header_token_matching_the_cookie() {
+        let cookie = format!("theme=dark; waf_csrf={}", TOKEN);
+        let object = request("POST", &[("Host", "shop.example.com:8443"), ("Origin", "https://shop.example.com:8443"), ("Cookie", &cookie), ("X-CSRF-Token", TOKEN)]);
+
+        assert!(matches!(check_request(&object, &configdb::CsrfPolicy::default()), Verdict::Allowed));
+    }
+
+    #[test]
+    fn denies_a_header_token_other_than_the_cookie() {
+        let cookie = format!("waf_csrf={}", TOKEN);
+        let object = request("POST", &[("Host", "shop.example.com"), ("Cookie", &cookie), ("X-CSRF-Token", &TOKEN.replace('0', "1"))]);
+
+        assert_eq!(denied_reason(check_request(&object, &configdb::CsrfPolicy::default())), Some(String::from("the token does not match the cookie")));
+    }
+
+    #[test]
+    fn asks_for_the_form_token_without_the_header() {
+        let cookie = format!("waf_csrf={}", TOKEN);
+        let object = request("POST", &[("Host", "shop.example.com"), ("Cookie", &cookie)]);
+
+        assert!(matches!(check_request(&object, &configdb::CsrfPolicy::default()), Verdict::FormToken(token) if token == TOKEN));
+        assert!(denied_reason(check_request(&request("POST", &[("Host", "shop.example.com")]), &configdb::CsrfPolicy::default())).is_some());
+    }
+
+    #[test]
+    fn matches_the_origin_with_the_host_and_its_port() {
+        let cookie = format!("waf_csrf={}", TOKEN);
+        let csrf_policy = configdb::CsrfPolicy { allowed_origins: vec![String::from("https://app.example.com/")], ..Default::default() };
+
+        let same_port = request("POST", &[("Host", "shop.example.com:8443"), ("Origin", "https://Shop.Example.com:8443"), ("Cookie", &cookie), ("X-CSRF-Token", TOKEN)]);
+        let other_port = request("POST", &[("Host", "shop.example.com:8443"), ("Origin", "https://shop.example.com"), ("Cookie", &cookie), ("X-CSRF-Token", TOKEN)]);
+        let trusted = request("POST", &[("Host", "shop.example.com"), ("Origin", "https://app.example.com"), ("Cookie", &cookie), ("X-CSRF-Token", TOKEN)]);
+        let referer = request("POST", &[("Host", "shop.example.com"), ("Referer", "https://evil.example.org/shop.example.com"), ("Cookie", &cookie), ("X-CSRF-Token", TOKEN)]);
+
+        assert!(matches!(check_request(&same_port, &csrf_policy), Verdict::Allowed));
+        assert!(denied_reason(check_request(&other_port, &csrf_policy)).is_some());
+        assert!(matches!(check_request(&trusted, &csrf_policy), Verdict::Allowed));
+        assert!(denied_reason(check_request(&referer, &csrf_policy)).is_some_and(|reason| reason.starts_with("referer")));
+    }
+
+    #[test]
+    fn leaves_the_unchecked_methods_alone() {
+        assert!(matches!(check_request(&request("GET", &[("Origin", "https://evil.example.org")]), &configdb::CsrfPolicy::default()), Verdict::Allowed));
+    }
+
+    #[test]
+    fn finds_the_token_of_an_urlencoded_form() {
+        assert!(pending_form(&format!("amount=10&waf_csrf={}&to=bob", TOKEN)).has_token());
+        assert!(!pending_form(&format!("amount=10&waf_csrf={}", TOKEN.replace('f', "e"))).has_token());
+        assert!(!pending_form(&format!("amount=10&other_csrf={}", TOKEN)).has_token());
+    }
+
+    #[test]
+    fn finds_the_token_of_a_multipart_form() {
+        let body = format!("--boundary\r\nContent-Disposition: form-data; name=\"amount\"\r\n\r\n10\r\n--boundary\r\nContent-Disposition: form-data; name=\"waf_csrf\"\r\n\r\n{}\r\n--boundary--\r\n", TOKEN);
+
+        assert!(pending_form(&body).has_token());
+        assert!(!pending_form(&body.replace(TOKEN, &TOKEN.replace('a', "b"))).has_token());
+    }
+
+    #[test]
+    fn injects_the_token_into_a_form_tag_split_across_blocks() {
+        let mut form_injector = FormInjector::new(&configdb::CsrfPolicy::default(), TOKEN);
+
+        let mut result = form_injector.process(b"<html><body><form action=\"/transfer\" me");
+        result.extend(form_injector.process(b"thod=\"POST\"><input name=\"amount\"></form>"));
+        result.extend(form_injector.finish());
+
+        let expected = format!("<html><body><form action=\"/transfer\" method=\"POST\"><input type=\"hidden\" name=\"waf_csrf\" value=\"{}\"><input name=\"amount\"></form>", TOKEN);
+        assert_eq!(String::from_utf8(result).unwrap(), expected);
+    }
+
+    #[test]
+    fn leaves_the_get_forms_alone() {
+        let mut form_injector = FormInjector::new(&configdb::CsrfPolicy::default(), TOKEN);
+        let page = b"<form method=\"get\" action=\"/search\"><formfield method=\"post\"></form><form>";
+
+        assert_eq!(form_injector.process(page), page.to_vec());
+    }
+
+    #[test]
+    fn reads_the_method_attribute_and_not_the_names_ending_with_it() {
+        assert!(is_post_form("<form method=post>"));
+        assert!(is_post_form("<form action=\"/x\" METHOD = 'Post'>"));
+        assert!(!is_post_form("<form data-method=\"post\" method=\"get\">"));
+        assert!(!is_post_form("<form data-method=\"post\">"));
+        assert!(!is_post_form("<form action=\"/method=post\">"));
+    }
+}
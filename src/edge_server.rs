@@ -24,16 +24,18 @@ pub fn decrement_conn_count(edge_server_ip: String) {
     }
 }
 
-pub fn find_edge_server() -> Option<configdb::Edge> {
+/// HTTP/1.1 connections are relayed byte for byte, only HTTP/2 streams may be sent to edges speaking HTTP/2; a
+/// listener with an upstream pool only sends to the edges whose destination it lists
+pub fn find_edge_server(http2_stream: bool, pool: &[String]) -> Option<configdb::Edge> {
     let mut result: Option<configdb::Edge> = None;
 
     match EDGE_SERVERS_LISTS.lock() {
         Ok(mut edge_server_list) => {
             edge_server_list.sort_by(|a, b| { a.0.cmp(&b.0) });
 
-            if let Some(_) = edge_server_list.get(0) {
-                edge_server_list[0].0 = edge_server_list[0].0 + 1; // increment the conn count
-                result = Some(edge_server_list[0].1.clone());
+            if let Some(edge_server) = edge_server_list.iter_mut().find(|edge_server| (http2_stream || !edge_server.1.http2) && (pool.is_empty() || pool.contains(&edge_server.1.destination))) {
+                edge_server.0 = edge_server.0 + 1; // increment the conn count
+                result = Some(edge_server.1.clone());
             }
         },
         Err(err) => {
@@ -0,0 +1,96 @@
+use crate::configdb;
+use crate::http1;
+
+/// headers describing the client to the edge server, the copies sent by the client are dropped
+const FORWARDED_HEADERS: [&str; 6] = ["x-forwarded-for", "x-forwarded-proto", "x-forwarded-host", "forwarded", "x-real-ip", "x-request-id"];
+
+pub fn is_forwarded_header(name: &str) -> bool {
+    return FORWARDED_HEADERS.iter().any(|header| name.eq_ignore_ascii_case(header));
+}
+
+/// a random (version 4) UUID
+fn create_request_id() -> String {
+    let mut id = [0_u8; 16];
+
+    if let Err(err) = openssl::rand::rand_bytes(&mut id) {
+        eprintln!("internal error, failed to generate a request id, error: {}; aborting", err.to_string());
+        std::process::abort();
+    }
+
+    id[6] = (id[6] & 0x0f) | 0x40;
+    id[8] = (id[8] & 0x3f) | 0x80;
+
+    let hex: String = id.iter().map(|byte| format!("{:02x}", byte)).collect();
+    return format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32]);
+}
+
+/// RFC 7239 node and host values, IPv6 addresses and ports are not tokens and are quoted
+fn quote_forwarded_value(value: &str) -> String {
+    match value.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)) {
+        true => { return value.to_string(); },
+        false => { return format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")); }
+    }
+}
+
+/// the headers telling the edge server who the client is, none when the general configuration leaves them out
+pub fn create_forwarded_headers(object: &http1::Http, client_ip: &std::net::IpAddr, https: bool, general_config: &configdb::General) -> Vec<(String, String)> {
+    if !general_config.forwarded_headers {
+        return Vec::new();
+    }
+
+    let proto = match https {
+        true => { "https" },
+        false => { "http" }
+    };
+
+    let node = match client_ip {
+        std::net::IpAddr::V4(_) => { client_ip.to_string() },
+        std::net::IpAddr::V6(_) => { format!("[{}]", client_ip) }
+    };
+
+    let mut forwarded = format!("for={};proto={}", quote_forwarded_value(&node), proto);
+    let mut result: Vec<(String, String)> = vec![
+        ("X-Forwarded-For".to_string(), client_ip.to_string()),
+        ("X-Forwarded-Proto".to_string(), proto.to_string()),
+    ];
+
+    if let Some(host) = http1::find_property(&object.properties, "host") {
+        result.push(("X-Forwarded-Host".to_string(), host.trim().to_string()));
+        forwarded.push_str(&format!(";host={}", quote_forwarded_value(host.trim())));
+    }
+
+    result.push(("Forwarded".to_string(), forwarded));
+    result.push(("X-Real-IP".to_string(), client_ip.to_string()));
+    result.push(("X-Request-Id".to_string(), create_request_id()));
+
+    return result;
+}
+
+/// the request head without the client's forwarding headers and with the WAF's own
+pub fn rewrite_request_head(head: &[u8], forwarded_headers: &[(String, String)]) -> Vec<u8> {
+    if forwarded_headers.is_empty() {
+        return head.to_vec();
+    }
+
+    let head = String::from_utf8_lossy(head);
+    let mut result = String::new();
+
+    for line in head.trim_end_matches("\r\n").split("\r\n") {
+        let forwarded = match line.split_once(':') {
+            Some((name, _)) => { is_forwarded_header(name.trim()) },
+            None => { false }
+        };
+
+        if !forwarded {
+            result.push_str(line);
+            result.push_str("\r\n");
+        }
+    }
+
+    for (name, value) in forwarded_headers.iter() {
+        result.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    result.push_str("\r\n");
+    return result.into_bytes();
+}
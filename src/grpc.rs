@@ -0,0 +1,500 @@
+use notify::Watcher;
+
+use crate::configdb;
+use crate::http1;
+
+pub const GRPC_CONTENT_TYPE: &str = "application/grpc";
+const GRPC_MESSAGE_HEADER_LENGTH: usize = 5;
+const GRPC_DEFAULT_MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+pub const GRPC_STATUS_INVALID_ARGUMENT: u32 = 3;
+pub const GRPC_STATUS_PERMISSION_DENIED: u32 = 7;
+pub const GRPC_STATUS_RESOURCE_EXHAUSTED: u32 = 8;
+pub const GRPC_STATUS_UNIMPLEMENTED: u32 = 12;
+
+/// a rule along with the descriptor set it references, decoded once when the rules are loaded
+type GrpcRuleEntry = (configdb::GrpcRule, Option<prost_reflect::DescriptorPool>);
+
+lazy_static::lazy_static! {
+    #[allow(non_upper_case_globals)]
+    static ref GRPC_RULES_LISTS: std::sync::Arc<std::sync::Mutex<Vec<GrpcRuleEntry>>> = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+}
+
+/// the status of a refused call, reported to the client in the grpc-status and grpc-message fields
+#[derive(Clone)]
+pub struct GrpcError {
+    pub status: u32,
+    pub message: String,
+}
+
+/// checks the length-prefixed messages of a call as the request body flows through the proxy
+pub struct MessageInspector {
+    service: String,
+    method: String,
+    max_message_size: usize,
+    field_rules: Vec<configdb::GrpcFieldRule>,
+    input: Option<prost_reflect::MessageDescriptor>,
+    storage: Vec<u8>,
+    refusal: Option<GrpcError>,
+}
+
+/// "application/grpc", "application/grpc+proto" and the other codecs share the prefix
+pub fn is_grpc(request: &http1::Http) -> bool {
+    match http1::find_property(&request.properties, "content-type") {
+        Some(content_type) => {
+            return content_type.to_ascii_lowercase().starts_with(GRPC_CONTENT_TYPE);
+        },
+        None => {
+            return false;
+        }
+    }
+}
+
+/// "/package.Service/Method" into its service and method
+pub fn split_path(location: &str) -> Option<(String, String)> {
+    let (service, method) = location.strip_prefix('/')?.split_once('/')?;
+
+    if service.is_empty() || method.is_empty() || method.contains('/') {
+        return None;
+    }
+
+    return Some((service.to_string(), method.to_string()));
+}
+
+/// a rule naming the method takes precedence over the "*" rule of its service
+pub fn get_grpc_rule<Service: AsRef<str>, Method: AsRef<str>>(service: Service, method: Method) -> Option<GrpcRuleEntry> {
+    let mut result: Option<GrpcRuleEntry> = None;
+
+    match GRPC_RULES_LISTS.lock() {
+        Ok(grpc_rule_list) => {
+            for rule in grpc_rule_list.iter() {
+                if rule.0.service == service.as_ref() && rule.0.method == method.as_ref() {
+                    result = Some(rule.clone());
+                    break;
+                }
+            }
+
+            if result.is_none() {
+                for rule in grpc_rule_list.iter() {
+                    if rule.0.service == service.as_ref() && rule.0.method == "*" {
+                        result = Some(rule.clone());
+                        break;
+                    }
+                }
+            }
+        },
+        Err(err) => {
+            eprintln!("internal error, failed to lock GRPC_RULES_LISTS, error: {}; aborting", err.to_string());
+            std::process::abort();
+        }
+    }
+
+    result
+}
+
+/// applies the method rules to a gRPC call, an allowed call gets the inspector of its request messages
+pub fn evaluate_call(request: &http1::Http, general_config: &configdb::General) -> Result<MessageInspector, GrpcError> {
+    let (service, method) = match split_path(&request.location) {
+        Some(path) => { path },
+        None => {
+            return Err(GrpcError { status: GRPC_STATUS_UNIMPLEMENTED, message: format!("malformed method path '{}'", request.location) });
+        }
+    };
+
+    let mut max_message_size = match general_config.grpc_max_message_size {
+        0 => { GRPC_DEFAULT_MAX_MESSAGE_SIZE },
+        max_message_size => { max_message_size }
+    };
+    let mut field_rules: Vec<configdb::GrpcFieldRule> = Vec::new();
+    let mut input: Option<prost_reflect::MessageDescriptor> = None;
+
+    let denied = match get_grpc_rule(&service, &method) {
+        Some((rule, descriptor_pool)) => {
+            if rule.max_message_size > 0 {
+                max_message_size = rule.max_message_size;
+            }
+
+            field_rules = rule.field_rules.clone();
+
+            // the request message type of the method comes from the descriptor set of the rule
+            if let Some(descriptor_pool) = descriptor_pool {
+                input = descriptor_pool.get_service_by_name(&service).and_then(|service_descriptor| {
+                    service_descriptor.methods().find(|method_descriptor| method_descriptor.name() == method).map(|method_descriptor| method_descriptor.input())
+                });
+
+                if input.is_none() {
+                    eprintln!("the descriptor set {} does not describe {}/{}, its messages are not decoded", &rule.descriptor_set, &service, &method);
+                }
+            }
+
+            match rule.ingress {
+                configdb::RuleGress::GenericRule => { matches!(general_config.ingress, configdb::GenericRuleGress::Deny) },
+                configdb::RuleGress::Deny => { true },
+                configdb::RuleGress::Allow => { false }
+            }
+        },
+        None => {
+            false
+        }
+    };
+
+    if denied {
+        return Err(GrpcError { status: GRPC_STATUS_PERMISSION_DENIED, message: format!("{}/{} is blocked by rule", &service, &method) });
+    }
+
+    return Ok(MessageInspector { service, method, max_message_size, field_rules, input, storage: Vec::new(), refusal: None });
+}
+
+fn collect_field_values(value: &prost_reflect::Value, path: &[&str], result: &mut Vec<String>) {
+    match value {
+        prost_reflect::Value::List(values) => {
+            for value in values.iter() {
+                collect_field_values(value, path, result);
+            }
+        },
+        prost_reflect::Value::Message(message) => {
+            if let Some((name, rest)) = path.split_first() {
+                if let Some(value) = message.get_field_by_name(name) {
+                    collect_field_values(&value, rest, result);
+                }
+            }
+        },
+        prost_reflect::Value::String(value) if path.is_empty() => {
+            result.push(value.clone());
+        },
+        prost_reflect::Value::Bytes(value) if path.is_empty() => {
+            result.push(String::from_utf8_lossy(value).to_string());
+        },
+        _ => {}
+    }
+}
+
+impl MessageInspector {
+    fn inspect_message(&self, message: &[u8]) -> Result<(), GrpcError> {
+        let input = match &self.input {
+            Some(input) => { input },
+            None => {
+                return Ok(());
+            }
+        };
+
+        let message = match prost_reflect::DynamicMessage::decode(input.clone(), message) {
+            Ok(message) => { message },
+            Err(err) => {
+                return Err(GrpcError { status: GRPC_STATUS_INVALID_ARGUMENT, message: format!("the message does not match {}, error: {}", input.full_name(), err.to_string()) });
+            }
+        };
+
+        let message = prost_reflect::Value::Message(message);
+
+        for field_rule in self.field_rules.iter() {
+            let path: Vec<&str> = field_rule.field.split('.').collect();
+            let mut values: Vec<String> = Vec::new();
+            collect_field_values(&message, &path, &mut values);
+
+            for value in values.iter() {
+                if field_rule.max_length > 0 && value.len() > field_rule.max_length {
+                    return Err(GrpcError { status: GRPC_STATUS_INVALID_ARGUMENT, message: format!("the field {} exceeds {} bytes", &field_rule.field, field_rule.max_length) });
+                }
+
+                // denied values are matched as case-insensitive substrings
+                let lowercase_value = value.to_lowercase();
+                if field_rule.denied_values.iter().any(|denied_value| lowercase_value.contains(&denied_value.to_lowercase())) {
+                    return Err(GrpcError { status: GRPC_STATUS_PERMISSION_DENIED, message: format!("the field {} holds a denied value", &field_rule.field) });
+                }
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// takes the next block of the request body, every message completed by it is checked
+    pub fn inspect(&mut self, block: &[u8]) -> Result<(), GrpcError> {
+        let result = self.inspect_block(block);
+
+        if let Err(err) = &result {
+            self.refusal = Some(err.clone());
+        }
+
+        return result;
+    }
+
+    /// the reason the call was refused by inspect, if it was
+    pub fn refusal(&self) -> Option<&GrpcError> {
+        return self.refusal.as_ref();
+    }
+
+    fn inspect_block(&mut self, block: &[u8]) -> Result<(), GrpcError> {
+        self.storage.extend_from_slice(block);
+
+        while self.storage.len() >= GRPC_MESSAGE_HEADER_LENGTH {
+            let compressed = self.storage[0] != 0;
+            let message_length = u32::from_be_bytes([self.storage[1], self.storage[2], self.storage[3], self.storage[4]]) as usize;
+
+            // the size is checked on the prefix, an oversized message is refused before it is buffered
+            if message_length > self.max_message_size {
+                return Err(GrpcError { status: GRPC_STATUS_RESOURCE_EXHAUSTED, message: format!("{}/{} message of {} bytes exceeds the limit of {} bytes", &self.service, &self.method, message_length, self.max_message_size) });
+            }
+
+            if self.storage.len() < GRPC_MESSAGE_HEADER_LENGTH + message_length {
+                break;
+            }
+
+            let message: Vec<u8> = self.storage.drain(..GRPC_MESSAGE_HEADER_LENGTH + message_length).skip(GRPC_MESSAGE_HEADER_LENGTH).collect();
+
+            // compressed messages cannot be decoded here, they are refused when the fields of the method are checked
+            if compressed {
+                if self.input.is_some() || !self.field_rules.is_empty() {
+                    return Err(GrpcError { status: GRPC_STATUS_UNIMPLEMENTED, message: format!("{}/{} compressed messages cannot be inspected", &self.service, &self.method) });
+                }
+
+                continue;
+            }
+
+            self.inspect_message(&message)?;
+        }
+
+        return Ok(());
+    }
+
+    pub fn call_name(&self) -> String {
+        return format!("{}/{}", self.service, self.method);
+    }
+}
+
+fn load_descriptor_set(filename: &str) -> Option<prost_reflect::DescriptorPool> {
+    match std::fs::read(filename) {
+        Ok(content) => {
+            match prost_reflect::DescriptorPool::decode(content.as_slice()) {
+                Ok(descriptor_pool) => {
+                    return Some(descriptor_pool);
+                },
+                Err(err) => {
+                    eprintln!("failed to decode the descriptor set {}, error: {}", filename, err.to_string());
+                    return None;
+                }
+            }
+        },
+        Err(err) => {
+            eprintln!("failed to access {}, error: {}", filename, err.to_string());
+            return None;
+        }
+    }
+}
+
+fn load_rules() {
+    match GRPC_RULES_LISTS.lock() {
+        Ok(mut grpc_rule_list) => {
+            println!("loading gRPC rules");
+
+            match std::fs::read_dir(configdb::GRPC_RULES_DIRNAME) {
+                Ok(dir) => {
+                    let mut new_grpc_rule_list: Vec<GrpcRuleEntry> = Vec::new();
+
+                    for file in dir.flatten() {
+                        if let Some(filename) = file.file_name().to_str() {
+                            let filename = format!("{}/{}", configdb::GRPC_RULES_DIRNAME, filename);
+
+                            match std::fs::read_to_string(&filename) {
+                                Ok(content) => {
+                                    match serde_yaml::from_str::<configdb::GrpcRule>(&content) {
+                                        Ok(object) => {
+                                            let descriptor_pool = match object.descriptor_set.is_empty() {
+                                                true => { None },
+                                                false => { load_descriptor_set(&object.descriptor_set) }
+                                            };
+
+                                            new_grpc_rule_list.push((object, descriptor_pool));
+                                        },
+                                        Err(err) => {
+                                            eprintln!("failed to deserialize {}, error: {}", &filename, err.to_string());
+                                        }
+                                    }
+                                },
+                                Err(err) => {
+                                    eprintln!("failed to access {}, error: {}", &filename, err.to_string());
+                                }
+                            }
+                        }
+                    }
+
+                    *grpc_rule_list = new_grpc_rule_list;
+                },
+                Err(err) => {
+                    // gRPC rules are optional, without the folder every call is allowed
+                    eprintln!("failed to enumerate the folder {}, error: {}", configdb::GRPC_RULES_DIRNAME, err.to_string());
+                }
+            }
+        },
+        Err(err) => {
+            eprintln!("internal error, failed to lock GRPC_RULES_LISTS, error: {}; aborting", err.to_string());
+            std::process::abort();
+        }
+    }
+}
+
+fn folder_watch() {
+    let (event_sender, event_receiver) = std::sync::mpsc::channel::<notify::Result<notify::Event>>();
+
+    let mut watcher = match notify::recommended_watcher(event_sender) {
+        Ok(watcher) => {
+            watcher
+        },
+        Err(err) => {
+            eprintln!("failed to monitor the folder {} for update events, error: {}", configdb::GRPC_RULES_DIRNAME, err.to_string());
+            return;
+        }
+    };
+
+    if let Err(err) = watcher.watch(std::path::Path::new(configdb::GRPC_RULES_DIRNAME), notify::RecursiveMode::Recursive) {
+        eprintln!("failed to monitor the folder {} for update events, error: {}", configdb::GRPC_RULES_DIRNAME, err.to_string());
+        return;
+    }
+
+    loop {
+        match event_receiver.recv() {
+            Ok(Ok(_)) => {
+                load_rules();
+            },
+            Ok(Err(err)) => {
+                eprintln!("failed to monitor the folder {} for update events, error: {}; aborting", configdb::GRPC_RULES_DIRNAME, err.to_string());
+                std::process::abort();
+            },
+            Err(err) => {
+                eprintln!("failed to monitor the folder {} for update events, error: {}; aborting", configdb::GRPC_RULES_DIRNAME, err.to_string());
+                std::process::abort();
+            }
+        }
+    }
+}
+
+pub fn initialize() {
+    load_rules();
+
+    std::thread::spawn(|| {
+        folder_watch();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// "test.Greeter/Hello" taking a "test.Request" with a single string field "name"
+    fn request_descriptor() -> prost_reflect::MessageDescriptor {
+        let file = prost_reflect::prost_types::FileDescriptorProto {
+            name: Some("test.proto".to_string()),
+            package: Some("test".to_string()),
+            message_type: vec![prost_reflect::prost_types::DescriptorProto {
+                name: Some("Request".to_string()),
+                field: vec![prost_reflect::prost_types::FieldDescriptorProto {
+                    name: Some("name".to_string()),
+                    number: Some(1),
+                    label: Some(prost_reflect::prost_types::field_descriptor_proto::Label::Optional as i32),
+                    r#type: Some(prost_reflect::prost_types::field_descriptor_proto::Type::String as i32),
+                    json_name: Some("name".to_string()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            syntax: Some("proto3".to_string()),
+            ..Default::default()
+        };
+
+        let mut descriptor_pool = prost_reflect::DescriptorPool::new();
+        descriptor_pool.add_file_descriptor_proto(file).unwrap();
+        return descriptor_pool.get_message_by_name("test.Request").unwrap();
+    }
+
+    fn inspector(max_message_size: usize, field_rules: Vec<configdb::GrpcFieldRule>, input: Option<prost_reflect::MessageDescriptor>) -> MessageInspector {
+        return MessageInspector { service: "test.Greeter".to_string(), method: "Hello".to_string(), max_message_size, field_rules, input, storage: Vec::new(), refusal: None };
+    }
+
+    fn denied_name_rule() -> Vec<configdb::GrpcFieldRule> {
+        return vec![configdb::GrpcFieldRule { field: "name".to_string(), max_length: 16, denied_values: vec!["DROP TABLE".to_string()] }];
+    }
+
+    /// a request message whose "name" field holds the value
+    fn request_message(name: &str) -> Vec<u8> {
+        let mut result = vec![0x0a, name.len() as u8];
+        result.extend_from_slice(name.as_bytes());
+        return result;
+    }
+
+    fn frame(compressed: bool, message: &[u8]) -> Vec<u8> {
+        let mut result = vec![compressed as u8];
+        result.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        result.extend_from_slice(message);
+        return result;
+    }
+
+    #[test]
+    fn checks_a_message_split_across_blocks_once_complete() {
+        let mut message_inspector = inspector(1024, denied_name_rule(), Some(request_descriptor()));
+        let block = frame(false, &request_message("x; drop table"));
+
+        assert!(message_inspector.inspect(&block[..3]).is_ok());
+        assert!(message_inspector.inspect(&block[3..9]).is_ok());
+
+        let refusal = message_inspector.inspect(&block[9..]).err().unwrap();
+        assert_eq!(refusal.status, GRPC_STATUS_PERMISSION_DENIED);
+        assert_eq!(message_inspector.refusal().map(|refusal| refusal.status), Some(GRPC_STATUS_PERMISSION_DENIED));
+    }
+
+    #[test]
+    fn checks_every_message_of_a_block() {
+        let mut message_inspector = inspector(1024, denied_name_rule(), Some(request_descriptor()));
+        let mut block = frame(false, &request_message("alice"));
+        block.extend(frame(false, &request_message("a much longer name")));
+
+        assert_eq!(message_inspector.inspect(&block).err().map(|refusal| refusal.status), Some(GRPC_STATUS_INVALID_ARGUMENT));
+    }
+
+    #[test]
+    fn refuses_an_oversized_message_from_its_prefix() {
+        let mut message_inspector = inspector(8, Vec::new(), None);
+
+        assert!(message_inspector.inspect(&frame(false, &[0; 8])).is_ok());
+        assert_eq!(message_inspector.inspect(&frame(false, &[0; 9])[..5]).err().map(|refusal| refusal.status), Some(GRPC_STATUS_RESOURCE_EXHAUSTED));
+    }
+
+    #[test]
+    fn refuses_compressed_messages_when_fields_are_checked() {
+        let mut message_inspector = inspector(1024, denied_name_rule(), Some(request_descriptor()));
+
+        assert_eq!(message_inspector.inspect(&frame(true, &request_message("alice"))).err().map(|refusal| refusal.status), Some(GRPC_STATUS_UNIMPLEMENTED));
+    }
+
+    #[test]
+    fn passes_compressed_messages_without_field_checks() {
+        let mut message_inspector = inspector(1024, Vec::new(), None);
+
+        assert!(message_inspector.inspect(&frame(true, &[0x1f, 0x8b, 0x08, 0x00])).is_ok());
+        assert!(message_inspector.inspect(&frame(true, &[0; 2048])[..5]).is_err());
+    }
+
+    #[test]
+    fn method_rule_takes_precedence_over_the_service_wildcard() {
+        match GRPC_RULES_LISTS.lock() {
+            Ok(mut grpc_rule_list) => {
+                grpc_rule_list.push((configdb::GrpcRule { service: "test.Precedence".to_string(), method: "*".to_string(), ingress: configdb::RuleGress::Deny, ..Default::default() }, None));
+                grpc_rule_list.push((configdb::GrpcRule { service: "test.Precedence".to_string(), method: "Hello".to_string(), ingress: configdb::RuleGress::Allow, ..Default::default() }, None));
+            },
+            Err(err) => {
+                panic!("failed to lock GRPC_RULES_LISTS, error: {}", err.to_string());
+            }
+        }
+
+        assert_eq!(get_grpc_rule("test.Precedence", "Hello").map(|rule| rule.0.method), Some("Hello".to_string()));
+        assert_eq!(get_grpc_rule("test.Precedence", "Goodbye").map(|rule| rule.0.method), Some("*".to_string()));
+        assert!(get_grpc_rule("test.Other", "Hello").is_none());
+    }
+
+    #[test]
+    fn splits_the_method_path() {
+        assert_eq!(split_path("/test.Greeter/Hello"), Some(("test.Greeter".to_string(), "Hello".to_string())));
+        assert!(split_path("/test.Greeter/Hello/extra").is_none());
+        assert!(split_path("test.Greeter/Hello").is_none());
+        assert!(split_path("/test.Greeter/").is_none());
+    }
+}
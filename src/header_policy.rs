@@ -0,0 +1,523 @@
+use crate::configdb;
+use crate::cookie_protection;
+use crate::csrf;
+use crate::host_rule;
+use crate::http1;
+use crate::location_rule;
+
+/// headers telling which software and version the edge server runs
+const SERVER_HEADERS: [&str; 3] = ["server", "x-powered-by", "x-aspnet-version"];
+const HOST_PREFIX: &str = "__Host-";
+
+/// what is applied to a request and its responses, each part comes from the location rule or else the host rule
+#[derive(Clone, Default)]
+pub struct ResponsePolicy {
+    pub security_headers: Option<configdb::SecurityHeaders>,
+    pub cookie_policy: Option<configdb::CookiePolicy>,
+    pub csrf: Option<configdb::CsrfPolicy>,
+}
+
+pub fn get_response_policy(object: &http1::Http) -> ResponsePolicy {
+    let location_rule = location_rule::get_location_rule(&object.method, &object.location).unwrap_or_default();
+    let mut result = ResponsePolicy { security_headers: location_rule.security_headers, cookie_policy: location_rule.cookie_policy, csrf: location_rule.csrf };
+
+    if result.security_headers.is_none() || result.cookie_policy.is_none() || result.csrf.is_none() {
+        if let Some(host_rule) = host_rule::get_host_rule(host_rule::get_hostname(object)) {
+            result.security_headers = result.security_headers.or(host_rule.security_headers);
+            result.cookie_policy = result.cookie_policy.or(host_rule.cookie_policy);
+            result.csrf = result.csrf.or(host_rule.csrf);
+        }
+    }
+
+    return result;
+}
+
+fn get_injected_headers(security_headers: &configdb::SecurityHeaders) -> Vec<(&'static str, &String)> {
+    let injected_headers = [
+        ("Strict-Transport-Security", &security_headers.strict_transport_security),
+        ("Content-Security-Policy", &security_headers.content_security_policy),
+        ("X-Content-Type-Options", &security_headers.x_content_type_options),
+        ("X-Frame-Options", &security_headers.x_frame_options),
+        ("Referrer-Policy", &security_headers.referrer_policy),
+        ("Permissions-Policy", &security_headers.permissions_policy),
+    ];
+
+    return injected_headers.into_iter().filter(|(_, value)| !value.is_empty()).collect();
+}
+
+fn apply_security_headers(headers: &mut Vec<(String, String)>, security_headers: &configdb::SecurityHeaders) {
+    if security_headers.strip_server_headers {
+        headers.retain(|(name, _)| !SERVER_HEADERS.contains(&name.to_ascii_lowercase().as_str()));
+    }
+
+    for (name, value) in get_injected_headers(security_headers) {
+        let present = headers.iter().any(|(header_name, _)| header_name.eq_ignore_ascii_case(name));
+
+        if present && security_headers.injection == configdb::HeaderInjection::Missing {
+            continue;
+        }
+
+        headers.retain(|(header_name, _)| !header_name.eq_ignore_ascii_case(name));
+        headers.push((name.to_string(), value.clone()));
+    }
+}
+
+fn apply_security_headers_to_map(headers: &mut http::HeaderMap, security_headers: &configdb::SecurityHeaders) {
+    if security_headers.strip_server_headers {
+        for name in SERVER_HEADERS.iter() {
+            headers.remove(*name);
+        }
+    }
+
+    for (name, value) in get_injected_headers(security_headers) {
+        let name = match http::header::HeaderName::from_bytes(name.as_bytes()) {
+            Ok(name) => { name },
+            Err(_) => { continue; }
+        };
+
+        if headers.contains_key(&name) && security_headers.injection == configdb::HeaderInjection::Missing {
+            continue;
+        }
+
+        match http::header::HeaderValue::from_str(value) {
+            Ok(value) => {
+                headers.insert(name, value);
+            },
+            Err(err) => {
+                eprintln!("invalid value for the security header {}, error: {}", name, err.to_string());
+            }
+        }
+    }
+}
+
+fn attribute_name(attribute: &str) -> &str {
+    return attribute.split('=').next().unwrap_or_default().trim();
+}
+
+fn find_attribute<'a>(attributes: &'a [String], name: &str) -> Option<&'a String> {
+    return attributes.iter().find(|attribute| attribute_name(attribute).eq_ignore_ascii_case(name));
+}
+
+fn set_attribute(attributes: &mut Vec<String>, name: &str, attribute: String) {
+    attributes.retain(|existing| !attribute_name(existing).eq_ignore_ascii_case(name));
+    attributes.push(attribute);
+}
+
+fn is_in_scope(cookie_policy: &configdb::CookiePolicy, name: &str) -> bool {
+    return cookie_policy.cookies.is_empty() || cookie_policy.cookies.iter().any(|cookie| cookie == name);
+}
+
+/// the Set-Cookie value once hardened and protected, and the attributes it was missing
+fn harden_set_cookie(set_cookie: &str, cookie_policy: &configdb::CookiePolicy) -> Option<(String, String, Vec<&'static str>)> {
+    let mut parts = set_cookie.split(';');
+    let (name, value) = parts.next()?.split_once('=')?;
+    let name = name.trim();
+
+    let hardened = is_in_scope(cookie_policy, name);
+    let protected = cookie_protection::is_protected(cookie_policy, name);
+
+    if !hardened && !protected {
+        return None;
+    }
+
+    let mut attributes: Vec<String> = parts.map(|attribute| attribute.trim().to_string()).filter(|attribute| !attribute.is_empty()).collect();
+    let same_site = cookie_policy.same_site.trim();
+    // browsers drop SameSite=None cookies and prefixed cookies that are not Secure
+    let secure = cookie_policy.secure || same_site.eq_ignore_ascii_case("none") || !cookie_policy.prefix.is_empty();
+    let mut violations: Vec<&'static str> = Vec::new();
+
+    if hardened {
+        if secure && find_attribute(&attributes, "secure").is_none() {
+            violations.push("Secure");
+        }
+
+        if cookie_policy.http_only && find_attribute(&attributes, "httponly").is_none() {
+            violations.push("HttpOnly");
+        }
+
+        if !same_site.is_empty() {
+            let current = find_attribute(&attributes, "samesite").and_then(|attribute| attribute.split_once('=')).map(|(_, value)| value.trim());
+
+            if !current.is_some_and(|current| current.eq_ignore_ascii_case(same_site)) {
+                violations.push("SameSite");
+            }
+        }
+    }
+
+    if cookie_policy.log_only {
+        return Some((name.to_string(), set_cookie.to_string(), violations));
+    }
+
+    let mut cookie_name = name.to_string();
+
+    if hardened {
+        if secure {
+            set_attribute(&mut attributes, "secure", "Secure".to_string());
+        }
+
+        if cookie_policy.http_only {
+            set_attribute(&mut attributes, "httponly", "HttpOnly".to_string());
+        }
+
+        if !same_site.is_empty() {
+            set_attribute(&mut attributes, "samesite", format!("SameSite={}", same_site));
+        }
+
+        // a __Host- cookie is bound to the host that set it, for the whole site
+        if cookie_policy.prefix == HOST_PREFIX {
+            attributes.retain(|attribute| !attribute_name(attribute).eq_ignore_ascii_case("domain"));
+            set_attribute(&mut attributes, "path", "Path=/".to_string());
+        }
+
+        let renamed = match cookie_policy.renamed_cookies.iter().find(|renamed_cookie| renamed_cookie.name == name) {
+            Some(renamed_cookie) => { renamed_cookie.renamed_to.as_str() },
+            None => { name }
+        };
+
+        cookie_name = format!("{}{}", cookie_policy.prefix, renamed);
+    }
+
+    let value = match protected {
+        true => {
+            match cookie_protection::seal(cookie_policy, name, value) {
+                Ok(value) => { value },
+                Err(err) => {
+                    eprintln!("failed to protect the cookie {}, error: {}", name, err.to_string());
+                    return None;
+                }
+            }
+        },
+        false => { value.to_string() }
+    };
+
+    let mut result = format!("{}={}", cookie_name, value);
+    for attribute in attributes.iter() {
+        result.push_str("; ");
+        result.push_str(attribute);
+    }
+
+    return Some((name.to_string(), result, violations));
+}
+
+fn log_violations(name: &str, violations: &[&'static str], cookie_policy: &configdb::CookiePolicy, method: &str, location: &str) {
+    if violations.is_empty() {
+        return;
+    }
+
+    let outcome = match cookie_policy.log_only {
+        true => { "passed as it is" },
+        false => { "fixed" }
+    };
+
+    println!("cookie {} set in the response to {} {} lacks {}; {}", name, method, location, violations.join(", "), outcome);
+}
+
+fn apply_cookie_policy(headers: &mut [(String, String)], cookie_policy: &configdb::CookiePolicy, method: &str, location: &str) {
+    for (name, value) in headers.iter_mut() {
+        if !name.eq_ignore_ascii_case("set-cookie") {
+            continue;
+        }
+
+        if let Some((cookie_name, hardened, violations)) = harden_set_cookie(value, cookie_policy) {
+            log_violations(&cookie_name, &violations, cookie_policy, method, location);
+            *value = hardened;
+        }
+    }
+}
+
+fn apply_cookie_policy_to_map(headers: &mut http::HeaderMap, cookie_policy: &configdb::CookiePolicy, method: &str, location: &str) {
+    let set_cookies: Vec<http::header::HeaderValue> = headers.get_all(http::header::SET_COOKIE).iter().cloned().collect();
+    if set_cookies.is_empty() {
+        return;
+    }
+
+    headers.remove(http::header::SET_COOKIE);
+
+    for set_cookie in set_cookies.into_iter() {
+        let hardened = set_cookie.to_str().ok().and_then(|value| harden_set_cookie(value, cookie_policy));
+
+        match hardened {
+            Some((cookie_name, hardened, violations)) => {
+                log_violations(&cookie_name, &violations, cookie_policy, method, location);
+
+                match http::header::HeaderValue::from_str(&hardened) {
+                    Ok(hardened) => {
+                        headers.append(http::header::SET_COOKIE, hardened);
+                    },
+                    Err(_) => {
+                        headers.append(http::header::SET_COOKIE, set_cookie);
+                    }
+                }
+            },
+            None => {
+                headers.append(http::header::SET_COOKIE, set_cookie);
+            }
+        }
+    }
+}
+
+/// applies the policy to the headers of an HTTP/1.1 response
+pub fn apply_response_policy(headers: &mut Vec<(String, String)>, response_policy: &ResponsePolicy, method: &str, location: &str) {
+    if let Some(security_headers) = &response_policy.security_headers {
+        apply_security_headers(headers, security_headers);
+    }
+
+    if let Some(cookie_policy) = &response_policy.cookie_policy {
+        apply_cookie_policy(headers, cookie_policy, method, location);
+    }
+}
+
+/// applies the policy of the request to the headers of an HTTP/2 or HTTP/3 response
+pub fn harden_response(headers: &mut http::HeaderMap, object: &http1::Http) {
+    let response_policy = get_response_policy(object);
+
+    if let Some(security_headers) = &response_policy.security_headers {
+        apply_security_headers_to_map(headers, security_headers);
+    }
+
+    if let Some(cookie_policy) = &response_policy.cookie_policy {
+        apply_cookie_policy_to_map(headers, cookie_policy, &object.method, &object.location);
+    }
+
+    // the forms are not rewritten here, the token is only given for the scripts to send it back in a header
+    if let Some(csrf_policy) = &response_policy.csrf {
+        let html = headers.get(http::header::CONTENT_TYPE).and_then(|content_type| content_type.to_str().ok()).is_some_and(|content_type| content_type.to_ascii_lowercase().contains("text/html"));
+
+        if html && csrf::find_cookie_token(object, csrf_policy).is_none() {
+            if let Ok(set_cookie) = http::header::HeaderValue::from_str(&csrf::create_set_cookie(csrf_policy, &csrf::create_token())) {
+                headers.append(http::header::SET_COOKIE, set_cookie);
+            }
+        }
+    }
+}
+
+/// the name the edge server gave the cookie, before the prefix and the renaming
+fn find_original_name<'a>(cookie_policy: &'a configdb::CookiePolicy, name: &'a str) -> &'a str {
+    // every cookie hardened carries the prefix, the others are the client's own
+    let unprefixed = match name.strip_prefix(cookie_policy.prefix.as_str()) {
+        Some(unprefixed) => { unprefixed },
+        None => { return name; }
+    };
+
+    let original = match cookie_policy.renamed_cookies.iter().find(|renamed_cookie| renamed_cookie.renamed_to == unprefixed) {
+        Some(renamed_cookie) => { renamed_cookie.name.as_str() },
+        None => { unprefixed }
+    };
+
+    match is_in_scope(cookie_policy, original) {
+        true => { return original; },
+        false => { return name; }
+    }
+}
+
+/// the Cookie header with the names and values the edge server gave its cookies
+pub fn restore_cookie_names(cookie: &str, cookie_policy: &configdb::CookiePolicy) -> String {
+    if cookie_policy.log_only {
+        return cookie.to_string();
+    }
+
+    let mut result: Vec<String> = Vec::new();
+
+    for pair in cookie.split(';') {
+        let pair = pair.trim();
+
+        let (name, value) = match pair.split_once('=') {
+            Some((name, value)) => { (name.trim(), value) },
+            None => {
+                result.push(pair.to_string());
+                continue;
+            }
+        };
+
+        let original = find_original_name(cookie_policy, name);
+
+        if cookie_protection::is_protected(cookie_policy, original) {
+            // a cookie failing the verification never reaches the edge server, the request was dropped already
+            if let Some(value) = cookie_protection::open(cookie_policy, original, value) {
+                result.push(format!("{}={}", original, value));
+            }
+        } else if original != name {
+            result.push(format!("{}={}", original, value));
+        } else {
+            result.push(pair.to_string());
+        }
+    }
+
+    return result.join("; ");
+}
+
+/// the first protected cookie of the request whose signature or encryption does not hold; the policy is found
+/// through the Host the client chose, so a sealed cookie of another rule must hold under that rule as well
+pub fn find_tampered_cookie(object: &http1::Http) -> Option<String> {
+    let cookie = http1::find_property(&object.properties, "cookie")?;
+    let cookie_policy = get_response_policy(object).cookie_policy;
+
+    let mut cookie_policies = host_rule::get_cookie_policies();
+    cookie_policies.extend(location_rule::get_cookie_policies());
+
+    return find_tampered_pair(cookie, &cookie_policy, &cookie_policies);
+}
+
+/// the Cookie header checked against the policy of the request and, for the sealed values, every other policy
+fn find_tampered_pair(cookie: &str, cookie_policy: &Option<configdb::CookiePolicy>, cookie_policies: &[configdb::CookiePolicy]) -> Option<String> {
+    for pair in cookie.split(';') {
+        if let Some((name, value)) = pair.split_once('=') {
+            let name = name.trim();
+            let value = value.trim();
+
+            if let Some(cookie_policy) = cookie_policy {
+                let original = find_original_name(cookie_policy, name);
+
+                if cookie_protection::is_protected(cookie_policy, original) {
+                    if cookie_protection::open(cookie_policy, original, value).is_none() {
+                        return Some(name.to_string());
+                    }
+
+                    continue;
+                }
+            }
+
+            // a value sealed for another rule but not verified here would reach the edge server as the client wrote
+            // it; plain values are the host's own cookies sharing the name
+            let tampered = cookie_policies.iter().any(|other_policy| {
+                let original = find_original_name(other_policy, name);
+                cookie_protection::is_protected(other_policy, original) && cookie_protection::looks_sealed(other_policy, value) && cookie_protection::open(other_policy, original, value).is_none()
+            });
+
+            if tampered {
+                return Some(name.to_string());
+            }
+        }
+    }
+
+    return None;
+}
+
+/// the request head with the Cookie headers restored for the edge server
+pub fn restore_request_cookies(head: &[u8], cookie_policy: &configdb::CookiePolicy) -> Vec<u8> {
+    let head = String::from_utf8_lossy(head);
+    let mut result = String::new();
+
+    for line in head.split_inclusive("\r\n") {
+        match line.split_once(':') {
+            Some((name, value)) if name.eq_ignore_ascii_case("cookie") => {
+                result.push_str(&format!("{}: {}\r\n", name, restore_cookie_names(value.trim(), cookie_policy)));
+            },
+            _ => {
+                result.push_str(line);
+            }
+        }
+    }
+
+    return result.into_bytes();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn protected_policy() -> configdb::CookiePolicy {
+        return configdb::CookiePolicy {
+            protected_cookies: vec!["session".to_string()],
+            protection: configdb::CookieProtection::Signed,
+            protection_keys: vec!["key".to_string()],
+            ..Default::default()
+        };
+    }
+
+    fn hardening_policy() -> configdb::CookiePolicy {
+        return configdb::CookiePolicy { secure: true, http_only: true, same_site: String::from("Lax"), ..Default::default() };
+    }
+
+    fn hardened(set_cookie: &str, cookie_policy: &configdb::CookiePolicy) -> Option<(String, Vec<&'static str>)> {
+        return harden_set_cookie(set_cookie, cookie_policy).map(|(_, hardened, violations)| (hardened, violations));
+    }
+
+    #[test]
+    fn adds_the_missing_cookie_attributes() {
+        let result = hardened("session=abc; Path=/app; samesite=none", &hardening_policy());
+        assert_eq!(result, Some((String::from("session=abc; Path=/app; Secure; HttpOnly; SameSite=Lax"), vec!["Secure", "HttpOnly", "SameSite"])));
+
+        let result = hardened("session=abc; Secure; HttpOnly; SameSite=lax", &hardening_policy());
+        assert_eq!(result.map(|(_, violations)| violations), Some(Vec::new()));
+    }
+
+    #[test]
+    fn leaves_the_cookies_out_of_scope_alone() {
+        let cookie_policy = configdb::CookiePolicy { cookies: vec![String::from("session")], ..hardening_policy() };
+        assert_eq!(hardened("theme=dark", &cookie_policy), None);
+    }
+
+    #[test]
+    fn log_only_reports_without_changing_the_cookie() {
+        let cookie_policy = configdb::CookiePolicy { log_only: true, prefix: String::from(HOST_PREFIX), ..hardening_policy() };
+        let result = hardened("session=abc; Domain=example.com", &cookie_policy);
+
+        assert_eq!(result, Some((String::from("session=abc; Domain=example.com"), vec!["Secure", "HttpOnly", "SameSite"])));
+    }
+
+    #[test]
+    fn host_prefix_drops_the_domain_and_covers_the_whole_site() {
+        let cookie_policy = configdb::CookiePolicy { prefix: String::from(HOST_PREFIX), ..Default::default() };
+        let result = hardened("session=abc; Domain=example.com; Path=/app", &cookie_policy);
+
+        assert_eq!(result.map(|(hardened, _)| hardened), Some(String::from("__Host-session=abc; Secure; Path=/")));
+    }
+
+    #[test]
+    fn renamed_and_prefixed_cookies_get_their_name_back() {
+        let cookie_policy = configdb::CookiePolicy {
+            prefix: String::from("__Secure-"),
+            renamed_cookies: vec![configdb::RenamedCookie { name: String::from("PHPSESSID"), renamed_to: String::from("sid") }],
+            ..Default::default()
+        };
+
+        let set_cookie = hardened("PHPSESSID=abc; Path=/", &cookie_policy).map(|(hardened, _)| hardened).unwrap();
+        assert_eq!(set_cookie, "__Secure-sid=abc; Path=/; Secure");
+
+        let theme = hardened("theme=dark", &cookie_policy).map(|(hardened, _)| hardened).unwrap();
+        assert_eq!(theme, "__Secure-theme=dark; Secure");
+
+        // the client sends back the names it was given, its own unprefixed cookies are passed as they are
+        let cookie = "__Secure-sid=abc; __Secure-theme=dark; sid=mine";
+        assert_eq!(restore_cookie_names(cookie, &cookie_policy), "PHPSESSID=abc; theme=dark; sid=mine");
+    }
+
+    #[test]
+    fn protected_cookies_round_trip_through_the_client() {
+        let cookie_policy = configdb::CookiePolicy { prefix: String::from(HOST_PREFIX), ..protected_policy() };
+
+        let set_cookie = hardened("session=alice; Path=/", &cookie_policy).map(|(hardened, _)| hardened).unwrap();
+        let (pair, _) = set_cookie.split_once(';').unwrap();
+        assert!(pair.starts_with("__Host-session=alice."));
+
+        assert_eq!(restore_cookie_names(&format!("{}; theme=dark", pair), &cookie_policy), "session=alice; theme=dark");
+    }
+
+    #[test]
+    fn an_unprotected_host_keeps_its_own_same_named_cookie() {
+        let other_policies = vec![protected_policy()];
+
+        assert_eq!(find_tampered_pair("session=abc123; theme=dark", &None, &other_policies), None);
+        assert_eq!(find_tampered_pair("session=abc123", &Some(configdb::CookiePolicy::default()), &other_policies), None);
+    }
+
+    #[test]
+    fn a_sealed_cookie_of_another_rule_must_open_under_it() {
+        let other_policies = vec![protected_policy()];
+        let sealed = cookie_protection::seal(&protected_policy(), "session", "alice").unwrap();
+        let forged = sealed.replacen("alice", "admin", 1);
+
+        assert_eq!(find_tampered_pair(&format!("session={}", sealed), &None, &other_policies), None);
+        assert_eq!(find_tampered_pair(&format!("session={}", forged), &None, &other_policies), Some("session".to_string()));
+    }
+
+    #[test]
+    fn a_protected_cookie_must_open_under_the_request_policy() {
+        let cookie_policy = Some(protected_policy());
+        let sealed = cookie_protection::seal(&protected_policy(), "session", "alice").unwrap();
+
+        assert_eq!(find_tampered_pair(&format!("theme=dark; session={}", sealed), &cookie_policy, &[]), None);
+        assert_eq!(find_tampered_pair("theme=dark; session=alice", &cookie_policy, &[]), Some("session".to_string()));
+    }
+}
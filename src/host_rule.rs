@@ -0,0 +1,178 @@
+use notify::Watcher;
+
+use crate::cert_store;
+use crate::configdb;
+use crate::http1;
+
+lazy_static::lazy_static! {
+    #[allow(non_upper_case_globals)]
+    static ref HOST_RULES_LISTS: std::sync::Arc<std::sync::Mutex<Vec<configdb::HostRule>>> = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+}
+
+/// the hostname of the request without the port
+pub fn get_hostname(object: &http1::Http) -> String {
+    let host = match http1::find_property(&object.properties, "host") {
+        Some(host) => { host.trim() },
+        None => { return String::new(); }
+    };
+
+    // a bracketed IPv6 address keeps its colons
+    let hostname = match host.rsplit_once(':') {
+        Some((hostname, port)) if !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()) && (!hostname.contains(':') || hostname.ends_with(']')) => {
+            hostname
+        },
+        _ => {
+            host
+        }
+    };
+
+    // the fully qualified form names the same host
+    return hostname.strip_suffix('.').unwrap_or(hostname).to_ascii_lowercase();
+}
+
+/// exact hostnames take precedence over wildcards, "*" applies to any host left
+pub fn get_host_rule<Hostname: AsRef<str>>(hostname: Hostname) -> Option<configdb::HostRule> {
+    match HOST_RULES_LISTS.lock() {
+        Ok(host_rule_list) => {
+            let hostname = hostname.as_ref();
+
+            if let Some(rule) = host_rule_list.iter().find(|rule| !rule.hostname.starts_with('*') && cert_store::hostname_matches(&rule.hostname, hostname)) {
+                return Some(rule.clone());
+            }
+
+            if let Some(rule) = host_rule_list.iter().find(|rule| rule.hostname.starts_with("*.") && cert_store::hostname_matches(&rule.hostname, hostname)) {
+                return Some(rule.clone());
+            }
+
+            return host_rule_list.iter().find(|rule| rule.hostname == "*").cloned();
+        },
+        Err(err) => {
+            eprintln!("internal error, failed to lock HOST_RULES_LISTS, error: {}; aborting", err.to_string());
+            std::process::abort();
+        }
+    }
+}
+
+/// the cookie policies of every host rule
+pub fn get_cookie_policies() -> Vec<configdb::CookiePolicy> {
+    match HOST_RULES_LISTS.lock() {
+        Ok(host_rule_list) => {
+            return host_rule_list.iter().filter_map(|rule| rule.cookie_policy.clone()).collect();
+        },
+        Err(err) => {
+            eprintln!("internal error, failed to lock HOST_RULES_LISTS, error: {}; aborting", err.to_string());
+            std::process::abort();
+        }
+    }
+}
+
+fn load_rules() {
+    match HOST_RULES_LISTS.lock() {
+        Ok(mut host_rule_list) => {
+            println!("loading host rules");
+
+            match std::fs::read_dir(configdb::HOST_RULES_DIRNAME) {
+                Ok(dir) => {
+                    let mut new_host_rule_list: Vec<configdb::HostRule> = Vec::new();
+
+                    for file in dir.flatten() {
+                        if let Some(filename) = file.file_name().to_str() {
+                            let filename = format!("{}/{}", configdb::HOST_RULES_DIRNAME, filename);
+
+                            match std::fs::read_to_string(&filename) {
+                                Ok(content) => {
+                                    match serde_yaml::from_str::<configdb::HostRule>(&content) {
+                                        Ok(object) => {
+                                            new_host_rule_list.push(object);
+                                        },
+                                        Err(err) => {
+                                            eprintln!("failed to deserialize {}, error: {}", &filename, err.to_string());
+                                        }
+                                    }
+                                },
+                                Err(err) => {
+                                    eprintln!("failed to access {}, error: {}", &filename, err.to_string());
+                                }
+                            }
+                        }
+                    }
+
+                    *host_rule_list = new_host_rule_list;
+                },
+                Err(err) => {
+                    // host rules are optional, without the folder responses are passed as the edge servers send them
+                    eprintln!("failed to enumerate the folder {}, error: {}", configdb::HOST_RULES_DIRNAME, err.to_string());
+                }
+            }
+        },
+        Err(err) => {
+            eprintln!("internal error, failed to lock HOST_RULES_LISTS, error: {}; aborting", err.to_string());
+            std::process::abort();
+        }
+    }
+}
+
+fn folder_watch() {
+    let (event_sender, event_receiver) = std::sync::mpsc::channel::<notify::Result<notify::Event>>();
+
+    let mut watcher = match notify::recommended_watcher(event_sender) {
+        Ok(watcher) => {
+            watcher
+        },
+        Err(err) => {
+            eprintln!("failed to monitor the folder {} for update events, error: {}", configdb::HOST_RULES_DIRNAME, err.to_string());
+            return;
+        }
+    };
+
+    if let Err(err) = watcher.watch(std::path::Path::new(configdb::HOST_RULES_DIRNAME), notify::RecursiveMode::Recursive) {
+        eprintln!("failed to monitor the folder {} for update events, error: {}", configdb::HOST_RULES_DIRNAME, err.to_string());
+        return;
+    }
+
+    loop {
+        match event_receiver.recv() {
+            Ok(Ok(_)) => {
+                load_rules();
+            },
+            Ok(Err(err)) => {
+                eprintln!("failed to monitor the folder {} for update events, error: {}; aborting", configdb::HOST_RULES_DIRNAME, err.to_string());
+                std::process::abort();
+            },
+            Err(err) => {
+                eprintln!("failed to monitor the folder {} for update events, error: {}; aborting", configdb::HOST_RULES_DIRNAME, err.to_string());
+                std::process::abort();
+            }
+        }
+    }
+}
+
+pub fn initialize() {
+    load_rules();
+
+    std::thread::spawn(|| {
+        folder_watch();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hostname_of(host: &str) -> String {
+        let mut object = http1::Http::default();
+        object.properties.insert("Host".to_string(), host.to_string());
+        return get_hostname(&object);
+    }
+
+    #[test]
+    fn hostname_drops_the_port_and_the_trailing_dot() {
+        assert_eq!(hostname_of("Example.COM"), "example.com");
+        assert_eq!(hostname_of("example.com:8443"), "example.com");
+        assert_eq!(hostname_of("example.com."), "example.com");
+        assert_eq!(hostname_of("example.com.:443"), "example.com");
+        assert_eq!(hostname_of("[::1]:443"), "[::1]");
+        assert_eq!(hostname_of("::1"), "::1");
+        assert_eq!(get_hostname(&http1::Http::default()), "");
+    }
+}
@@ -65,3 +65,355 @@ pub fn parse(block: Vec<u8>) -> Result<Http, std::io::Error> {
         }
     }
 }
+
+#[derive(Clone, Default)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub reason: String,
+    pub properties: std::collections::HashMap<String, String>,
+    /// every header line in order, repeated headers such as Set-Cookie included
+    pub headers: Vec<(String, String)>
+}
+
+/// header names are case-insensitive, the properties keep the spelling used on the wire
+pub fn find_property<'a>(properties: &'a std::collections::HashMap<String, String>, name: &str) -> Option<&'a String> {
+    for (key, value) in properties.iter() {
+        if key.eq_ignore_ascii_case(name) {
+            return Some(value);
+        }
+    }
+
+    return None;
+}
+
+/// the head without the lines of the named header
+pub fn remove_header(head: &[u8], name: &str) -> Vec<u8> {
+    let head = String::from_utf8_lossy(head);
+    let prefix = format!("{}:", name.to_ascii_lowercase());
+    let mut result = String::new();
+
+    for line in head.split_inclusive("\r\n") {
+        if line.to_ascii_lowercase().starts_with(&prefix) {
+            continue;
+        }
+
+        result.push_str(line);
+    }
+
+    return result.into_bytes();
+}
+
+pub fn parse_response(block: Vec<u8>) -> Result<HttpResponse, std::io::Error> {
+    match String::from_utf8(block) {
+        Ok(block) => {
+            let mut result = HttpResponse::default();
+
+            for (idx, line) in block.trim().lines().enumerate() {
+                let line = line.trim();
+
+                match idx {
+                    0 => {
+                        let storage: Vec<&str> = line.splitn(3, ' ').collect();
+
+                        match storage.get(0) {
+                            Some(protocol) => {
+                                if *protocol != "HTTP/1.1" && *protocol != "HTTP/1.0" {
+                                    return Err(std::io::Error::new(std::io::ErrorKind::Other, "unsupported HTTP version"));
+                                }
+                            },
+                            None => {
+                                return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("faulty line is '{}'", line)));
+                            }
+                        }
+
+                        match storage.get(1) {
+                            Some(status) => {
+                                match status.parse::<u16>() {
+                                    Ok(status) => {
+                                        result.status = status;
+                                    },
+                                    Err(_) => {
+                                        return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("faulty line is '{}'", line)));
+                                    }
+                                }
+                            },
+                            None => {
+                                return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("faulty line is '{}'", line)));
+                            }
+                        }
+
+                        result.reason = storage.get(2).unwrap_or(&"").to_string();
+                    },
+                    _ => {
+                        let storage: Vec<&str> = line.splitn(2, ':').collect();
+
+                        if storage.len() == 2 {
+                            result.properties.insert(storage[0].trim().to_string(), storage[1].trim().to_string());
+                            result.headers.push((storage[0].trim().to_string(), storage[1].trim().to_string()));
+                        } else {
+                            return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("faulty line is '{}'", line)));
+                        }
+                    }
+                }
+            }
+
+            return Ok(result);
+        },
+        Err(err) => {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+        }
+    }
+}
+
+/// decodes a complete chunked body, returns None while the terminating chunk has not been received yet
+pub fn decode_chunked(block: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+    let mut result: Vec<u8> = Vec::new();
+    let mut idx: usize = 0;
+
+    loop {
+        let line_length = match block[idx..].windows(2).position(|a| a == b"\r\n") {
+            Some(line_length) => { line_length },
+            None => {
+                return Ok(None);
+            }
+        };
+
+        let size_line = String::from_utf8_lossy(&block[idx..idx + line_length]).to_string();
+        let size_field = size_line.split(';').next().unwrap_or("").trim();
+
+        let chunk_size = match usize::from_str_radix(size_field, 16) {
+            Ok(chunk_size) => { chunk_size },
+            Err(err) => {
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("faulty chunk size '{}', error: {}", size_line, err.to_string())));
+            }
+        };
+
+        idx = idx + line_length + 2;
+
+        if chunk_size == 0 {
+            // the trailer section ends with an empty line
+            match block[idx..].windows(2).position(|a| a == b"\r\n") {
+                Some(0) => {
+                    return Ok(Some(result));
+                },
+                Some(_) => {
+                    match block[idx..].windows(4).position(|a| a == b"\r\n\r\n") {
+                        Some(_) => {
+                            return Ok(Some(result));
+                        },
+                        None => {
+                            return Ok(None);
+                        }
+                    }
+                },
+                None => {
+                    return Ok(None);
+                }
+            }
+        }
+
+        // the size comes from the peer, a huge one must not wrap around
+        let chunk_end = match idx.checked_add(chunk_size).and_then(|chunk_end| chunk_end.checked_add(2)) {
+            Some(chunk_end) => { chunk_end },
+            None => {
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("faulty chunk size '{}', it is too large", size_line)));
+            }
+        };
+
+        if block.len() < chunk_end {
+            return Ok(None);
+        }
+
+        result.extend_from_slice(&block[idx..idx + chunk_size]);
+        idx = chunk_end;
+    }
+}
+
+#[derive(Clone, Default, PartialEq)]
+enum ChunkedState {
+    #[default]
+    Size,
+    Data,
+    DataEnd,
+    Trailer,
+    Done,
+}
+
+/// decodes a chunked body as it arrives, unlike decode_chunked it does not need the whole body in memory
+#[derive(Clone, Default)]
+pub struct ChunkedDecoder {
+    storage: Vec<u8>,
+    remaining: usize,
+    state: ChunkedState,
+}
+
+impl ChunkedDecoder {
+    const LINE_HARD_LIMIT: usize = 8 * 1024;
+
+    pub fn is_done(&self) -> bool {
+        return self.state == ChunkedState::Done;
+    }
+
+    /// the bytes received past the end of the body, they belong to the next message
+    pub fn into_remainder(self) -> Vec<u8> {
+        return self.storage;
+    }
+
+    fn take_line(&mut self) -> Result<Option<String>, std::io::Error> {
+        match self.storage.windows(2).position(|a| a == b"\r\n") {
+            Some(line_length) => {
+                let line = String::from_utf8_lossy(&self.storage[..line_length]).to_string();
+                self.storage.drain(..line_length + 2);
+                return Ok(Some(line));
+            },
+            None => {
+                if self.storage.len() > Self::LINE_HARD_LIMIT {
+                    return Err(std::io::Error::new(std::io::ErrorKind::Other, "hard limit on chunk line reached"));
+                }
+
+                return Ok(None);
+            }
+        }
+    }
+
+    /// returns the body bytes carried by the block, the framing is consumed
+    pub fn decode(&mut self, block: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let mut result: Vec<u8> = Vec::new();
+        self.storage.extend_from_slice(block);
+
+        loop {
+            match self.state {
+                ChunkedState::Size => {
+                    let size_line = match self.take_line()? {
+                        Some(size_line) => { size_line },
+                        None => { break; }
+                    };
+                    let size_field = size_line.split(';').next().unwrap_or("").trim();
+
+                    match usize::from_str_radix(size_field, 16) {
+                        Ok(0) => {
+                            self.state = ChunkedState::Trailer;
+                        },
+                        Ok(chunk_size) => {
+                            self.remaining = chunk_size;
+                            self.state = ChunkedState::Data;
+                        },
+                        Err(err) => {
+                            return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("faulty chunk size '{}', error: {}", size_line, err.to_string())));
+                        }
+                    }
+                },
+                ChunkedState::Data => {
+                    if self.storage.is_empty() {
+                        break;
+                    }
+
+                    let len = self.remaining.min(self.storage.len());
+                    result.extend(self.storage.drain(..len));
+                    self.remaining = self.remaining - len;
+
+                    if self.remaining == 0 {
+                        self.state = ChunkedState::DataEnd;
+                    }
+                },
+                ChunkedState::DataEnd => {
+                    if self.storage.len() < 2 {
+                        break;
+                    }
+
+                    if &self.storage[..2] != b"\r\n" {
+                        return Err(std::io::Error::new(std::io::ErrorKind::Other, "missing CRLF after chunk data"));
+                    }
+
+                    self.storage.drain(..2);
+                    self.state = ChunkedState::Size;
+                },
+                ChunkedState::Trailer => {
+                    // trailer fields are dropped, the section ends with an empty line
+                    match self.take_line()? {
+                        Some(line) => {
+                            if line.is_empty() {
+                                self.state = ChunkedState::Done;
+                            }
+                        },
+                        None => { break; }
+                    }
+                },
+                ChunkedState::Done => {
+                    break;
+                }
+            }
+        }
+
+        return Ok(result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// feeds the decoder one byte at a time, as a slow edge server would send it
+    fn decode_bytewise(decoder: &mut ChunkedDecoder, block: &[u8]) -> Vec<u8> {
+        let mut result: Vec<u8> = Vec::new();
+
+        for byte in block.iter() {
+            result.extend(decoder.decode(&[*byte]).unwrap());
+        }
+
+        return result;
+    }
+
+    #[test]
+    fn decode_chunked_waits_for_the_last_chunk() {
+        assert_eq!(decode_chunked(b"5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\n\r\n").unwrap(), Some(b"hello world".to_vec()));
+        assert_eq!(decode_chunked(b"5\r\nhello\r\n0\r\nExpires: never\r\n\r\n").unwrap(), Some(b"hello".to_vec()));
+        assert_eq!(decode_chunked(b"5\r\nhello\r\n").unwrap(), None);
+        assert_eq!(decode_chunked(b"5\r\nhel").unwrap(), None);
+    }
+
+    #[test]
+    fn decode_chunked_rejects_faulty_sizes() {
+        assert!(decode_chunked(b"zz\r\nhello\r\n").is_err());
+        assert!(decode_chunked(b"ffffffffffffffff\r\nhello\r\n").is_err());
+        assert!(decode_chunked(b"fffffffffffffffe\r\nhello\r\n").is_err());
+        assert!(decode_chunked(b"10000000000000000\r\nhello\r\n").is_err());
+    }
+
+    #[test]
+    fn chunked_decoder_handles_split_framing() {
+        let body = b"5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\nExpires: never\r\n\r\nHTTP/1.1 200 OK";
+        let mut decoder = ChunkedDecoder::default();
+
+        assert_eq!(decode_bytewise(&mut decoder, body), b"hello world".to_vec());
+        assert!(decoder.is_done());
+        assert_eq!(decoder.into_remainder(), b"HTTP/1.1 200 OK".to_vec());
+    }
+
+    #[test]
+    fn chunked_decoder_waits_for_the_trailer() {
+        let mut decoder = ChunkedDecoder::default();
+
+        assert_eq!(decoder.decode(b"3\r\nabc\r\n0\r\n").unwrap(), b"abc".to_vec());
+        assert!(!decoder.is_done());
+        assert!(decoder.decode(b"\r\n").unwrap().is_empty());
+        assert!(decoder.is_done());
+    }
+
+    #[test]
+    fn chunked_decoder_rejects_faulty_framing() {
+        assert!(ChunkedDecoder::default().decode(b"3\r\nabcX\r\n").is_err());
+        assert!(ChunkedDecoder::default().decode(b"xyz\r\n").is_err());
+        assert!(ChunkedDecoder::default().decode(b"10000000000000000\r\n").is_err());
+        assert!(ChunkedDecoder::default().decode(&[b'1'; ChunkedDecoder::LINE_HARD_LIMIT + 1]).is_err());
+    }
+
+    #[test]
+    fn chunked_decoder_streams_a_huge_chunk() {
+        let mut decoder = ChunkedDecoder::default();
+
+        assert_eq!(decoder.decode(b"ffffffffffffffff\r\nabc").unwrap(), b"abc".to_vec());
+        assert_eq!(decoder.decode(b"def").unwrap(), b"def".to_vec());
+        assert!(!decoder.is_done());
+    }
+}
@@ -0,0 +1,1137 @@
+use crate::configdb;
+use crate::client;
+use crate::client_hello;
+use crate::csrf;
+use crate::edge_server;
+use crate::forwarded;
+use crate::grpc;
+use crate::header_policy;
+use crate::http1;
+use crate::http3;
+use crate::location_response;
+use crate::response;
+use crate::rewrite;
+use crate::server;
+
+pub const HTTP2_ALPN_PROTOCOL: &[u8] = b"h2";
+const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const HTTP2_DEFAULT_MAX_CONCURRENT_STREAMS: u32 = 100;
+const EDGE_RESPONSE_HEADER_HARD_LIMIT: usize = 128 * 1024;
+
+// connection-specific headers have no meaning in HTTP/2 and are dropped in both directions (RFC 9113 8.2.2)
+const CONNECTION_SPECIFIC_HEADERS: [&str; 6] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade", "te"];
+
+lazy_static::lazy_static! {
+    #[allow(non_upper_case_globals)]
+    static ref EDGE_CONNECTIONS: std::sync::Arc<std::sync::Mutex<Vec<EdgeConnection>>> = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+}
+
+/// a multiplexed connection to an edge server speaking HTTP/2, shared by the streams of every client
+#[derive(Clone)]
+pub struct EdgeConnection {
+    pub edgeaddr: String,
+    pub send_request: h2::client::SendRequest<bytes::Bytes>,
+    pub closed: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    pub streams: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+}
+
+#[derive(Clone)]
+struct StreamContext {
+    connaddr: String,
+    client_identity: Option<server::ClientIdentity>,
+    tls_fingerprint: Option<client_hello::TlsFingerprint>,
+    client_ip: std::net::IpAddr,
+    https: bool,
+    ip_rule: Option<configdb::IpRule>,
+    listener_config: configdb::Listener,
+    general_config: configdb::General,
+}
+
+/// h2 is negotiated through ALPN on the TLS listener, on the plain listener h2c is only recognized by its
+/// connection preface (prior knowledge) and only when enabled
+pub async fn is_http2(conn: &server::TcpClient, general_config: &configdb::General) -> bool {
+    match conn {
+        server::TcpClient::Https(ssl_stream) => {
+            return ssl_stream.ssl().selected_alpn_protocol() == Some(HTTP2_ALPN_PROTOCOL);
+        },
+        server::TcpClient::Http(tcp_stream) => {
+            if !general_config.h2c {
+                return false;
+            }
+
+            let mut block = [0_u8; HTTP2_PREFACE.len()];
+
+            for _ in 0..50 {
+                let len = match tcp_stream.peek(&mut block).await {
+                    Ok(0) => {
+                        return false;
+                    },
+                    Ok(len) => { len },
+                    Err(_) => {
+                        return false;
+                    }
+                };
+
+                // an HTTP/1.1 request line differs from the preface within its first bytes
+                if block[..len] != HTTP2_PREFACE[..len] {
+                    return false;
+                }
+
+                if len == HTTP2_PREFACE.len() {
+                    return true;
+                }
+
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+
+            return false;
+        }
+    }
+}
+
+fn is_connection_specific_header(name: &str) -> bool {
+    return CONNECTION_SPECIFIC_HEADERS.iter().any(|header| name.eq_ignore_ascii_case(header));
+}
+
+/// the HTTP/1.1 view of an HTTP/2 or HTTP/3 request, it goes through the same rules as the requests received over HTTP/1.1
+pub fn to_http1_request(parts: &http::request::Parts) -> http1::Http {
+    let mut result = http1::Http {
+        method: parts.method.as_str().to_string(),
+        location: match parts.uri.path_and_query() {
+            Some(path_and_query) => { path_and_query.as_str().to_string() },
+            None => { String::from("/") }
+        },
+        properties: std::collections::HashMap::new(),
+    };
+
+    for (name, value) in parts.headers.iter() {
+        let value = String::from_utf8_lossy(value.as_bytes()).to_string();
+
+        match result.properties.get_mut(name.as_str()) {
+            Some(property) => {
+                // cookies may be split over several fields in HTTP/2 (RFC 9113 8.2.3)
+                let separator = if name == http::header::COOKIE { "; " } else { ", " };
+                *property = format!("{}{}{}", property, separator, value);
+            },
+            None => {
+                result.properties.insert(name.as_str().to_string(), value);
+            }
+        }
+    }
+
+    if let Some(authority) = parts.uri.authority() {
+        result.properties.insert(String::from("host"), authority.as_str().to_string());
+    }
+
+    return result;
+}
+
+pub fn create_edge_request_head(headers: &http::HeaderMap, object: &http1::Http, chunked: bool, forwarded_headers: &[(String, String)]) -> String {
+    let request_rewrite = rewrite::get_request_rewrite(object);
+
+    let mut result = match &request_rewrite {
+        Some(request_rewrite) => { format!("{} {} HTTP/1.1\r\n", object.method, rewrite::rewrite_location(&object.location, request_rewrite)) },
+        None => { format!("{} {} HTTP/1.1\r\n", object.method, object.location) }
+    };
+
+    let mut edge_headers: Vec<(String, String)> = Vec::new();
+
+    if let Some(host) = object.properties.get("host") {
+        edge_headers.push((String::from("Host"), host.clone()));
+    }
+
+    let cookie_policy = header_policy::get_response_policy(object).cookie_policy;
+
+    for (name, value) in headers.iter() {
+        // the host header was written from :authority already
+        if is_connection_specific_header(name.as_str()) || name == http::header::HOST {
+            continue;
+        }
+
+        // the client's copies are replaced by the WAF's own
+        if !forwarded_headers.is_empty() && forwarded::is_forwarded_header(name.as_str()) {
+            continue;
+        }
+
+        let value = String::from_utf8_lossy(value.as_bytes());
+
+        match &cookie_policy {
+            Some(cookie_policy) if name == http::header::COOKIE => {
+                edge_headers.push((name.as_str().to_string(), header_policy::restore_cookie_names(&value, cookie_policy)));
+            },
+            _ => {
+                edge_headers.push((name.as_str().to_string(), value.to_string()));
+            }
+        }
+    }
+
+    if let Some(request_rewrite) = &request_rewrite {
+        edge_headers = rewrite::rewrite_headers(edge_headers, request_rewrite);
+    }
+
+    for (name, value) in edge_headers.iter().chain(forwarded_headers.iter()) {
+        result.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    if chunked {
+        result.push_str("Transfer-Encoding: chunked\r\n");
+    }
+
+    // one edge connection per stream, the end of the response is also signalled by the edge closing it
+    result.push_str("Connection: close\r\n\r\n");
+
+    return result;
+}
+
+/// sends a body block on the stream without exceeding the flow control window granted by the client
+pub async fn send_data(send_stream: &mut h2::SendStream<bytes::Bytes>, mut block: bytes::Bytes, end_of_stream: bool) -> Result<(), std::io::Error> {
+    while !block.is_empty() {
+        send_stream.reserve_capacity(block.len());
+
+        match std::future::poll_fn(|cx| send_stream.poll_capacity(cx)).await {
+            Some(Ok(0)) => {},
+            Some(Ok(capacity)) => {
+                let chunk = block.split_to(capacity.min(block.len()));
+
+                if let Err(err) = send_stream.send_data(chunk, false) {
+                    return Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+                }
+            },
+            Some(Err(err)) => {
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+            },
+            None => {
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, "the stream was closed by the client"));
+            }
+        }
+    }
+
+    if end_of_stream {
+        if let Err(err) = send_stream.send_data(bytes::Bytes::new(), true) {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+        }
+    }
+
+    return Ok(());
+}
+
+/// moves the request body from the stream to the edge, in chunks when the client did not announce its length
+async fn forward_request_body(body: &mut h2::RecvStream, edge_conn: &mut server::TcpClient, chunked: bool, grpc_inspector: &mut Option<grpc::MessageInspector>) -> Result<(), std::io::Error> {
+    while let Some(data) = body.data().await {
+        let data = match data {
+            Ok(data) => { data },
+            Err(err) => {
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+            }
+        };
+
+        if let Err(err) = body.flow_control().release_capacity(data.len()) {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+        }
+
+        if data.is_empty() {
+            continue;
+        }
+
+        if let Some(grpc_inspector) = grpc_inspector {
+            if let Err(err) = grpc_inspector.inspect(&data) {
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("gRPC call {} refused, {}", grpc_inspector.call_name(), err.message)));
+            }
+        }
+
+        if chunked {
+            edge_conn.write_all(format!("{:x}\r\n", data.len()).as_bytes()).await?;
+            edge_conn.write_all(&data).await?;
+            edge_conn.write_all(b"\r\n").await?;
+        } else {
+            edge_conn.write_all(&data).await?;
+        }
+    }
+
+    if chunked {
+        edge_conn.write_all(b"0\r\n\r\n").await?;
+    }
+
+    return Ok(());
+}
+
+/// reads the response head of the edge, informational responses are skipped; returns the head and the body
+/// bytes read along with it
+pub async fn read_response_head(edge_conn: &mut server::TcpClient) -> Result<(http1::HttpResponse, Vec<u8>), std::io::Error> {
+    let mut storage: Vec<u8> = Vec::new();
+    let mut edge_mtu_block = [0_u8; 1500];
+
+    loop {
+        if let Some(header_length) = storage.windows(4).position(|a| a == b"\r\n\r\n") {
+            let response = http1::parse_response(storage[..header_length].to_vec())?;
+            storage.drain(..header_length + 4);
+
+            if (100..200).contains(&response.status) && response.status != 101 {
+                continue;
+            }
+
+            return Ok((response, storage));
+        }
+
+        if storage.len() > EDGE_RESPONSE_HEADER_HARD_LIMIT {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, "hard limit on response header reached"));
+        }
+
+        match edge_conn.read(&mut edge_mtu_block).await {
+            Ok(0) => {
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, "the edge server closed the connection before responding"));
+            },
+            Ok(len) => {
+                storage.extend_from_slice(&edge_mtu_block[..len]);
+            },
+            Err(err) => {
+                return Err(err);
+            }
+        }
+    }
+}
+
+pub fn create_response(edge_response: &http1::HttpResponse) -> Result<http::Response<()>, std::io::Error> {
+    let mut result = http::Response::new(());
+
+    match http::StatusCode::from_u16(edge_response.status) {
+        Ok(status) => {
+            *result.status_mut() = status;
+        },
+        Err(err) => {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+        }
+    }
+
+    // headers listed in Connection are hop-by-hop as well
+    let connection_headers: Vec<String> = match http1::find_property(&edge_response.properties, "connection") {
+        Some(value) => { value.split(',').map(|name| name.trim().to_ascii_lowercase()).collect() },
+        None => { Vec::new() }
+    };
+
+    for (name, value) in edge_response.headers.iter() {
+        if is_connection_specific_header(name) || connection_headers.contains(&name.to_ascii_lowercase()) {
+            continue;
+        }
+
+        match (http::header::HeaderName::from_bytes(name.to_ascii_lowercase().as_bytes()), http::header::HeaderValue::from_str(value)) {
+            (Ok(name), Ok(value)) => {
+                result.headers_mut().append(name, value);
+            },
+            _ => {
+                eprintln!("dropping the faulty response header '{}' received from the edge server", name);
+            }
+        }
+    }
+
+    return Ok(result);
+}
+
+/// the body of an edge response, delimited by Content-Length, chunked framing or the edge closing the connection
+pub struct EdgeResponseBody {
+    chunked: bool,
+    chunked_decoder: http1::ChunkedDecoder,
+    delimited: bool,
+    remaining: usize,
+    pending: Vec<u8>,
+    done: bool,
+}
+
+impl EdgeResponseBody {
+    /// reads the response head of the edge and sets up the reading of its body
+    pub async fn read_head(edge_conn: &mut server::TcpClient, method: &str) -> Result<(http1::HttpResponse, EdgeResponseBody), std::io::Error> {
+        let (edge_response, pending) = read_response_head(edge_conn).await?;
+        let body = EdgeResponseBody::new(&edge_response, method, pending)?;
+
+        return Ok((edge_response, body));
+    }
+
+    /// the body following a response head already read, pending holds the bytes received past the head
+    pub fn new(edge_response: &http1::HttpResponse, method: &str, pending: Vec<u8>) -> Result<EdgeResponseBody, std::io::Error> {
+        let content_length: Option<usize> = match http1::find_property(&edge_response.properties, "content-length") {
+            Some(content_length) => {
+                match content_length.parse() {
+                    Ok(content_length) => { Some(content_length) },
+                    Err(err) => {
+                        return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("corrupted Content-Length from the edge server, error: {}", err.to_string())));
+                    }
+                }
+            },
+            None => { None }
+        };
+
+        let chunked = match http1::find_property(&edge_response.properties, "transfer-encoding") {
+            Some(transfer_encoding) => { transfer_encoding.to_ascii_lowercase().contains("chunked") },
+            None => { false }
+        };
+
+        let bodyless = method == "HEAD" || edge_response.status == 204 || edge_response.status == 304 || content_length == Some(0);
+
+        let body = EdgeResponseBody {
+            chunked,
+            chunked_decoder: http1::ChunkedDecoder::default(),
+            delimited: chunked || content_length.is_some(),
+            remaining: content_length.unwrap_or(usize::MAX),
+            pending,
+            done: bodyless,
+        };
+
+        return Ok(body);
+    }
+
+    pub fn is_done(&self) -> bool {
+        return self.done;
+    }
+
+    /// the next block of the body with its framing removed, None once the body is complete
+    pub async fn next_block(&mut self, edge_conn: &mut server::TcpClient) -> Result<Option<Vec<u8>>, std::io::Error> {
+        let mut edge_mtu_block = [0_u8; 1500];
+
+        loop {
+            if self.done {
+                return Ok(None);
+            }
+
+            if !self.pending.is_empty() {
+                let mut block = std::mem::take(&mut self.pending);
+
+                let data = match self.chunked {
+                    true => { self.chunked_decoder.decode(&block)? },
+                    false => {
+                        block.truncate(self.remaining);
+                        self.remaining -= block.len();
+                        block
+                    }
+                };
+
+                self.done = (self.chunked && self.chunked_decoder.is_done()) || (!self.chunked && self.remaining == 0);
+
+                if !data.is_empty() {
+                    return Ok(Some(data));
+                }
+
+                continue;
+            }
+
+            match edge_conn.read(&mut edge_mtu_block).await {
+                Ok(0) => {
+                    if self.delimited {
+                        return Err(std::io::Error::new(std::io::ErrorKind::Other, "the edge server closed the connection before the end of the response"));
+                    }
+
+                    self.done = true;
+                },
+                Ok(len) => {
+                    self.pending = edge_mtu_block[..len].to_vec();
+                },
+                Err(err) => {
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+/// the body of an edge response, read from an HTTP/1 edge connection or from the stream of an HTTP/2 edge
+pub enum EdgeBody<'a> {
+    Http1(&'a mut server::TcpClient, EdgeResponseBody),
+    Http2(h2::RecvStream),
+}
+
+impl EdgeBody<'_> {
+    pub fn is_end_stream(&self) -> bool {
+        match self {
+            EdgeBody::Http1(_, body) => { return body.is_done(); },
+            EdgeBody::Http2(body) => { return body.is_end_stream(); }
+        }
+    }
+
+    /// the next block of the body, None once the body is complete
+    pub async fn next_block(&mut self) -> Result<Option<bytes::Bytes>, (std::io::Error, h2::Reason)> {
+        match self {
+            EdgeBody::Http1(edge_conn, body) => {
+                match body.next_block(edge_conn).await {
+                    Ok(block) => { return Ok(block.map(bytes::Bytes::from)); },
+                    Err(err) => { return Err((err, h2::Reason::INTERNAL_ERROR)); }
+                }
+            },
+            EdgeBody::Http2(body) => {
+                match body.data().await {
+                    Some(Ok(data)) => {
+                        body.flow_control().release_capacity(data.len()).map_err(h2_error)?;
+                        return Ok(Some(data));
+                    },
+                    Some(Err(err)) => { return Err(h2_error(err)); },
+                    None => { return Ok(None); }
+                }
+            }
+        }
+    }
+
+    /// the trailers following the body, gRPC carries its status in them
+    pub async fn trailers(&mut self) -> Result<Option<http::HeaderMap>, (std::io::Error, h2::Reason)> {
+        match self {
+            EdgeBody::Http1(_, _) => { return Ok(None); },
+            EdgeBody::Http2(body) => { return body.trailers().await.map_err(h2_error); }
+        }
+    }
+}
+
+/// sends the head held back so far, the stream of the response body opens with it
+fn release_head(respond: &mut h2::server::SendResponse<bytes::Bytes>, head: &mut Option<http::Response<()>>, send_stream: &mut Option<h2::SendStream<bytes::Bytes>>) -> Result<(), (std::io::Error, h2::Reason)> {
+    if let Some(response) = head.take() {
+        *send_stream = Some(respond.send_response(response, false).map_err(h2_error)?);
+    }
+
+    return Ok(());
+}
+
+/// relays the response of the edge to the stream, its body goes through the detectors and the data leak
+/// filter of the location rule; a response blocked on a leak is replaced by an error page
+async fn send_inspected_response(respond: &mut h2::server::SendResponse<bytes::Bytes>, mut response: http::Response<()>, mut body: EdgeBody<'_>, object: &http1::Http, context: &StreamContext) -> Result<(), (std::io::Error, h2::Reason)> {
+    header_policy::harden_response(response.headers_mut(), object);
+    http3::add_alt_svc(response.headers_mut(), &context.listener_config, &context.general_config);
+
+    let mut inspector = response::StreamInspector::new(&context.connaddr, object);
+    inspector.start_response(response.status().as_u16(), response.headers());
+
+    if body.is_end_stream() {
+        inspector.finish();
+        respond.send_response(response, true).map_err(h2_error)?;
+        return Ok(());
+    }
+
+    let mut head = Some(response);
+    let mut send_stream: Option<h2::SendStream<bytes::Bytes>> = None;
+
+    let result = async {
+        while let Some(data) = body.next_block().await? {
+            let data = inspector.process(&data);
+
+            if !inspector.is_head_held() {
+                release_head(respond, &mut head, &mut send_stream)?;
+            }
+
+            if let Some(send_stream) = &mut send_stream {
+                send_data(send_stream, bytes::Bytes::from(data), false).await.map_err(|err| (err, h2::Reason::CANCEL))?;
+            }
+        }
+
+        let trailers = body.trailers().await?;
+
+        let rest = match inspector.finish() {
+            Some(rest) => { rest },
+            None => {
+                let (response, blocked_body) = response::create_blocked_stream_response();
+                let mut blocked_stream = respond.send_response(response, false).map_err(h2_error)?;
+                return send_data(&mut blocked_stream, blocked_body, true).await.map_err(|err| (err, h2::Reason::CANCEL));
+            }
+        };
+
+        release_head(respond, &mut head, &mut send_stream)?;
+
+        if let Some(send_stream) = &mut send_stream {
+            send_data(send_stream, bytes::Bytes::from(rest), false).await.map_err(|err| (err, h2::Reason::CANCEL))?;
+
+            // gRPC carries its status in the trailers
+            match trailers {
+                Some(trailers) => {
+                    send_stream.send_trailers(trailers).map_err(h2_error)?;
+                },
+                None => {
+                    send_data(send_stream, bytes::Bytes::new(), true).await.map_err(|err| (err, h2::Reason::CANCEL))?;
+                }
+            }
+        }
+
+        Ok(())
+    }.await;
+
+    if let (Err((_, reason)), Some(send_stream)) = (&result, &mut send_stream) {
+        send_stream.send_reset(*reason);
+    }
+
+    return result;
+}
+
+/// relays the response of the HTTP/1 edge to the stream
+async fn forward_response(edge_conn: &mut server::TcpClient, respond: &mut h2::server::SendResponse<bytes::Bytes>, object: &http1::Http, context: &StreamContext) -> Result<(), std::io::Error> {
+    let (edge_response, body) = EdgeResponseBody::read_head(edge_conn, &object.method).await?;
+    let response = create_response(&edge_response)?;
+
+    return send_inspected_response(respond, response, EdgeBody::Http1(edge_conn, body), object, context).await.map_err(|(err, _)| err);
+}
+
+async fn relay_to_http1_edge(request: http::Request<h2::RecvStream>, respond: &mut h2::server::SendResponse<bytes::Bytes>, object: &http1::Http, edge_info: &configdb::Edge, context: &StreamContext, grpc_inspector: &mut Option<grpc::MessageInspector>) {
+    let edgeaddr = format!("{}:{}", edge_info.destination, edge_info.destination_port);
+
+    match client::connect_to_edge_server(edge_info).await {
+        Ok(mut edge_conn) => {
+            let (parts, mut body) = request.into_parts();
+            let chunked = !parts.headers.contains_key(http::header::CONTENT_LENGTH) && !body.is_end_stream();
+
+            let result = match edge_conn.write_all(create_edge_request_head(&parts.headers, object, chunked, &forwarded::create_forwarded_headers(object, &context.client_ip, context.https, &context.general_config)).as_bytes()).await {
+                Ok(_) => {
+                    match forward_request_body(&mut body, &mut edge_conn, chunked, grpc_inspector).await {
+                        Ok(_) => {
+                            forward_response(&mut edge_conn, respond, object, context).await
+                        },
+                        Err(err) => { Err(err) }
+                    }
+                },
+                Err(err) => { Err(err) }
+            };
+
+            if let Err(err) = result {
+                if !refuse_grpc_call(respond, &mut body, grpc_inspector).await {
+                    eprintln!("failed to relay the stream from client {} to edge server {}, error: {}", &context.connaddr, &edgeaddr, err.to_string());
+                    respond.send_reset(h2::Reason::INTERNAL_ERROR);
+                }
+            }
+        },
+        Err(err) => {
+            eprintln!("failed to connect to edge server {}, error: {}", &edgeaddr, err.to_string());
+            respond.send_reset(h2::Reason::REFUSED_STREAM);
+        }
+    }
+}
+
+/// the edge connection is spawned on its own task, it stays open for the next streams until either side closes it
+async fn handshake_edge<T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static>(conn: T, edgeaddr: String, closed: std::sync::Arc<std::sync::atomic::AtomicBool>) -> Result<h2::client::SendRequest<bytes::Bytes>, std::io::Error> {
+    match h2::client::handshake(conn).await {
+        Ok((send_request, h2_conn)) => {
+            tokio::spawn(async move {
+                if let Err(err) = h2_conn.await {
+                    eprintln!("HTTP/2 connection with edge server {} failed, error: {}", &edgeaddr, err.to_string());
+                }
+
+                closed.store(true, std::sync::atomic::Ordering::SeqCst);
+            });
+
+            return Ok(send_request);
+        },
+        Err(err) => {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+        }
+    }
+}
+
+async fn connect_to_http2_edge(edge_info: &configdb::Edge) -> Result<EdgeConnection, std::io::Error> {
+    let edgeaddr = format!("{}:{}", edge_info.destination, edge_info.destination_port);
+    let closed = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+    let send_request = match client::connect_to_edge_server(edge_info).await? {
+        server::TcpClient::Http(tcp_stream) => {
+            handshake_edge(tcp_stream, edgeaddr.clone(), closed.clone()).await?
+        },
+        server::TcpClient::Https(ssl_stream) => {
+            if ssl_stream.ssl().selected_alpn_protocol() != Some(HTTP2_ALPN_PROTOCOL) {
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, "the edge server did not negotiate h2"));
+            }
+
+            handshake_edge(ssl_stream, edgeaddr.clone(), closed.clone()).await?
+        }
+    };
+
+    println!("opened a HTTP/2 connection with edge server {}", &edgeaddr);
+
+    return Ok(EdgeConnection { edgeaddr, send_request, closed, streams: std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0)) });
+}
+
+/// picks the pooled connection with the fewest streams in flight, a new one is opened while the pool of the edge
+/// is not full; the stream counter of the returned connection is already incremented
+pub async fn get_edge_connection(edge_info: &configdb::Edge) -> Result<EdgeConnection, std::io::Error> {
+    let edgeaddr = format!("{}:{}", edge_info.destination, edge_info.destination_port);
+    let pool_size = edge_info.http2_connections.max(1);
+
+    let pooled: Option<EdgeConnection> = match EDGE_CONNECTIONS.lock() {
+        Ok(mut edge_connections) => {
+            edge_connections.retain(|edge_connection| !edge_connection.closed.load(std::sync::atomic::Ordering::SeqCst));
+
+            let candidates: Vec<&EdgeConnection> = edge_connections.iter().filter(|edge_connection| edge_connection.edgeaddr == edgeaddr).collect();
+
+            if candidates.len() >= pool_size {
+                candidates.into_iter().min_by_key(|edge_connection| edge_connection.streams.load(std::sync::atomic::Ordering::SeqCst)).cloned()
+            } else {
+                None
+            }
+        },
+        Err(err) => {
+            eprintln!("internal error, failed to lock EDGE_CONNECTIONS, error: {}; aborting", err.to_string());
+            std::process::abort();
+        }
+    };
+
+    let edge_connection = match pooled {
+        Some(edge_connection) => { edge_connection },
+        None => {
+            let edge_connection = connect_to_http2_edge(edge_info).await?;
+
+            match EDGE_CONNECTIONS.lock() {
+                Ok(mut edge_connections) => {
+                    edge_connections.push(edge_connection.clone());
+                },
+                Err(err) => {
+                    eprintln!("internal error, failed to lock EDGE_CONNECTIONS, error: {}; aborting", err.to_string());
+                    std::process::abort();
+                }
+            }
+
+            edge_connection
+        }
+    };
+
+    edge_connection.streams.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    return Ok(edge_connection);
+}
+
+fn h2_error(err: h2::Error) -> (std::io::Error, h2::Reason) {
+    let reason = err.reason().unwrap_or(h2::Reason::INTERNAL_ERROR);
+    return (std::io::Error::new(std::io::ErrorKind::Other, err.to_string()), reason);
+}
+
+/// moves DATA frames and trailers from one stream to the other, flow control is honoured on both sides
+async fn relay_stream_body(body: &mut h2::RecvStream, send_stream: &mut h2::SendStream<bytes::Bytes>, grpc_inspector: &mut Option<grpc::MessageInspector>) -> Result<(), (std::io::Error, h2::Reason)> {
+    while let Some(data) = body.data().await {
+        let data = data.map_err(h2_error)?;
+
+        if let Err(err) = body.flow_control().release_capacity(data.len()) {
+            return Err(h2_error(err));
+        }
+
+        if let Some(grpc_inspector) = grpc_inspector {
+            if let Err(err) = grpc_inspector.inspect(&data) {
+                return Err((std::io::Error::new(std::io::ErrorKind::Other, format!("gRPC call {} refused, {}", grpc_inspector.call_name(), err.message)), h2::Reason::CANCEL));
+            }
+        }
+
+        if let Err(err) = send_data(send_stream, data, false).await {
+            return Err((err, h2::Reason::CANCEL));
+        }
+    }
+
+    // gRPC carries its status in the trailers
+    match body.trailers().await.map_err(h2_error)? {
+        Some(trailers) => {
+            if let Err(err) = send_stream.send_trailers(trailers) {
+                return Err(h2_error(err));
+            }
+        },
+        None => {
+            if let Err(err) = send_data(send_stream, bytes::Bytes::new(), true).await {
+                return Err((err, h2::Reason::CANCEL));
+            }
+        }
+    }
+
+    return Ok(());
+}
+
+pub fn create_edge_request(parts: &http::request::Parts, object: &http1::Http, edge_info: &configdb::Edge, forwarded_headers: &[(String, String)]) -> Result<http::Request<()>, std::io::Error> {
+    let request_rewrite = rewrite::get_request_rewrite(object);
+
+    let mut authority = match object.properties.get("host") {
+        Some(host) => { host.clone() },
+        None => { edge_info.resolve_name.clone() }
+    };
+
+    let mut location = object.location.clone();
+
+    if let Some(request_rewrite) = &request_rewrite {
+        location = rewrite::rewrite_location(&object.location, request_rewrite);
+
+        if !request_rewrite.host.is_empty() {
+            authority = request_rewrite.host.clone();
+        }
+    }
+
+    let uri = http::Uri::builder()
+        .scheme(if edge_info.https { "https" } else { "http" })
+        .authority(authority)
+        .path_and_query(location.as_str())
+        .build();
+
+    let mut result = http::Request::new(());
+    *result.method_mut() = parts.method.clone();
+
+    match uri {
+        Ok(uri) => {
+            *result.uri_mut() = uri;
+        },
+        Err(err) => {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+        }
+    }
+
+    let cookie_policy = header_policy::get_response_policy(object).cookie_policy;
+
+    for (name, value) in parts.headers.iter() {
+        // "te: trailers" is the only connection-specific header allowed in HTTP/2 and gRPC requires it
+        let is_te_trailers = name == http::header::TE && value.as_bytes().eq_ignore_ascii_case(b"trailers");
+
+        if (is_connection_specific_header(name.as_str()) && !is_te_trailers) || name == http::header::HOST {
+            continue;
+        }
+
+        if !forwarded_headers.is_empty() && forwarded::is_forwarded_header(name.as_str()) {
+            continue;
+        }
+
+        let restored = match (&cookie_policy, value.to_str()) {
+            (Some(cookie_policy), Ok(cookie)) if name == http::header::COOKIE => {
+                http::header::HeaderValue::from_str(&header_policy::restore_cookie_names(cookie, cookie_policy)).ok()
+            },
+            _ => { None }
+        };
+
+        result.headers_mut().append(name.clone(), restored.unwrap_or_else(|| value.clone()));
+    }
+
+    if let Some(request_rewrite) = &request_rewrite {
+        rewrite::rewrite_header_map(result.headers_mut(), request_rewrite);
+    }
+
+    for (name, value) in forwarded_headers.iter() {
+        match (http::header::HeaderName::from_bytes(name.as_bytes()), http::header::HeaderValue::from_str(value)) {
+            (Ok(name), Ok(value)) => {
+                result.headers_mut().append(name, value);
+            },
+            _ => {
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("invalid forwarded header {}: {}", name, value)));
+            }
+        }
+    }
+
+    return Ok(result);
+}
+
+async fn exchange_with_http2_edge(request: http::Request<h2::RecvStream>, respond: &mut h2::server::SendResponse<bytes::Bytes>, object: &http1::Http, edge_info: &configdb::Edge, edge_connection: &EdgeConnection, grpc_inspector: &mut Option<grpc::MessageInspector>, context: &StreamContext) -> Result<(), (std::io::Error, h2::Reason)> {
+    let (parts, mut body) = request.into_parts();
+    let forwarded_headers = forwarded::create_forwarded_headers(object, &context.client_ip, context.https, &context.general_config);
+    let edge_request = create_edge_request(&parts, object, edge_info, &forwarded_headers).map_err(|err| (err, h2::Reason::INTERNAL_ERROR))?;
+
+    let mut send_request = edge_connection.send_request.clone().ready().await.map_err(|err| (std::io::Error::new(std::io::ErrorKind::Other, err.to_string()), h2::Reason::REFUSED_STREAM))?;
+    let end_of_stream = body.is_end_stream();
+    let (response_future, mut edge_send_stream) = send_request.send_request(edge_request, end_of_stream).map_err(|err| (std::io::Error::new(std::io::ErrorKind::Other, err.to_string()), h2::Reason::REFUSED_STREAM))?;
+
+    // the request body and the response flow at the same time, streaming calls depend on it
+    let request_relay = async {
+        if end_of_stream {
+            return Ok(());
+        }
+
+        let result = relay_stream_body(&mut body, &mut edge_send_stream, grpc_inspector).await;
+        if let Err((_, reason)) = &result {
+            edge_send_stream.send_reset(*reason);
+        }
+
+        result
+    };
+
+    let response_relay = async {
+        let edge_response = response_future.await.map_err(h2_error)?;
+        let (edge_parts, edge_body) = edge_response.into_parts();
+
+        let mut response = http::Response::new(());
+        *response.status_mut() = edge_parts.status;
+        *response.headers_mut() = edge_parts.headers;
+
+        send_inspected_response(respond, response, EdgeBody::Http2(edge_body), object, context).await
+    };
+
+    let (request_result, response_result) = tokio::join!(request_relay, response_relay);
+
+    // the edge stream was reset already, the client gets the status of the refused call
+    if request_result.is_err() && refuse_grpc_call(respond, &mut body, grpc_inspector).await {
+        return Ok(());
+    }
+
+    response_result?;
+    request_result?;
+
+    return Ok(());
+}
+
+async fn relay_to_http2_edge(request: http::Request<h2::RecvStream>, respond: &mut h2::server::SendResponse<bytes::Bytes>, object: &http1::Http, edge_info: &configdb::Edge, context: &StreamContext, grpc_inspector: &mut Option<grpc::MessageInspector>) {
+    let edgeaddr = format!("{}:{}", edge_info.destination, edge_info.destination_port);
+
+    match get_edge_connection(edge_info).await {
+        Ok(edge_connection) => {
+            // errors of the edge stream are mapped back to the client stream, the connection itself carries on
+            if let Err((err, reason)) = exchange_with_http2_edge(request, respond, object, edge_info, &edge_connection, grpc_inspector, context).await {
+                eprintln!("failed to relay the stream from client {} to edge server {}, error: {}", &context.connaddr, &edgeaddr, err.to_string());
+                respond.send_reset(reason);
+            }
+
+            edge_connection.streams.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+        },
+        Err(err) => {
+            eprintln!("failed to connect to edge server {}, error: {}", &edgeaddr, err.to_string());
+            respond.send_reset(h2::Reason::REFUSED_STREAM);
+        }
+    }
+}
+
+/// a Trailers-Only response, gRPC clients read the status of the call from it; fails once the response started
+fn send_grpc_error(respond: &mut h2::server::SendResponse<bytes::Bytes>, error: &grpc::GrpcError) -> bool {
+    let mut response = http::Response::new(());
+    response.headers_mut().insert(http::header::CONTENT_TYPE, http::header::HeaderValue::from_static(grpc::GRPC_CONTENT_TYPE));
+    response.headers_mut().insert("grpc-status", http::header::HeaderValue::from(error.status));
+
+    if let Ok(message) = http::header::HeaderValue::from_str(&error.message) {
+        response.headers_mut().insert("grpc-message", message);
+    }
+
+    match respond.send_response(response, true) {
+        Ok(_) => {
+            return true;
+        },
+        Err(err) => {
+            eprintln!("failed to send the gRPC status, error: {}", err.to_string());
+            return false;
+        }
+    }
+}
+
+/// the redirect or static page of the location rule, answered without the edge server
+async fn send_location_response(respond: &mut h2::server::SendResponse<bytes::Bytes>, location_response: &location_response::LocationResponse, object: &http1::Http) -> Result<(), std::io::Error> {
+    let mut response = create_response(&location_response.response)?;
+    header_policy::harden_response(response.headers_mut(), object);
+
+    let bodyless = location_response.body.is_empty() || object.method == "HEAD";
+    let mut send_stream = respond.send_response(response, bodyless).map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err.to_string()))?;
+
+    if !bodyless {
+        send_data(&mut send_stream, bytes::Bytes::from(location_response.body.clone()), true).await?;
+    }
+
+    return Ok(());
+}
+
+/// dropping a request body the client is still sending resets the stream, and the reset discards a response not
+/// flushed yet; what remains of the body is read and thrown away for a bounded time instead
+async fn drain_request_body(body: &mut h2::RecvStream) {
+    let drain = async {
+        while let Some(Ok(data)) = body.data().await {
+            let _ = body.flow_control().release_capacity(data.len());
+        }
+    };
+
+    let _ = tokio::time::timeout(std::time::Duration::from_secs(5), drain).await;
+}
+
+/// answers a call refused by the message inspection with its gRPC status, if the response has not started yet
+async fn refuse_grpc_call(respond: &mut h2::server::SendResponse<bytes::Bytes>, body: &mut h2::RecvStream, grpc_inspector: &Option<grpc::MessageInspector>) -> bool {
+    match grpc_inspector.as_ref().and_then(|grpc_inspector| grpc_inspector.refusal()) {
+        Some(refusal) => {
+            println!("refusing the gRPC call {}, {}", grpc_inspector.as_ref().map(|grpc_inspector| grpc_inspector.call_name()).unwrap_or_default(), &refusal.message);
+
+            if send_grpc_error(respond, refusal) {
+                drain_request_body(body).await;
+                return true;
+            }
+
+            return false;
+        },
+        None => {
+            return false;
+        }
+    }
+}
+
+async fn stream_procedure(request: http::Request<h2::RecvStream>, mut respond: h2::server::SendResponse<bytes::Bytes>, mut context: StreamContext) {
+    let (parts, body) = request.into_parts();
+    let object = to_http1_request(&parts);
+    let request = http::Request::from_parts(parts, body);
+
+    // behind a trusted proxy every stream may come from another client
+    match client::resolve_client(&object, &context.connaddr, context.client_ip, &context.ip_rule, &context.general_config) {
+        Some((client_ip, ip_rule)) => {
+            context.client_ip = client_ip;
+            context.ip_rule = ip_rule;
+        },
+        None => {
+            respond.send_reset(h2::Reason::CANCEL);
+            return;
+        }
+    }
+
+    // a rejected request only resets its stream, the other streams of the connection carry on
+    if client::evaluate_request(&object, &context.connaddr, &context.client_identity, &context.tls_fingerprint, &context.ip_rule, &context.general_config).is_none() {
+        respond.send_reset(h2::Reason::CANCEL);
+        return;
+    }
+
+    if let Some(location_response) = location_response::get_location_response(&object, context.https) {
+        println!("{} {} {} answered with {} by the location rule", &context.connaddr, &object.method, &object.location, location_response.response.status);
+
+        match send_location_response(&mut respond, &location_response, &object).await {
+            Ok(_) => {
+                drain_request_body(&mut request.into_body()).await;
+            },
+            Err(err) => {
+                eprintln!("failed to answer the stream from {}, error: {}", &context.connaddr, err.to_string());
+                respond.send_reset(h2::Reason::INTERNAL_ERROR);
+            }
+        }
+
+        return;
+    }
+
+    if let Err(reason) = csrf::check_streamed_request(&object) {
+        println!("refusing the stream from {}, CSRF check failed: {}", &context.connaddr, reason);
+        respond.send_reset(h2::Reason::CANCEL);
+        return;
+    }
+
+    let mut grpc_inspector: Option<grpc::MessageInspector> = None;
+    if grpc::is_grpc(&object) {
+        match grpc::evaluate_call(&object, &context.general_config) {
+            Ok(inspector) => {
+                grpc_inspector = Some(inspector);
+            },
+            Err(err) => {
+                println!("refusing the gRPC call {} from {}, {}", &object.location, &context.connaddr, &err.message);
+
+                if send_grpc_error(&mut respond, &err) {
+                    drain_request_body(&mut request.into_body()).await;
+                }
+
+                return;
+            }
+        }
+    }
+
+    let edge_info = match edge_server::find_edge_server(true, &context.listener_config.edges) {
+        Some(edge_info) => { edge_info },
+        None => {
+            eprintln!("failed to find an edge server, refusing the stream from {}", &context.connaddr);
+            respond.send_reset(h2::Reason::REFUSED_STREAM);
+            return;
+        }
+    };
+
+    match edge_info.http2 {
+        true => {
+            relay_to_http2_edge(request, &mut respond, &object, &edge_info, &context, &mut grpc_inspector).await;
+        },
+        false => {
+            relay_to_http1_edge(request, &mut respond, &object, &edge_info, &context, &mut grpc_inspector).await;
+        }
+    }
+
+    edge_server::decrement_conn_count(edge_info.destination);
+}
+
+async fn serve<T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>(conn: T, context: StreamContext) {
+    let max_concurrent_streams = match context.general_config.http2_max_concurrent_streams {
+        0 => { HTTP2_DEFAULT_MAX_CONCURRENT_STREAMS },
+        max_concurrent_streams => { max_concurrent_streams }
+    };
+
+    let mut h2_conn = match h2::server::Builder::new().max_concurrent_streams(max_concurrent_streams).handshake::<T, bytes::Bytes>(conn).await {
+        Ok(h2_conn) => { h2_conn },
+        Err(err) => {
+            eprintln!("HTTP/2 handshake with {} failed, error: {}", &context.connaddr, err.to_string());
+            return;
+        }
+    };
+
+    let mut streams: Vec<tokio::task::JoinHandle<()>> = Vec::new();
+
+    // accepting also drives the connection, the streams only make progress while this loop runs
+    while let Some(request) = h2_conn.accept().await {
+        match request {
+            Ok((request, respond)) => {
+                let context = context.clone();
+                streams.retain(|stream| !stream.is_finished());
+                streams.push(tokio::spawn(async move { stream_procedure(request, respond, context).await }));
+            },
+            Err(err) => {
+                eprintln!("HTTP/2 error from {}, error: {}; closing the connection", &context.connaddr, err.to_string());
+                break;
+            }
+        }
+    }
+
+    for stream in streams {
+        stream.abort();
+    }
+}
+
+pub async fn handler(conn: server::TcpClient, connaddr: std::net::SocketAddr, client_identity: Option<server::ClientIdentity>, tls_fingerprint: Option<client_hello::TlsFingerprint>, ip_rule: Option<configdb::IpRule>, listener_config: configdb::Listener, general_config: configdb::General) {
+    let https = matches!(conn, server::TcpClient::Https(_));
+    let context = StreamContext { connaddr: connaddr.to_string(), client_ip: connaddr.ip(), https, client_identity, tls_fingerprint, ip_rule, listener_config, general_config };
+
+    match conn {
+        server::TcpClient::Http(tcp_stream) => {
+            serve(tcp_stream, context).await;
+        },
+        server::TcpClient::Https(ssl_stream) => {
+            serve(ssl_stream, context).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    /// an edge connection that sends the given bytes and then closes
+    async fn edge_connection(data: &[u8]) -> server::TcpClient {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut edge = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (conn, _) = listener.accept().await.unwrap();
+
+        edge.write_all(data).await.unwrap();
+        edge.shutdown().await.unwrap();
+
+        return server::TcpClient::Http(conn);
+    }
+
+    async fn read_body(response: &[u8], method: &str) -> Result<Vec<u8>, std::io::Error> {
+        let mut edge_conn = edge_connection(response).await;
+        let (_, mut body) = EdgeResponseBody::read_head(&mut edge_conn, method).await?;
+        let mut result: Vec<u8> = Vec::new();
+
+        while let Some(block) = body.next_block(&mut edge_conn).await? {
+            result.extend(block);
+        }
+
+        return Ok(result);
+    }
+
+    #[tokio::test]
+    async fn reads_a_content_length_body_and_stops() {
+        let body = read_body(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhelloHTTP/1.1 200 OK\r\n", "GET").await.unwrap();
+        assert_eq!(body, b"hello".to_vec());
+    }
+
+    #[tokio::test]
+    async fn reads_a_chunked_body() {
+        let body = read_body(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n", "GET").await.unwrap();
+        assert_eq!(body, b"hello world".to_vec());
+    }
+
+    #[tokio::test]
+    async fn reads_a_body_delimited_by_the_close() {
+        let body = read_body(b"HTTP/1.0 200 OK\r\n\r\nuntil the end", "GET").await.unwrap();
+        assert_eq!(body, b"until the end".to_vec());
+    }
+
+    #[tokio::test]
+    async fn bodyless_responses_have_no_body() {
+        assert!(read_body(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n", "HEAD").await.unwrap().is_empty());
+        assert!(read_body(b"HTTP/1.1 204 No Content\r\n\r\n", "GET").await.unwrap().is_empty());
+        assert!(read_body(b"HTTP/1.1 304 Not Modified\r\n\r\n", "GET").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_and_faulty_bodies() {
+        assert!(read_body(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nhello", "GET").await.is_err());
+        assert!(read_body(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n", "GET").await.is_err());
+        assert!(read_body(b"HTTP/1.1 200 OK\r\nContent-Length: ten\r\n\r\nhello", "GET").await.is_err());
+        assert!(read_body(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffffff\r\n", "GET").await.is_err());
+    }
+}
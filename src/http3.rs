@@ -0,0 +1,616 @@
+use crate::configdb;
+use crate::cert_store;
+use crate::client;
+use crate::client_ip;
+use crate::csrf;
+use crate::edge_server;
+use crate::forwarded;
+use crate::header_policy;
+use crate::http1;
+use crate::http2;
+use crate::ip_rule;
+use crate::location_response;
+use crate::response;
+use crate::server;
+
+const HTTP3_DEFAULT_ALT_SVC_MAX_AGE: u64 = 86400;
+
+type RequestSendStream = h3::server::RequestStream<h3_quinn::SendStream<bytes::Bytes>, bytes::Bytes>;
+type RequestRecvStream = h3::server::RequestStream<h3_quinn::RecvStream, bytes::Bytes>;
+type CertifiedKeyCache = std::collections::HashMap<(String, String), std::sync::Arc<rustls::sign::CertifiedKey>>;
+
+lazy_static::lazy_static! {
+    #[allow(non_upper_case_globals)]
+    static ref CERTIFIED_KEYS: std::sync::Arc<std::sync::Mutex<CertifiedKeyCache>> = std::sync::Arc::new(std::sync::Mutex::new(CertifiedKeyCache::new()));
+}
+
+#[derive(Clone)]
+struct RequestContext {
+    connaddr: String,
+    client_ip: std::net::IpAddr,
+    ip_rule: Option<configdb::IpRule>,
+    listener_config: configdb::Listener,
+    general_config: configdb::General,
+}
+
+/// rustls does not use the OpenSSL contexts of the certificate store, it picks the same certificate files instead
+#[derive(Debug)]
+struct CertificateResolver {
+    listener_config: configdb::Listener,
+    general_config: configdb::General,
+}
+
+impl rustls::server::ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, client_hello: rustls::server::ClientHello) -> Option<std::sync::Arc<rustls::sign::CertifiedKey>> {
+        let (ssl_certificate, ssl_certificate_key) = match client_hello.server_name().and_then(|servername| cert_store::find_certificate_files(&self.listener_config, servername)) {
+            Some(certificate) => { (certificate.ssl_certificate, certificate.ssl_certificate_key) },
+            None => { (self.general_config.ssl_certificate.clone(), self.general_config.ssl_certificate_key.clone()) }
+        };
+
+        match get_certified_key(&ssl_certificate, &ssl_certificate_key) {
+            Ok(certified_key) => {
+                return Some(certified_key);
+            },
+            Err(err) => {
+                eprintln!("failed to load the certificate {} for HTTP/3, error: {}", &ssl_certificate, err.to_string());
+                return None;
+            }
+        }
+    }
+}
+
+fn http3_port(listener_config: &configdb::Listener, general_config: &configdb::General) -> u16 {
+    match general_config.http3_port {
+        0 => { listener_config.listen_port },
+        port => { port }
+    }
+}
+
+/// the Alt-Svc value advertising the HTTP/3 listener, None while it is not enabled or cannot start
+pub fn alt_svc(listener_config: &configdb::Listener, general_config: &configdb::General) -> Option<String> {
+    if !general_config.http3 || !listener_config.https || !matches!(general_config.client_certificate, configdb::ClientCertificate::None) {
+        return None;
+    }
+
+    let max_age = match general_config.http3_alt_svc_max_age {
+        0 => { HTTP3_DEFAULT_ALT_SVC_MAX_AGE },
+        max_age => { max_age }
+    };
+
+    return Some(format!("h3=\":{}\"; ma={}", http3_port(listener_config, general_config), max_age));
+}
+
+/// advertises HTTP/3 on a response, an Alt-Svc set by the edge server is kept
+pub fn add_alt_svc(headers: &mut http::HeaderMap, listener_config: &configdb::Listener, general_config: &configdb::General) {
+    if headers.contains_key(http::header::ALT_SVC) {
+        return;
+    }
+
+    if let Some(alt_svc) = alt_svc(listener_config, general_config) {
+        if let Ok(alt_svc) = http::header::HeaderValue::from_str(&alt_svc) {
+            headers.insert(http::header::ALT_SVC, alt_svc);
+        }
+    }
+}
+
+/// the certificate store reloaded, the keys are loaded again from the files on the next handshake
+pub fn clear_certificate_cache() {
+    match CERTIFIED_KEYS.lock() {
+        Ok(mut certified_keys) => {
+            certified_keys.clear();
+        },
+        Err(err) => {
+            eprintln!("internal error, failed to lock CERTIFIED_KEYS, error: {}; aborting", err.to_string());
+            std::process::abort();
+        }
+    }
+}
+
+fn load_certified_key(ssl_certificate: &str, ssl_certificate_key: &str) -> Result<rustls::sign::CertifiedKey, std::io::Error> {
+    use rustls::pki_types::pem::PemObject;
+
+    let certificates = match rustls::pki_types::CertificateDer::pem_file_iter(ssl_certificate) {
+        Ok(certificates) => {
+            match certificates.collect::<Result<Vec<_>, _>>() {
+                Ok(certificates) => { certificates },
+                Err(err) => {
+                    return Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+                }
+            }
+        },
+        Err(err) => {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+        }
+    };
+
+    let private_key = match rustls::pki_types::PrivateKeyDer::from_pem_file(ssl_certificate_key) {
+        Ok(private_key) => { private_key },
+        Err(err) => {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+        }
+    };
+
+    match rustls::crypto::ring::default_provider().key_provider.load_private_key(private_key) {
+        Ok(signing_key) => {
+            return Ok(rustls::sign::CertifiedKey::new(certificates, signing_key));
+        },
+        Err(err) => {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+        }
+    }
+}
+
+fn get_certified_key(ssl_certificate: &str, ssl_certificate_key: &str) -> Result<std::sync::Arc<rustls::sign::CertifiedKey>, std::io::Error> {
+    let cache_key = (ssl_certificate.to_string(), ssl_certificate_key.to_string());
+
+    match CERTIFIED_KEYS.lock() {
+        Ok(mut certified_keys) => {
+            if let Some(certified_key) = certified_keys.get(&cache_key) {
+                return Ok(certified_key.clone());
+            }
+
+            let certified_key = std::sync::Arc::new(load_certified_key(ssl_certificate, ssl_certificate_key)?);
+            certified_keys.insert(cache_key, certified_key.clone());
+            return Ok(certified_key);
+        },
+        Err(err) => {
+            eprintln!("internal error, failed to lock CERTIFIED_KEYS, error: {}; aborting", err.to_string());
+            std::process::abort();
+        }
+    }
+}
+
+/// QUIC mandates TLS 1.3, the TLS presets and cipher settings of the TCP listener do not apply here
+fn create_server_config(listener_config: &configdb::Listener, general_config: &configdb::General) -> Result<quinn::ServerConfig, std::io::Error> {
+    let provider = std::sync::Arc::new(rustls::crypto::ring::default_provider());
+
+    let mut tls_config = match rustls::ServerConfig::builder_with_provider(provider).with_protocol_versions(&[&rustls::version::TLS13]) {
+        Ok(builder) => {
+            builder.with_no_client_auth().with_cert_resolver(std::sync::Arc::new(CertificateResolver { listener_config: listener_config.clone(), general_config: general_config.clone() }))
+        },
+        Err(err) => {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+        }
+    };
+
+    tls_config.alpn_protocols = vec![b"h3".to_vec()];
+
+    match quinn::crypto::rustls::QuicServerConfig::try_from(tls_config) {
+        Ok(quic_config) => {
+            return Ok(quinn::ServerConfig::with_crypto(std::sync::Arc::new(quic_config)));
+        },
+        Err(err) => {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+        }
+    }
+}
+
+fn h3_error<E: std::fmt::Display>(err: E) -> std::io::Error {
+    return std::io::Error::new(std::io::ErrorKind::Other, err.to_string());
+}
+
+async fn recv_block(recv_stream: &mut RequestRecvStream) -> Result<Option<bytes::Bytes>, std::io::Error> {
+    match recv_stream.recv_data().await {
+        Ok(Some(mut data)) => {
+            let length = bytes::Buf::remaining(&data);
+            return Ok(Some(bytes::Buf::copy_to_bytes(&mut data, length)));
+        },
+        Ok(None) => {
+            return Ok(None);
+        },
+        Err(err) => {
+            return Err(h3_error(err));
+        }
+    }
+}
+
+async fn forward_request_body(recv_stream: &mut RequestRecvStream, edge_conn: &mut server::TcpClient, first_block: Option<bytes::Bytes>, chunked: bool) -> Result<(), std::io::Error> {
+    let mut block = first_block;
+
+    while let Some(data) = block {
+        if !data.is_empty() {
+            if chunked {
+                edge_conn.write_all(format!("{:x}\r\n", data.len()).as_bytes()).await?;
+                edge_conn.write_all(&data).await?;
+                edge_conn.write_all(b"\r\n").await?;
+            } else {
+                edge_conn.write_all(&data).await?;
+            }
+        }
+
+        block = recv_block(recv_stream).await?;
+    }
+
+    if chunked {
+        edge_conn.write_all(b"0\r\n\r\n").await?;
+    }
+
+    return Ok(());
+}
+
+/// relays the response of the edge to the stream, its body goes through the detectors and the data leak
+/// filter of the location rule; a response blocked on a leak is replaced by an error page
+async fn send_inspected_response(send_stream: &mut RequestSendStream, mut response: http::Response<()>, mut body: http2::EdgeBody<'_>, object: &http1::Http, connaddr: &str) -> Result<(), std::io::Error> {
+    header_policy::harden_response(response.headers_mut(), object);
+
+    let mut inspector = response::StreamInspector::new(connaddr, object);
+    inspector.start_response(response.status().as_u16(), response.headers());
+
+    let mut head = Some(response);
+
+    while let Some(data) = body.next_block().await.map_err(|(err, _)| err)? {
+        let data = inspector.process(&data);
+
+        if inspector.is_head_held() {
+            continue;
+        }
+
+        if let Some(response) = head.take() {
+            send_stream.send_response(response).await.map_err(h3_error)?;
+        }
+
+        if !data.is_empty() {
+            send_stream.send_data(bytes::Bytes::from(data)).await.map_err(h3_error)?;
+        }
+    }
+
+    let trailers = body.trailers().await.map_err(|(err, _)| err)?;
+
+    let rest = match inspector.finish() {
+        Some(rest) => { rest },
+        None => {
+            let (response, blocked_body) = response::create_blocked_stream_response();
+            send_stream.send_response(response).await.map_err(h3_error)?;
+            send_stream.send_data(blocked_body).await.map_err(h3_error)?;
+            return send_stream.finish().await.map_err(h3_error);
+        }
+    };
+
+    if let Some(response) = head.take() {
+        send_stream.send_response(response).await.map_err(h3_error)?;
+    }
+
+    if !rest.is_empty() {
+        send_stream.send_data(bytes::Bytes::from(rest)).await.map_err(h3_error)?;
+    }
+
+    // gRPC carries its status in the trailers
+    match trailers {
+        Some(trailers) => {
+            return send_stream.send_trailers(trailers).await.map_err(h3_error);
+        },
+        None => {
+            return send_stream.finish().await.map_err(h3_error);
+        }
+    }
+}
+
+async fn forward_response(edge_conn: &mut server::TcpClient, send_stream: &mut RequestSendStream, object: &http1::Http, connaddr: &str) -> Result<(), std::io::Error> {
+    let (edge_response, body) = http2::EdgeResponseBody::read_head(edge_conn, &object.method).await?;
+    let response = http2::create_response(&edge_response)?;
+
+    return send_inspected_response(send_stream, response, http2::EdgeBody::Http1(edge_conn, body), object, connaddr).await;
+}
+
+/// the redirect or static page of the location rule, answered without the edge server
+async fn send_location_response(send_stream: &mut RequestSendStream, location_response: &location_response::LocationResponse, object: &http1::Http) -> Result<(), std::io::Error> {
+    let mut response = http2::create_response(&location_response.response)?;
+    header_policy::harden_response(response.headers_mut(), object);
+
+    send_stream.send_response(response).await.map_err(h3_error)?;
+
+    if !location_response.body.is_empty() && object.method != "HEAD" {
+        send_stream.send_data(bytes::Bytes::from(location_response.body.clone())).await.map_err(h3_error)?;
+    }
+
+    return send_stream.finish().await.map_err(h3_error);
+}
+
+async fn relay_to_http1_edge(parts: &http::request::Parts, object: &http1::Http, send_stream: &mut RequestSendStream, recv_stream: &mut RequestRecvStream, edge_info: &configdb::Edge, context: &RequestContext) -> Result<(), (std::io::Error, h3::error::Code)> {
+    let mut edge_conn = client::connect_to_edge_server(edge_info).await.map_err(|err| (err, h3::error::Code::H3_REQUEST_REJECTED))?;
+
+    // a request without a body finishes its stream right after the headers, the edge must not see chunked framing then
+    let first_block = recv_block(recv_stream).await.map_err(|err| (err, h3::error::Code::H3_REQUEST_CANCELLED))?;
+    let chunked = !parts.headers.contains_key(http::header::CONTENT_LENGTH) && first_block.is_some();
+    // QUIC is always encrypted
+    let forwarded_headers = forwarded::create_forwarded_headers(object, &context.client_ip, true, &context.general_config);
+
+    let result = async {
+        edge_conn.write_all(http2::create_edge_request_head(&parts.headers, object, chunked, &forwarded_headers).as_bytes()).await?;
+        forward_request_body(recv_stream, &mut edge_conn, first_block, chunked).await?;
+        forward_response(&mut edge_conn, send_stream, object, &context.connaddr).await
+    };
+
+    return result.await.map_err(|err| (err, h3::error::Code::H3_INTERNAL_ERROR));
+}
+
+async fn exchange_with_http2_edge(parts: &http::request::Parts, object: &http1::Http, send_stream: &mut RequestSendStream, recv_stream: &mut RequestRecvStream, edge_info: &configdb::Edge, edge_connection: &http2::EdgeConnection, context: &RequestContext) -> Result<(), (std::io::Error, h3::error::Code)> {
+    // QUIC is always encrypted
+    let forwarded_headers = forwarded::create_forwarded_headers(object, &context.client_ip, true, &context.general_config);
+    let edge_request = http2::create_edge_request(parts, object, edge_info, &forwarded_headers).map_err(|err| (err, h3::error::Code::H3_INTERNAL_ERROR))?;
+
+    // a request without a body finishes its stream right after the headers, the edge stream must end with them too
+    let first_block = recv_block(recv_stream).await.map_err(|err| (err, h3::error::Code::H3_REQUEST_CANCELLED))?;
+    let end_of_stream = first_block.is_none();
+
+    let mut send_request = edge_connection.send_request.clone().ready().await.map_err(|err| (h3_error(err), h3::error::Code::H3_REQUEST_REJECTED))?;
+    let (response_future, mut edge_send_stream) = send_request.send_request(edge_request, end_of_stream).map_err(|err| (h3_error(err), h3::error::Code::H3_REQUEST_REJECTED))?;
+
+    // the request body and the response flow at the same time
+    let request_relay = async {
+        if end_of_stream {
+            return Ok(());
+        }
+
+        let result: Result<(), std::io::Error> = async {
+            let mut block = first_block;
+
+            while let Some(data) = block {
+                http2::send_data(&mut edge_send_stream, data, false).await?;
+                block = recv_block(recv_stream).await?;
+            }
+
+            match recv_stream.recv_trailers().await.map_err(h3_error)? {
+                Some(trailers) => {
+                    edge_send_stream.send_trailers(trailers).map_err(h3_error)
+                },
+                None => {
+                    http2::send_data(&mut edge_send_stream, bytes::Bytes::new(), true).await
+                }
+            }
+        }.await;
+
+        if result.is_err() {
+            edge_send_stream.send_reset(h2::Reason::CANCEL);
+        }
+
+        result.map_err(|err| (err, h3::error::Code::H3_REQUEST_CANCELLED))
+    };
+
+    let response_relay = async {
+        let result: Result<(), std::io::Error> = async {
+            let edge_response = response_future.await.map_err(h3_error)?;
+            let (edge_parts, edge_body) = edge_response.into_parts();
+
+            let mut response = http::Response::new(());
+            *response.status_mut() = edge_parts.status;
+            *response.headers_mut() = edge_parts.headers;
+
+            send_inspected_response(send_stream, response, http2::EdgeBody::Http2(edge_body), object, &context.connaddr).await
+        }.await;
+
+        result.map_err(|err| (err, h3::error::Code::H3_INTERNAL_ERROR))
+    };
+
+    let (request_result, response_result) = tokio::join!(request_relay, response_relay);
+
+    response_result?;
+    request_result?;
+
+    return Ok(());
+}
+
+async fn relay_to_http2_edge(parts: &http::request::Parts, object: &http1::Http, send_stream: &mut RequestSendStream, recv_stream: &mut RequestRecvStream, edge_info: &configdb::Edge, context: &RequestContext) -> Result<(), (std::io::Error, h3::error::Code)> {
+    let edge_connection = http2::get_edge_connection(edge_info).await.map_err(|err| (err, h3::error::Code::H3_REQUEST_REJECTED))?;
+    let result = exchange_with_http2_edge(parts, object, send_stream, recv_stream, edge_info, &edge_connection, context).await;
+    edge_connection.streams.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+
+    return result;
+}
+
+async fn request_procedure(request: http::Request<()>, stream: h3::server::RequestStream<h3_quinn::BidiStream<bytes::Bytes>, bytes::Bytes>, mut context: RequestContext) {
+    let (parts, _) = request.into_parts();
+    let object = http2::to_http1_request(&parts);
+    let (mut send_stream, mut recv_stream) = stream.split();
+
+    match client::resolve_client(&object, &context.connaddr, context.client_ip, &context.ip_rule, &context.general_config) {
+        Some((client_ip, ip_rule)) => {
+            context.client_ip = client_ip;
+            context.ip_rule = ip_rule;
+        },
+        None => {
+            send_stream.stop_stream(h3::error::Code::H3_REQUEST_CANCELLED);
+            return;
+        }
+    }
+
+    // the same request rules as the TCP listener, a rejected request only stops its own stream; QUIC carries no
+    // ClientHello to fingerprint, the rules allowing only some fingerprints refuse every HTTP/3 request
+    if client::evaluate_request(&object, &context.connaddr, &None, &None, &context.ip_rule, &context.general_config).is_none() {
+        send_stream.stop_stream(h3::error::Code::H3_REQUEST_CANCELLED);
+        return;
+    }
+
+    if let Some(location_response) = location_response::get_location_response(&object, true) {
+        println!("{} {} {} answered with {} by the location rule", &context.connaddr, &object.method, &object.location, location_response.response.status);
+
+        if let Err(err) = send_location_response(&mut send_stream, &location_response, &object).await {
+            eprintln!("failed to answer the HTTP/3 request from {}, error: {}", &context.connaddr, err.to_string());
+            send_stream.stop_stream(h3::error::Code::H3_INTERNAL_ERROR);
+        }
+
+        return;
+    }
+
+    if let Err(reason) = csrf::check_streamed_request(&object) {
+        println!("refusing the HTTP/3 request from {}, CSRF check failed: {}", &context.connaddr, reason);
+        send_stream.stop_stream(h3::error::Code::H3_REQUEST_CANCELLED);
+        return;
+    }
+
+    let edge_info = match edge_server::find_edge_server(true, &context.listener_config.edges) {
+        Some(edge_info) => { edge_info },
+        None => {
+            eprintln!("failed to find an edge server, refusing the request from {}", &context.connaddr);
+            send_stream.stop_stream(h3::error::Code::H3_REQUEST_REJECTED);
+            return;
+        }
+    };
+
+    let edgeaddr = format!("{}:{}", edge_info.destination, edge_info.destination_port);
+
+    let result = match edge_info.http2 {
+        true => { relay_to_http2_edge(&parts, &object, &mut send_stream, &mut recv_stream, &edge_info, &context).await },
+        false => { relay_to_http1_edge(&parts, &object, &mut send_stream, &mut recv_stream, &edge_info, &context).await }
+    };
+
+    if let Err((err, code)) = result {
+        eprintln!("failed to relay the HTTP/3 request from client {} to edge server {}, error: {}", &context.connaddr, &edgeaddr, err.to_string());
+        send_stream.stop_stream(code);
+    }
+
+    edge_server::decrement_conn_count(edge_info.destination);
+}
+
+async fn connection_procedure(incoming: quinn::Incoming, listener_config: configdb::Listener, general_config: configdb::General) {
+    let conn = match incoming.await {
+        Ok(conn) => { conn },
+        Err(err) => {
+            eprintln!("failed to accept a QUIC connection, error: {}", err.to_string());
+            return;
+        }
+    };
+
+    let connaddr = client_ip::to_canonical(conn.remote_address());
+    let connaddr_friendly = connaddr.to_string();
+    let ip_rule = ip_rule::get_ip_rule(connaddr.ip().to_string());
+
+    // the ClientHello of a QUIC handshake is not peeked, TLS fingerprint rules only match TCP connections
+    if !client::evaluate_connection(&connaddr_friendly, &ip_rule, &None, &general_config) {
+        conn.close(quinn::VarInt::from_u32(0), b"blocked");
+        return;
+    }
+
+    println!("new HTTP/3 connection {connaddr_friendly}");
+
+    let mut h3_conn: h3::server::Connection<h3_quinn::Connection, bytes::Bytes> = match h3::server::Connection::new(h3_quinn::Connection::new(conn)).await {
+        Ok(h3_conn) => { h3_conn },
+        Err(err) => {
+            eprintln!("HTTP/3 handshake with {} failed, error: {}", &connaddr_friendly, err.to_string());
+            return;
+        }
+    };
+
+    let context = RequestContext { connaddr: connaddr_friendly.clone(), client_ip: connaddr.ip(), ip_rule, listener_config, general_config };
+    let mut requests: Vec<tokio::task::JoinHandle<()>> = Vec::new();
+
+    loop {
+        match h3_conn.accept().await {
+            Ok(Some(resolver)) => {
+                let context = context.clone();
+                requests.retain(|request| !request.is_finished());
+                requests.push(tokio::spawn(async move {
+                    match resolver.resolve_request().await {
+                        Ok((request, stream)) => {
+                            request_procedure(request, stream, context).await;
+                        },
+                        Err(err) => {
+                            eprintln!("failed to read an HTTP/3 request from {}, error: {}", &context.connaddr, err.to_string());
+                        }
+                    }
+                }));
+            },
+            Ok(None) => {
+                break;
+            },
+            Err(err) => {
+                if !err.is_h3_no_error() {
+                    eprintln!("HTTP/3 error from {}, error: {}; closing the connection", &connaddr_friendly, err.to_string());
+                }
+
+                break;
+            }
+        }
+    }
+
+    for request in requests {
+        request.abort();
+    }
+
+    println!("the HTTP/3 connection with {}, closed", &connaddr_friendly);
+}
+
+/// the QUIC listener of an HTTPS listener, it shares the certificates and the rules with the TCP one
+pub async fn start(listener_config: configdb::Listener, general_config: configdb::General) {
+    if !matches!(general_config.client_certificate, configdb::ClientCertificate::None) {
+        eprintln!("client certificates are not supported over HTTP/3, the HTTP/3 listener is not started");
+        return;
+    }
+
+    let address = server::format_address(&listener_config.listen_address, http3_port(&listener_config, &general_config));
+
+    // bound like the TCP listener, dual-stack unless it is IPv6 only
+    let socket = match server::create_socket(&listener_config, http3_port(&listener_config, &general_config), socket2::Type::DGRAM) {
+        Ok(socket) => { socket },
+        Err(err) => {
+            eprintln!("failed to bind the address {} (UDP), error: {}", &address, err.to_string());
+            return;
+        }
+    };
+
+    let server_config = match create_server_config(&listener_config, &general_config) {
+        Ok(server_config) => { server_config },
+        Err(err) => {
+            eprintln!("failed to create the QUIC layer, error: {}", err.to_string());
+            return;
+        }
+    };
+
+    let endpoint = match quinn::Endpoint::new(quinn::EndpointConfig::default(), Some(server_config), socket.into(), std::sync::Arc::new(quinn::TokioRuntime)) {
+        Ok(endpoint) => { endpoint },
+        Err(err) => {
+            eprintln!("failed to bind the address {} (UDP), error: {}", &address, err.to_string());
+            return;
+        }
+    };
+
+    println!("HTTP/3 listening on {} (UDP)", &address);
+
+    let connections = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+    while let Some(incoming) = endpoint.accept().await {
+        if connections.load(std::sync::atomic::Ordering::SeqCst) >= general_config.maximum_connections {
+            println!("refusing to accept {} due limit of number of connections reached", incoming.remote_address().to_string());
+            incoming.refuse();
+            continue;
+        }
+
+        let connections = std::sync::Arc::clone(&connections);
+        let listener_config = listener_config.clone();
+        let general_config = general_config.clone();
+
+        connections.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        tokio::spawn(async move {
+            connection_procedure(incoming, listener_config, general_config).await;
+            connections.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn https_listener() -> configdb::Listener {
+        return configdb::Listener { listen_address: String::from("::"), listen_port: 443, https: true, ..Default::default() };
+    }
+
+    #[test]
+    fn advertises_the_http3_port_of_the_listener() {
+        let general_config = configdb::General { http3: true, ..Default::default() };
+        assert_eq!(alt_svc(&https_listener(), &general_config), Some(format!("h3=\":443\"; ma={}", HTTP3_DEFAULT_ALT_SVC_MAX_AGE)));
+
+        let general_config = configdb::General { http3: true, http3_port: 8443, http3_alt_svc_max_age: 60, ..Default::default() };
+        assert_eq!(alt_svc(&https_listener(), &general_config), Some(String::from("h3=\":8443\"; ma=60")));
+    }
+
+    #[test]
+    fn does_not_advertise_a_listener_that_is_not_started() {
+        let general_config = configdb::General { http3: true, client_certificate: configdb::ClientCertificate::Optional, ..Default::default() };
+        assert_eq!(alt_svc(&https_listener(), &general_config), None);
+
+        let general_config = configdb::General { http3: true, ..Default::default() };
+        assert_eq!(alt_svc(&configdb::Listener { https: false, ..https_listener() }, &general_config), None);
+        assert_eq!(alt_svc(&https_listener(), &configdb::General::default()), None);
+    }
+}
@@ -0,0 +1,172 @@
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+
+use crate::acme;
+use crate::client;
+use crate::client_ip;
+use crate::configdb;
+use crate::host_rule;
+use crate::http1;
+use crate::ip_rule;
+use crate::proxy_protocol;
+
+const REQUEST_HEAD_HARD_LIMIT: usize = 16 * 1024;
+/// a client gets this long to send its request head, the listener does nothing else worth waiting for
+const REQUEST_HEAD_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+const BAD_REQUEST_RESPONSE: &[u8] = b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+async fn read_request_head(conn: &mut tokio::net::TcpStream) -> Result<Vec<u8>, std::io::Error> {
+    let mut result: Vec<u8> = Vec::new();
+    let mut block = [0_u8; 1500];
+
+    while !result.windows(4).any(|window| window == b"\r\n\r\n") {
+        if result.len() > REQUEST_HEAD_HARD_LIMIT {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, "hard limit on request header reached"));
+        }
+
+        match conn.read(&mut block).await? {
+            0 => { return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "the client closed the connection")); },
+            len => { result.extend_from_slice(&block[..len]); }
+        }
+    }
+
+    let header_length = result.windows(4).position(|window| window == b"\r\n\r\n").unwrap_or(result.len());
+    result.truncate(header_length);
+    return Ok(result);
+}
+
+/// the same host and location over HTTPS; other methods than GET and HEAD get a 308 so that the client sends
+/// their body again. Only an origin-form target is redirected, "@evil.com" would otherwise turn the host into
+/// the credentials of another one
+fn create_redirect_response(object: &http1::Http, listener_config: &configdb::Listener) -> Option<Vec<u8>> {
+    let hostname = host_rule::get_hostname(object);
+
+    if hostname.is_empty() || !object.location.starts_with('/') {
+        return None;
+    }
+
+    let port = match listener_config.https_port {
+        0 | 443 => { String::new() },
+        port => { format!(":{}", port) }
+    };
+
+    let status = match object.method.as_str() {
+        "GET" | "HEAD" => { "301 Moved Permanently" },
+        _ => { "308 Permanent Redirect" }
+    };
+
+    return Some(format!("HTTP/1.1 {}\r\nLocation: https://{}{}{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status, hostname, port, object.location).into_bytes());
+}
+
+async fn handler(mut conn: tokio::net::TcpStream, connaddr: std::net::SocketAddr, listener_config: configdb::Listener, general_config: configdb::General) {
+    let connaddr = match proxy_protocol::accept(&mut conn, connaddr, &general_config).await {
+        Some(connaddr) => { connaddr },
+        None => { return; }
+    };
+
+    let connaddr_friendly = connaddr.to_string();
+    let ip_rule = ip_rule::get_ip_rule(connaddr.ip().to_string());
+
+    if !client::evaluate_connection(&connaddr_friendly, &ip_rule, &None, &general_config) {
+        return;
+    }
+
+    let request_head = match tokio::time::timeout(REQUEST_HEAD_TIMEOUT, read_request_head(&mut conn)).await {
+        Ok(Ok(request_head)) => { request_head },
+        Ok(Err(err)) => {
+            eprintln!("failed to read the request of {}, error: {}; closing the connection", &connaddr_friendly, err.to_string());
+            return;
+        },
+        Err(_) => {
+            println!("client {} sent no request in time, closing the connection", &connaddr_friendly);
+            return;
+        }
+    };
+
+    let object = match http1::parse(request_head) {
+        Ok(object) => { object },
+        Err(err) => {
+            eprintln!("processing the request from {} failed, error: {}", &connaddr_friendly, err.to_string());
+            let _ = conn.write_all(BAD_REQUEST_RESPONSE).await;
+            return;
+        }
+    };
+
+    if let Some((token, response)) = acme::create_http01_response(&object.location) {
+        println!("answering the ACME challenge {} for {}", token, &connaddr_friendly);
+
+        if let Err(err) = conn.write_all(&response).await {
+            eprintln!("failed to answer the ACME challenge for {}, error: {}", &connaddr_friendly, err.to_string());
+        }
+
+        return;
+    }
+
+    let response = match create_redirect_response(&object, &listener_config) {
+        Some(response) => { response },
+        None => {
+            println!("client {} sent no host or no path to redirect to", &connaddr_friendly);
+            let _ = conn.write_all(BAD_REQUEST_RESPONSE).await;
+            return;
+        }
+    };
+
+    if let Err(err) = conn.write_all(&response).await {
+        eprintln!("failed to redirect {}, error: {}", &connaddr_friendly, err.to_string());
+    }
+}
+
+/// the plain HTTP listener of port 80, nothing on it reaches the edge servers
+pub async fn serve(listener: tokio::net::TcpListener, listener_config: configdb::Listener, general_config: configdb::General) {
+    loop {
+        match listener.accept().await {
+            Ok((conn, connaddr)) => {
+                tokio::spawn(handler(conn, client_ip::to_canonical(connaddr), listener_config.clone(), general_config.clone()));
+            },
+            Err(err) => {
+                eprintln!("failed to accept a client, error: {}", err.to_string());
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, location: &str, host: &str) -> http1::Http {
+        return http1::Http { location: location.to_string(), method: method.to_string(), properties: [(String::from("Host"), host.to_string())].into_iter().collect() };
+    }
+
+    fn redirect(object: &http1::Http, https_port: u16) -> Option<String> {
+        let listener_config = configdb::Listener { https_port, ..Default::default() };
+        return create_redirect_response(object, &listener_config).map(|response| String::from_utf8(response).unwrap());
+    }
+
+    #[test]
+    fn redirects_get_with_301_and_other_methods_with_308() {
+        let response = redirect(&request("GET", "/shop?page=2", "www.example.com"), 443).unwrap();
+        assert!(response.starts_with("HTTP/1.1 301 Moved Permanently\r\nLocation: https://www.example.com/shop?page=2\r\n"));
+
+        let response = redirect(&request("POST", "/login", "www.example.com"), 0).unwrap();
+        assert!(response.starts_with("HTTP/1.1 308 Permanent Redirect\r\nLocation: https://www.example.com/login\r\n"));
+    }
+
+    #[test]
+    fn adds_the_https_port_unless_it_is_the_default_one() {
+        let response = redirect(&request("HEAD", "/", "www.example.com:8080"), 8443).unwrap();
+        assert!(response.contains("\r\nLocation: https://www.example.com:8443/\r\n"));
+
+        let response = redirect(&request("GET", "/", "www.example.com:8080"), 443).unwrap();
+        assert!(response.contains("\r\nLocation: https://www.example.com/\r\n"));
+    }
+
+    #[test]
+    fn refuses_targets_that_are_not_a_path() {
+        assert_eq!(redirect(&request("GET", "@evil.com", "www.example.com"), 443), None);
+        assert_eq!(redirect(&request("GET", "http://evil.com/", "www.example.com"), 443), None);
+        assert_eq!(redirect(&request("OPTIONS", "*", "www.example.com"), 443), None);
+        assert_eq!(redirect(&request("GET", "/", ""), 443), None);
+    }
+}
@@ -1,5 +1,13 @@
 use crate::configdb;
 
+/// the counters of the clients seen in the last second are kept, older ones are forgotten
+const REQUEST_COUNTERS_HARD_LIMIT: usize = 100000;
+
+lazy_static::lazy_static! {
+    #[allow(non_upper_case_globals)]
+    static ref REQUEST_COUNTERS: std::sync::Arc<std::sync::Mutex<std::collections::HashMap<std::net::IpAddr, (u64, usize)>>> = std::sync::Arc::new(std::sync::Mutex::new(std::collections::HashMap::new()));
+}
+
 pub fn get_ip_rule(ip: String) -> Option<configdb::IpRule> {
     let rule_filename = format!("{}/{}.yaml", configdb::IP_RULES_DIRNAME, ip);
 
@@ -26,3 +34,37 @@ pub fn get_ip_rule(ip: String) -> Option<configdb::IpRule> {
         }
     }
 }
+
+/// counts the request of the client within the current second, true once it goes beyond the limit of its rule
+pub fn exceeds_limit_rate(ip_rule: &Option<configdb::IpRule>, ip: std::net::IpAddr) -> bool {
+    let limit_rate = match ip_rule {
+        Some(ip_rule) if ip_rule.limit_rate > 0 => { ip_rule.limit_rate },
+        _ => { return false; }
+    };
+
+    let now = match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        Ok(now) => { now.as_secs() },
+        Err(_) => { return false; }
+    };
+
+    match REQUEST_COUNTERS.lock() {
+        Ok(mut request_counters) => {
+            if request_counters.len() >= REQUEST_COUNTERS_HARD_LIMIT {
+                request_counters.retain(|_, (second, _)| *second == now);
+            }
+
+            let counter = request_counters.entry(ip).or_insert((now, 0));
+
+            if counter.0 != now {
+                *counter = (now, 0);
+            }
+
+            counter.1 = counter.1 + 1;
+            return counter.1 > limit_rate;
+        },
+        Err(err) => {
+            eprintln!("internal error, failed to lock REQUEST_COUNTERS, error: {}; aborting", err.to_string());
+            std::process::abort();
+        }
+    }
+}
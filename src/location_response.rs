@@ -0,0 +1,94 @@
+use crate::header_policy;
+use crate::host_rule;
+use crate::http1;
+use crate::location_rule;
+use crate::response;
+
+pub const REDIRECT_STATUSES: [u16; 4] = [301, 302, 307, 308];
+
+/// a response the WAF gives on its own, without contacting an edge server
+pub struct LocationResponse {
+    pub response: http1::HttpResponse,
+    pub body: Vec<u8>,
+}
+
+/// "{scheme}://{hostname}{uri}"; the host keeps its port, the hostname does not
+fn expand_template(template: &str, object: &http1::Http, https: bool) -> String {
+    let host = http1::find_property(&object.properties, "host").map(|host| host.trim().to_string()).unwrap_or_default();
+    let (path, query) = object.location.split_once('?').unwrap_or((&object.location, ""));
+
+    let scheme = match https {
+        true => { "https" },
+        false => { "http" }
+    };
+
+    return template
+        .replace("{scheme}", scheme)
+        .replace("{hostname}", &host_rule::get_hostname(object))
+        .replace("{host}", &host)
+        .replace("{uri}", &object.location)
+        .replace("{path}", path)
+        .replace("{query}", query);
+}
+
+fn create_response(status: u16, mut headers: Vec<(String, String)>, body: Vec<u8>) -> LocationResponse {
+    headers.retain(|(name, _)| !name.eq_ignore_ascii_case("content-length"));
+    headers.push((String::from("Content-Length"), body.len().to_string()));
+
+    let reason = http::StatusCode::from_u16(status).ok().and_then(|status| status.canonical_reason()).unwrap_or_default();
+    let properties = headers.iter().cloned().collect();
+
+    return LocationResponse { response: http1::HttpResponse { status, reason: reason.to_string(), properties, headers }, body };
+}
+
+/// the redirect or static response of the location rule, None when the request goes to an edge server
+pub fn get_location_response(object: &http1::Http, https: bool) -> Option<LocationResponse> {
+    let location_rule = location_rule::get_location_rule(&object.method, &object.location)?;
+
+    if let Some(redirect) = &location_rule.redirect {
+        // an invalid status was reported when the rule was loaded
+        let status = match REDIRECT_STATUSES.contains(&redirect.status) {
+            true => { redirect.status },
+            false => { 302 }
+        };
+
+        let headers = vec![(String::from("Location"), expand_template(&redirect.location, object, https))];
+        return Some(create_response(status, headers, Vec::new()));
+    }
+
+    if let Some(static_response) = &location_rule.static_response {
+        let headers: Vec<(String, String)> = static_response.headers.iter().map(|header| (header.name.clone(), header.value.clone())).collect();
+
+        if static_response.body_file.is_empty() {
+            return Some(create_response(static_response.status, headers, Vec::new()));
+        }
+
+        // read on every request, the page can be changed while the WAF runs
+        match std::fs::read(&static_response.body_file) {
+            Ok(body) => {
+                return Some(create_response(static_response.status, headers, body));
+            },
+            Err(err) => {
+                eprintln!("failed to read from {}, error: {}; answering with an internal error", &static_response.body_file, err.to_string());
+                return Some(create_response(500, Vec::new(), Vec::new()));
+            }
+        }
+    }
+
+    return None;
+}
+
+/// the response written to an HTTP/1.1 client, the connection is closed after it; pipelined requests sent before
+/// it may be left unanswered
+pub fn to_http1(location_response: &LocationResponse, object: &http1::Http) -> Vec<u8> {
+    let mut response = location_response.response.clone();
+    header_policy::apply_response_policy(&mut response.headers, &header_policy::get_response_policy(object), &object.method, &object.location);
+    response.headers.push((String::from("Connection"), String::from("close")));
+
+    let mut result = response::create_response_head(&response);
+    if object.method != "HEAD" {
+        result.extend_from_slice(&location_response.body);
+    }
+
+    return result;
+}
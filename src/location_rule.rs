@@ -1,22 +1,36 @@
 use notify::Watcher;
 
 use crate::configdb;
+use crate::detector;
+use crate::location_response;
+use crate::rewrite;
+use crate::server;
 
 lazy_static::lazy_static! {
     #[allow(non_upper_case_globals)]
     static ref LOCATION_LISTS: std::sync::Arc<std::sync::Mutex<Vec<configdb::LocationRule>>> = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
 }
 
+/// the rule of the exact location, otherwise the rule of the longest prefix the location starts with
 pub fn get_location_rule<Method: AsRef<str>, Location: AsRef<str>>(method: Method, location: Location) -> Option<configdb::LocationRule> {
     let mut result: Option<configdb::LocationRule> = None;
 
     match LOCATION_LISTS.lock() {
         Ok(location_list) => {
             for rule in location_list.iter() {
-                if rule.method.as_str() == method.as_ref() && rule.location.as_str() == location.as_ref() {
+                if rule.method.as_str() != method.as_ref() {
+                    continue;
+                }
+
+                if rule.location.as_str() == location.as_ref() && matches!(rule.location_match, configdb::LocationMatch::Exact) {
                     result = Some((*rule).clone());
                     break;
                 }
+
+                let is_longer_prefix = result.as_ref().is_none_or(|prefix_rule| prefix_rule.location.len() < rule.location.len());
+                if matches!(rule.location_match, configdb::LocationMatch::Prefix) && location.as_ref().starts_with(rule.location.as_str()) && is_longer_prefix {
+                    result = Some((*rule).clone());
+                }
             }
         },
         Err(err) => {
@@ -28,6 +42,45 @@ pub fn get_location_rule<Method: AsRef<str>, Location: AsRef<str>>(method: Metho
     result
 }
 
+/// the cookie policies of every location rule
+pub fn get_cookie_policies() -> Vec<configdb::CookiePolicy> {
+    match LOCATION_LISTS.lock() {
+        Ok(location_list) => {
+            return location_list.iter().filter_map(|rule| rule.cookie_policy.clone()).collect();
+        },
+        Err(err) => {
+            eprintln!("internal error, failed to lock LOCATION_LISTS, error: {}; aborting", err.to_string());
+            std::process::abort();
+        }
+    }
+}
+
+/// a rule listing client subjects, alternative names or fingerprints only admits clients whose verified
+/// certificate matches one of them
+pub fn is_client_identity_allowed(rule: &configdb::LocationRule, client_identity: &Option<server::ClientIdentity>) -> bool {
+    if rule.client_subjects.is_empty() && rule.client_sans.is_empty() && rule.client_fingerprints.is_empty() {
+        return true;
+    }
+
+    match client_identity {
+        Some(client_identity) => {
+            if rule.client_subjects.contains(&client_identity.subject) {
+                return true;
+            }
+
+            if client_identity.sans.iter().any(|san| rule.client_sans.contains(san)) {
+                return true;
+            }
+
+            // fingerprints are compared in hex regardless of the case and colons used in the rule
+            return rule.client_fingerprints.iter().any(|fingerprint| fingerprint.replace(':', "").eq_ignore_ascii_case(&client_identity.fingerprint));
+        },
+        None => {
+            return false;
+        }
+    }
+}
+
 fn load_rules() {
     match LOCATION_LISTS.lock() {
         Ok(mut location_list) => {
@@ -44,6 +97,22 @@ fn load_rules() {
                                     Ok(content) => {
                                         match serde_yaml::from_str::<configdb::LocationRule>(&content) {
                                             Ok(object) => {
+                                                for detector_name in object.websocket_detectors.iter().chain(object.response_detectors.iter()).filter(|detector_name| !detector::is_known(detector_name)) {
+                                                    eprintln!("unknown detector '{}' in {}, it is ignored", detector_name, &filename);
+                                                }
+
+                                                for detector_name in object.dlp_detectors.iter().filter(|detector_name| !detector::is_known_leak(detector_name)) {
+                                                    eprintln!("unknown data leak detector '{}' in {}, it is ignored", detector_name, &filename);
+                                                }
+
+                                                if let Some(Err(err)) = object.rewrite.as_ref().filter(|rewrite| !rewrite.path_pattern.is_empty()).map(|rewrite| rewrite::add_path_pattern(&rewrite.path_pattern)) {
+                                                    eprintln!("invalid path pattern in {}, error: {}; the path is not rewritten", &filename, err.to_string());
+                                                }
+
+                                                if let Some(redirect) = object.redirect.as_ref().filter(|redirect| !location_response::REDIRECT_STATUSES.contains(&redirect.status)) {
+                                                    eprintln!("invalid redirect status {} in {}, 302 is used", redirect.status, &filename);
+                                                }
+
                                                 location_list.push(object);
                                             },
                                             Err(err) => {
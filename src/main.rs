@@ -5,6 +5,25 @@ pub mod edge_server;
 pub mod ip_rule;
 pub mod http1;
 pub mod location_rule;
+pub mod cert_store;
+pub mod acme;
+pub mod client_hello;
+pub mod http2;
+pub mod grpc;
+pub mod http3;
+pub mod detector;
+pub mod websocket;
+pub mod response;
+pub mod host_rule;
+pub mod header_policy;
+pub mod cookie_protection;
+pub mod csrf;
+pub mod forwarded;
+pub mod client_ip;
+pub mod proxy_protocol;
+pub mod rewrite;
+pub mod location_response;
+pub mod https_redirect;
 
 #[tokio::main]
 async fn main() {
@@ -12,6 +31,8 @@ async fn main() {
 
     loop {
         location_rule::initialize();
+        grpc::initialize();
+        host_rule::initialize();
         edge_server::initialize();
 
         let thread = tokio::spawn(async move {
@@ -0,0 +1,376 @@
+use tokio::io::AsyncReadExt;
+
+use crate::configdb;
+use crate::client_ip;
+
+const V1_SIGNATURE: &[u8] = b"PROXY ";
+const V2_SIGNATURE: [u8; 12] = [0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a];
+/// the longest v1 line, "PROXY TCP6" with both addresses and ports at their longest
+const V1_HEADER_HARD_LIMIT: usize = 107;
+const V2_HEADER_LENGTH: usize = 16;
+/// the load balancer sends the header right after connecting, a client that does not stalls the listener
+const HEADER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// what the header preceding the client's data said
+pub enum Header {
+    Missing,
+    /// a health check of the load balancer itself, or a client whose address it could not tell
+    Local,
+    Proxied(std::net::SocketAddr),
+}
+
+fn to_io_error(message: &str) -> std::io::Error {
+    return std::io::Error::new(std::io::ErrorKind::Other, message.to_string());
+}
+
+/// whether the connection starts with either signature, waiting while the data received so far could still be one
+async fn peek_signature(conn: &tokio::net::TcpStream) -> Result<Option<u8>, std::io::Error> {
+    let mut block = [0_u8; V2_SIGNATURE.len()];
+
+    for _ in 0..50 {
+        let len = conn.peek(&mut block).await?;
+
+        if len == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "the connection closed before the PROXY protocol header"));
+        }
+
+        if len >= V1_SIGNATURE.len() && block.starts_with(V1_SIGNATURE) {
+            return Ok(Some(1));
+        }
+
+        if len == V2_SIGNATURE.len() && block == V2_SIGNATURE {
+            return Ok(Some(2));
+        }
+
+        if !V1_SIGNATURE.starts_with(&block[..len.min(V1_SIGNATURE.len())]) && !V2_SIGNATURE.starts_with(&block[..len]) {
+            return Ok(None);
+        }
+
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+
+    return Ok(None);
+}
+
+/// "PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n"
+async fn read_v1(conn: &mut tokio::net::TcpStream) -> Result<Header, std::io::Error> {
+    let mut line: Vec<u8> = Vec::new();
+    let mut byte = [0_u8; 1];
+
+    // read byte by byte, whatever follows the line belongs to the TLS or HTTP layer
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_HEADER_HARD_LIMIT {
+            return Err(to_io_error("the PROXY protocol v1 line is too long"));
+        }
+
+        conn.read_exact(&mut byte).await?;
+        line.push(byte[0]);
+    }
+
+    let line = String::from_utf8_lossy(&line[..line.len() - 2]).to_string();
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    match fields.get(1) {
+        Some(&"TCP4") | Some(&"TCP6") if fields.len() == 6 => {
+            match (fields[2].parse::<std::net::IpAddr>(), fields[4].parse::<u16>()) {
+                (Ok(ip), Ok(port)) => { return Ok(Header::Proxied(std::net::SocketAddr::new(ip, port))); },
+                _ => { return Err(to_io_error(&format!("faulty PROXY protocol line is '{}'", line))); }
+            }
+        },
+        Some(&"UNKNOWN") => {
+            return Ok(Header::Local);
+        },
+        _ => {
+            return Err(to_io_error(&format!("faulty PROXY protocol line is '{}'", line)));
+        }
+    }
+}
+
+/// the binary header, its TLVs are skipped
+async fn read_v2(conn: &mut tokio::net::TcpStream) -> Result<Header, std::io::Error> {
+    let mut header = [0_u8; V2_HEADER_LENGTH];
+    conn.read_exact(&mut header).await?;
+
+    let mut addresses = vec![0_u8; u16::from_be_bytes([header[14], header[15]]) as usize];
+    conn.read_exact(&mut addresses).await?;
+
+    if header[12] >> 4 != 2 {
+        return Err(to_io_error("unsupported PROXY protocol version"));
+    }
+
+    match header[12] & 0x0f {
+        0 => { return Ok(Header::Local); },
+        1 => {},
+        _ => { return Err(to_io_error("unsupported PROXY protocol command")); }
+    }
+
+    // TCP over IPv4 or IPv6, source address and port come first
+    match header[13] {
+        0x11 if addresses.len() >= 12 => {
+            let ip = std::net::Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            return Ok(Header::Proxied(std::net::SocketAddr::new(std::net::IpAddr::V4(ip), port)));
+        },
+        0x21 if addresses.len() >= 36 => {
+            let mut octets = [0_u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            return Ok(Header::Proxied(std::net::SocketAddr::new(std::net::IpAddr::V6(std::net::Ipv6Addr::from(octets)), port)));
+        },
+        _ => {
+            return Ok(Header::Local);
+        }
+    }
+}
+
+pub async fn read_header(conn: &mut tokio::net::TcpStream) -> Result<Header, std::io::Error> {
+    let result = tokio::time::timeout(HEADER_TIMEOUT, async {
+        match peek_signature(conn).await? {
+            Some(1) => { return read_v1(conn).await; },
+            Some(_) => { return read_v2(conn).await; },
+            None => { return Ok(Header::Missing); }
+        }
+    });
+
+    match result.await {
+        Ok(header) => { return header; },
+        Err(_) => { return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out waiting for the PROXY protocol header")); }
+    }
+}
+
+/// the address of the client as told by the load balancer in front of the listener, None when the connection
+/// must be dropped
+pub async fn accept(conn: &mut tokio::net::TcpStream, connaddr: std::net::SocketAddr, general_config: &configdb::General) -> Option<std::net::SocketAddr> {
+    let required = match general_config.proxy_protocol {
+        configdb::ProxyProtocol::Disabled => { return Some(connaddr); },
+        configdb::ProxyProtocol::Optional => { false },
+        configdb::ProxyProtocol::Required => { true }
+    };
+
+    // only the load balancer may tell another address, a client's header is not even looked for
+    if !client_ip::is_trusted_proxy(&connaddr.ip(), general_config) {
+        if required {
+            println!("dropping connection with {}, PROXY protocol header required from a trusted proxy", connaddr);
+            return None;
+        }
+
+        return Some(connaddr);
+    }
+
+    match read_header(conn).await {
+        Ok(Header::Proxied(client_addr)) => {
+            return Some(client_addr);
+        },
+        Ok(Header::Local) => {
+            return Some(connaddr);
+        },
+        Ok(Header::Missing) => {
+            if required {
+                println!("dropping connection with {}, PROXY protocol header missing", connaddr);
+                return None;
+            }
+
+            return Some(connaddr);
+        },
+        Err(err) => {
+            eprintln!("failed to read the PROXY protocol header from {}, error: {}; dropping the connection", connaddr, err.to_string());
+            return None;
+        }
+    }
+}
+
+/// the header telling the edge server who the client is and which address it connected to
+pub fn create_header(version: configdb::EdgeProxyProtocol, source: std::net::SocketAddr, destination: std::net::SocketAddr) -> Vec<u8> {
+    let source = client_ip::to_canonical(source);
+    let destination = client_ip::to_canonical(destination);
+
+    match version {
+        configdb::EdgeProxyProtocol::None => {
+            return Vec::new();
+        },
+        configdb::EdgeProxyProtocol::V1 => {
+            let family = match (source.ip(), destination.ip()) {
+                (std::net::IpAddr::V4(_), std::net::IpAddr::V4(_)) => { "TCP4" },
+                (std::net::IpAddr::V6(_), std::net::IpAddr::V6(_)) => { "TCP6" },
+                _ => { return b"PROXY UNKNOWN\r\n".to_vec(); }
+            };
+
+            return format!("PROXY {} {} {} {} {}\r\n", family, source.ip(), destination.ip(), source.port(), destination.port()).into_bytes();
+        },
+        configdb::EdgeProxyProtocol::V2 => {
+            let mut result = V2_SIGNATURE.to_vec();
+
+            match (source.ip(), destination.ip()) {
+                (std::net::IpAddr::V4(source_ip), std::net::IpAddr::V4(destination_ip)) => {
+                    result.extend_from_slice(&[0x21, 0x11, 0, 12]);
+                    result.extend_from_slice(&source_ip.octets());
+                    result.extend_from_slice(&destination_ip.octets());
+                },
+                (std::net::IpAddr::V6(source_ip), std::net::IpAddr::V6(destination_ip)) => {
+                    result.extend_from_slice(&[0x21, 0x21, 0, 36]);
+                    result.extend_from_slice(&source_ip.octets());
+                    result.extend_from_slice(&destination_ip.octets());
+                },
+                _ => {
+                    result.extend_from_slice(&[0x20, 0x00, 0, 0]);
+                    return result;
+                }
+            }
+
+            result.extend_from_slice(&source.port().to_be_bytes());
+            result.extend_from_slice(&destination.port().to_be_bytes());
+            return result;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    /// the accepted end of a loopback connection the given bytes were written to, the writing end is closed
+    /// afterwards when close is set
+    async fn connection_with(data: &[u8], close: bool) -> (tokio::net::TcpStream, tokio::net::TcpStream) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (conn, _) = listener.accept().await.unwrap();
+
+        client.write_all(data).await.unwrap();
+        if close {
+            client.shutdown().await.unwrap();
+        }
+
+        return (conn, client);
+    }
+
+    fn proxied(header: Result<Header, std::io::Error>) -> Option<std::net::SocketAddr> {
+        match header {
+            Ok(Header::Proxied(addr)) => { return Some(addr); },
+            _ => { return None; }
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_v1_and_leaves_the_payload() {
+        let (mut conn, _client) = connection_with(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n", false).await;
+
+        assert_eq!(proxied(read_header(&mut conn).await), Some("192.0.2.1:56324".parse().unwrap()));
+
+        let mut rest = [0_u8; 5];
+        conn.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"GET /");
+    }
+
+    #[tokio::test]
+    async fn reads_v1_ipv6_and_unknown() {
+        let (mut conn, _client) = connection_with(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n", false).await;
+        assert_eq!(proxied(read_header(&mut conn).await), Some("[2001:db8::1]:4000".parse().unwrap()));
+
+        let (mut conn, _client) = connection_with(b"PROXY UNKNOWN\r\n", false).await;
+        assert!(matches!(read_header(&mut conn).await, Ok(Header::Local)));
+    }
+
+    #[tokio::test]
+    async fn rejects_faulty_v1() {
+        let (mut conn, _client) = connection_with(b"PROXY TCP4 192.0.2.1 198.51.100.1 port 443\r\n", false).await;
+        assert!(read_header(&mut conn).await.is_err());
+
+        let (mut conn, _client) = connection_with(b"PROXY TCP4 192.0.2.1\r\n", false).await;
+        assert!(read_header(&mut conn).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_v1() {
+        let mut line = b"PROXY TCP4 ".to_vec();
+        line.extend(std::iter::repeat_n(b'1', 200));
+        line.extend_from_slice(b"\r\n");
+
+        let (mut conn, _client) = connection_with(&line, false).await;
+        assert!(read_header(&mut conn).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_v1() {
+        let (mut conn, _client) = connection_with(b"PROXY TCP4 192.0.2.1 198.51", true).await;
+        assert!(read_header(&mut conn).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_what_create_header_writes() {
+        let source: std::net::SocketAddr = "192.0.2.1:56324".parse().unwrap();
+        let destination: std::net::SocketAddr = "198.51.100.1:443".parse().unwrap();
+        let source_v6: std::net::SocketAddr = "[2001:db8::1]:4000".parse().unwrap();
+        let destination_v6: std::net::SocketAddr = "[2001:db8::2]:443".parse().unwrap();
+
+        for version in [configdb::EdgeProxyProtocol::V1, configdb::EdgeProxyProtocol::V2] {
+            let (mut conn, _client) = connection_with(&create_header(version, source, destination), false).await;
+            assert_eq!(proxied(read_header(&mut conn).await), Some(source));
+
+            let (mut conn, _client) = connection_with(&create_header(version, source_v6, destination_v6), false).await;
+            assert_eq!(proxied(read_header(&mut conn).await), Some(source_v6));
+        }
+
+        assert!(create_header(configdb::EdgeProxyProtocol::None, source, destination).is_empty());
+    }
+
+    #[tokio::test]
+    async fn mapped_addresses_are_sent_as_ipv4() {
+        let source: std::net::SocketAddr = "[::ffff:192.0.2.1]:56324".parse().unwrap();
+        let destination: std::net::SocketAddr = "198.51.100.1:443".parse().unwrap();
+
+        assert_eq!(create_header(configdb::EdgeProxyProtocol::V1, source, destination), b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n".to_vec());
+    }
+
+    #[tokio::test]
+    async fn reads_v2_local_and_skips_tlvs() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20, 0x00, 0, 3, 0xaa, 0xbb, 0xcc]);
+        header.extend_from_slice(b"payload");
+
+        let (mut conn, _client) = connection_with(&header, false).await;
+        assert!(matches!(read_header(&mut conn).await, Ok(Header::Local)));
+
+        let mut rest = [0_u8; 7];
+        conn.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"payload");
+    }
+
+    #[tokio::test]
+    async fn rejects_faulty_v2() {
+        // version 1 in the binary format
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x11, 0x11, 0, 12]);
+        header.extend_from_slice(&[0; 12]);
+        let (mut conn, _client) = connection_with(&header, false).await;
+        assert!(read_header(&mut conn).await.is_err());
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x2f, 0x11, 0, 12]);
+        header.extend_from_slice(&[0; 12]);
+        let (mut conn, _client) = connection_with(&header, false).await;
+        assert!(read_header(&mut conn).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_v2() {
+        // the announced addresses never arrive
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0xff, 0xff]);
+        header.extend_from_slice(&[192, 0, 2, 1]);
+        let (mut conn, _client) = connection_with(&header, true).await;
+        assert!(read_header(&mut conn).await.is_err());
+
+        let (mut conn, _client) = connection_with(&V2_SIGNATURE[..8], true).await;
+        assert!(!matches!(read_header(&mut conn).await, Ok(Header::Proxied(_))));
+    }
+
+    #[tokio::test]
+    async fn plain_traffic_has_no_header() {
+        let (mut conn, _client) = connection_with(b"GET / HTTP/1.1\r\n\r\n", false).await;
+        assert!(matches!(read_header(&mut conn).await, Ok(Header::Missing)));
+
+        let (mut conn, _client) = connection_with(&[0x16, 0x03, 0x01, 0x00, 0x10], false).await;
+        assert!(matches!(read_header(&mut conn).await, Ok(Header::Missing)));
+    }
+}
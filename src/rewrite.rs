@@ -0,0 +1,223 @@
+use crate::configdb;
+use crate::http1;
+use crate::location_rule;
+
+lazy_static::lazy_static! {
+    #[allow(non_upper_case_globals)]
+    static ref PATH_PATTERNS: std::sync::Arc<std::sync::Mutex<std::collections::HashMap<String, regex::Regex>>> = std::sync::Arc::new(std::sync::Mutex::new(std::collections::HashMap::new()));
+}
+
+/// compiles the path pattern of a location rule as it is loaded, the requests only look it up
+pub fn add_path_pattern(path_pattern: &str) -> Result<(), regex::Error> {
+    let pattern = regex::Regex::new(path_pattern)?;
+
+    match PATH_PATTERNS.lock() {
+        Ok(mut path_patterns) => {
+            path_patterns.insert(path_pattern.to_string(), pattern);
+        },
+        Err(err) => {
+            eprintln!("internal error, failed to lock PATH_PATTERNS, error: {}; aborting", err.to_string());
+            std::process::abort();
+        }
+    }
+
+    return Ok(());
+}
+
+fn find_path_pattern(path_pattern: &str) -> Option<regex::Regex> {
+    match PATH_PATTERNS.lock() {
+        Ok(path_patterns) => {
+            return path_patterns.get(path_pattern).cloned();
+        },
+        Err(err) => {
+            eprintln!("internal error, failed to lock PATH_PATTERNS, error: {}; aborting", err.to_string());
+            std::process::abort();
+        }
+    }
+}
+
+pub fn get_request_rewrite(object: &http1::Http) -> Option<configdb::RequestRewrite> {
+    return location_rule::get_location_rule(&object.method, &object.location)?.rewrite;
+}
+
+/// the location the edge server gets, with the path rewritten and the stripped parameters left out of the query
+pub fn rewrite_location(location: &str, rewrite: &configdb::RequestRewrite) -> String {
+    let (path, query) = match location.split_once('?') {
+        Some((path, query)) => { (path, Some(query)) },
+        None => { (location, None) }
+    };
+
+    let mut result = match rewrite.path_pattern.is_empty() {
+        true => { path.to_string() },
+        false => {
+            // an invalid pattern was reported when the rule was loaded
+            match find_path_pattern(&rewrite.path_pattern) {
+                Some(pattern) => { pattern.replace(path, rewrite.path_replacement.as_str()).to_string() },
+                None => { path.to_string() }
+            }
+        }
+    };
+
+    if let Some(query) = query {
+        if !rewrite.strip_query_parameters.iter().any(|name| name == "*") {
+            let query: Vec<&str> = query.split('&').filter(|parameter| {
+                let name = parameter.split('=').next().unwrap_or_default();
+                !rewrite.strip_query_parameters.iter().any(|stripped| stripped == name)
+            }).collect();
+
+            if !query.is_empty() {
+                result.push('?');
+                result.push_str(&query.join("&"));
+            }
+        }
+    }
+
+    return result;
+}
+
+fn is_removed(name: &str, rewrite: &configdb::RequestRewrite) -> bool {
+    return rewrite.remove_headers.iter().any(|removed| removed.eq_ignore_ascii_case(name))
+        || rewrite.replace_headers.iter().any(|replaced| replaced.name.eq_ignore_ascii_case(name));
+}
+
+/// the headers without the removed and replaced ones, the replacing and added ones last; the host keeps its place
+pub fn rewrite_headers(headers: Vec<(String, String)>, rewrite: &configdb::RequestRewrite) -> Vec<(String, String)> {
+    let mut result: Vec<(String, String)> = Vec::new();
+
+    for (name, value) in headers.into_iter() {
+        if name.eq_ignore_ascii_case("host") && !rewrite.host.is_empty() {
+            result.push((name, rewrite.host.clone()));
+        } else if !is_removed(&name, rewrite) {
+            result.push((name, value));
+        }
+    }
+
+    for header in rewrite.replace_headers.iter().chain(rewrite.add_headers.iter()) {
+        result.push((header.name.clone(), header.value.clone()));
+    }
+
+    return result;
+}
+
+/// the same for HTTP/2 and HTTP/3 requests, whose host is the :authority
+pub fn rewrite_header_map(headers: &mut http::HeaderMap, rewrite: &configdb::RequestRewrite) {
+    for name in rewrite.remove_headers.iter().chain(rewrite.replace_headers.iter().map(|header| &header.name)) {
+        if let Ok(name) = http::header::HeaderName::from_bytes(name.as_bytes()) {
+            headers.remove(name);
+        }
+    }
+
+    for header in rewrite.replace_headers.iter().chain(rewrite.add_headers.iter()) {
+        match (http::header::HeaderName::from_bytes(header.name.as_bytes()), http::header::HeaderValue::from_str(&header.value)) {
+            (Ok(name), Ok(value)) => {
+                headers.append(name, value);
+            },
+            _ => {
+                eprintln!("invalid header {}: {} in a location rule, it is not added", header.name, header.value);
+            }
+        }
+    }
+}
+
+/// the request line and headers of an HTTP/1 request head rewritten
+pub fn rewrite_request_head(head: &[u8], rewrite: &configdb::RequestRewrite) -> Vec<u8> {
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.trim_end_matches("\r\n").split("\r\n");
+
+    let request_line = lines.next().unwrap_or_default();
+    let mut result = match request_line.splitn(3, ' ').collect::<Vec<&str>>()[..] {
+        [method, location, version] => { format!("{} {} {}\r\n", method, rewrite_location(location, rewrite), version) },
+        _ => { format!("{}\r\n", request_line) }
+    };
+
+    let headers: Vec<(String, String)> = lines.filter_map(|line| {
+        line.split_once(':').map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+    }).collect();
+
+    for (name, value) in rewrite_headers(headers, rewrite).iter() {
+        result.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    result.push_str("\r\n");
+    return result.into_bytes();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(name: &str, value: &str) -> configdb::HeaderEntry {
+        return configdb::HeaderEntry { name: name.to_string(), value: value.to_string() };
+    }
+
+    fn headers(values: &[(&str, &str)]) -> Vec<(String, String)> {
+        return values.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+    }
+
+    fn path_rewrite(path_pattern: &str, path_replacement: &str) -> configdb::RequestRewrite {
+        add_path_pattern(path_pattern).unwrap();
+        return configdb::RequestRewrite { path_pattern: path_pattern.to_string(), path_replacement: path_replacement.to_string(), ..Default::default() };
+    }
+
+    #[test]
+    fn rewrites_the_path_with_its_captures() {
+        let rewrite = path_rewrite("^/api/v1/(?P<resource>[a-z]+)/([0-9]+)$", "/internal/${resource}/item-$2");
+
+        assert_eq!(rewrite_location("/api/v1/orders/42?expand=1", &rewrite), "/internal/orders/item-42?expand=1");
+        assert_eq!(rewrite_location("/api/v2/orders/42", &rewrite), "/api/v2/orders/42");
+    }
+
+    #[test]
+    fn leaves_the_path_of_an_invalid_pattern_alone() {
+        assert!(add_path_pattern("/api/(").is_err());
+
+        let rewrite = configdb::RequestRewrite { path_pattern: String::from("/api/("), path_replacement: String::from("/x"), ..Default::default() };
+        assert_eq!(rewrite_location("/api/(", &rewrite), "/api/(");
+    }
+
+    #[test]
+    fn strips_the_listed_query_parameters() {
+        let rewrite = configdb::RequestRewrite { strip_query_parameters: vec![String::from("utm_source"), String::from("debug")], ..Default::default() };
+
+        assert_eq!(rewrite_location("/shop?utm_source=mail&page=2&debug", &rewrite), "/shop?page=2");
+        assert_eq!(rewrite_location("/shop?utm_source=mail", &rewrite), "/shop");
+        assert_eq!(rewrite_location("/shop", &rewrite), "/shop");
+    }
+
+    #[test]
+    fn strips_the_whole_query_with_a_wildcard() {
+        let rewrite = configdb::RequestRewrite { strip_query_parameters: vec![String::from("*")], ..path_rewrite("^/old/", "/new/") };
+
+        assert_eq!(rewrite_location("/old/page?a=1&b=2", &rewrite), "/new/page");
+    }
+
+    #[test]
+    fn replaces_the_host_in_place() {
+        let rewrite = configdb::RequestRewrite { host: String::from("backend.internal"), ..Default::default() };
+        let result = rewrite_headers(headers(&[("Accept", "*/*"), ("host", "www.example.com"), ("Cookie", "a=1")]), &rewrite);
+
+        assert_eq!(result, headers(&[("Accept", "*/*"), ("host", "backend.internal"), ("Cookie", "a=1")]));
+    }
+
+    #[test]
+    fn removes_then_replaces_then_adds_headers() {
+        let rewrite = configdb::RequestRewrite {
+            remove_headers: vec![String::from("x-debug")],
+            replace_headers: vec![header("X-Forwarded-Proto", "https")],
+            add_headers: vec![header("X-Request-Source", "waf"), header("X-Debug", "added")],
+            ..Default::default()
+        };
+        let result = rewrite_headers(headers(&[("Host", "www.example.com"), ("X-Debug", "1"), ("x-forwarded-proto", "http"), ("Accept", "*/*")]), &rewrite);
+
+        // an added header survives the removal of its name, the removal only applies to what the client sent
+        assert_eq!(result, headers(&[("Host", "www.example.com"), ("Accept", "*/*"), ("X-Forwarded-Proto", "https"), ("X-Request-Source", "waf"), ("X-Debug", "added")]));
+    }
+
+    #[test]
+    fn rewrites_the_request_line_and_headers_of_a_head() {
+        let rewrite = configdb::RequestRewrite { host: String::from("backend.internal"), ..path_rewrite("^/app", "") };
+        let head = b"GET /app/index.html?x=1 HTTP/1.1\r\nHost: www.example.com\r\nAccept: */*\r\n\r\n";
+
+        assert_eq!(String::from_utf8(rewrite_request_head(head, &rewrite)).unwrap(), "GET /index.html?x=1 HTTP/1.1\r\nHost: backend.internal\r\nAccept: */*\r\n\r\n");
+    }
+}
@@ -3,6 +3,21 @@ use tokio::io::AsyncWriteExt;
 
 use crate::configdb;
 use crate::client;
+use crate::client_ip;
+use crate::cert_store;
+use crate::acme;
+use crate::client_hello;
+use crate::http3;
+use crate::proxy_protocol;
+use crate::https_redirect;
+
+/// a client that stalls its handshake must not hold a connection slot forever
+const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+lazy_static::lazy_static! {
+    #[allow(non_upper_case_globals)]
+    static ref SSL_ACCEPTORS: std::sync::Arc<std::sync::Mutex<std::collections::HashMap<String, openssl::ssl::SslAcceptor>>> = std::sync::Arc::new(std::sync::Mutex::new(std::collections::HashMap::new()));
+}
 
 pub enum TcpClient {
     Http(tokio::net::TcpStream),
@@ -10,6 +25,17 @@ pub enum TcpClient {
 }
 
 impl TcpClient {
+    pub fn local_addr(&self) -> Result<std::net::SocketAddr, std::io::Error> {
+        match self {
+            TcpClient::Http(http) => {
+                return http.local_addr();
+            },
+            TcpClient::Https(https) => {
+                return https.get_ref().local_addr();
+            }
+        }
+    }
+
     pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
         match self {
             TcpClient::Http(http) => {
@@ -59,10 +85,238 @@ impl TcpClient {
             }
         };
     }
+
+    pub async fn write_all(&mut self, buf: &[u8]) -> Result<(), std::io::Error> {
+        match self {
+            TcpClient::Http(http) => {
+                return http.write_all(buf).await;
+            }
+            TcpClient::Https(https) => {
+                return https.write_all(buf).await;
+            }
+        };
+    }
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct ClientIdentity {
+    pub subject: String,
+    pub sans: Vec<String>,
+    pub fingerprint: String,
+}
+
+/// the identity of the verified certificate presented by the client, if any
+fn get_client_identity(ssl: &openssl::ssl::SslRef) -> Option<ClientIdentity> {
+    let certificate = ssl.peer_certificate()?;
+
+    let subject: Vec<String> = certificate.subject_name().entries().map(|entry| {
+        let name = entry.object().nid().short_name().unwrap_or("UNDEF");
+        let value = match entry.data().as_utf8() {
+            Ok(value) => { value.to_string() },
+            Err(_) => { String::new() }
+        };
+
+        format!("{}={}", name, value)
+    }).collect();
+
+    let mut sans: Vec<String> = Vec::new();
+    if let Some(names) = certificate.subject_alt_names() {
+        for name in names.iter() {
+            if let Some(dnsname) = name.dnsname() {
+                sans.push(dnsname.to_string());
+            } else if let Some(email) = name.email() {
+                sans.push(email.to_string());
+            } else if let Some(uri) = name.uri() {
+                sans.push(uri.to_string());
+            } else if let Some(ipaddress) = name.ipaddress() {
+                match ipaddress.len() {
+                    4 => { sans.push(std::net::Ipv4Addr::new(ipaddress[0], ipaddress[1], ipaddress[2], ipaddress[3]).to_string()); },
+                    16 => {
+                        let mut octets = [0 as u8; 16];
+                        octets.copy_from_slice(ipaddress);
+                        sans.push(std::net::Ipv6Addr::from(octets).to_string());
+                    },
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    let fingerprint = match certificate.digest(openssl::hash::MessageDigest::sha256()) {
+        Ok(digest) => {
+            digest.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()
+        },
+        Err(err) => {
+            eprintln!("SSL error: {}", err.to_string());
+            return None;
+        }
+    };
+
+    return Some(ClientIdentity { subject: subject.join(", "), sans, fingerprint });
+}
+
+fn set_client_verification(ssl_accepter: &mut openssl::ssl::SslAcceptorBuilder, general_config: &configdb::General) -> Result<(), openssl::error::ErrorStack> {
+    let verify_mode = match general_config.client_certificate {
+        configdb::ClientCertificate::None => {
+            return Ok(());
+        },
+        configdb::ClientCertificate::Optional => {
+            openssl::ssl::SslVerifyMode::PEER
+        },
+        configdb::ClientCertificate::Required => {
+            openssl::ssl::SslVerifyMode::PEER | openssl::ssl::SslVerifyMode::FAIL_IF_NO_PEER_CERT
+        }
+    };
+
+    let mut cert_store = openssl::x509::store::X509StoreBuilder::new()?;
+    let lookup = cert_store.add_lookup(openssl::x509::store::X509Lookup::file())?;
+    lookup.load_cert_file(&general_config.client_ca_bundle, openssl::ssl::SslFiletype::PEM)?;
+
+    if !general_config.client_crl.is_empty() {
+        lookup.load_crl_file(&general_config.client_crl, openssl::ssl::SslFiletype::PEM)?;
+        cert_store.set_flags(openssl::x509::verify::X509VerifyFlags::CRL_CHECK | openssl::x509::verify::X509VerifyFlags::CRL_CHECK_ALL)?;
+    }
+
+    ssl_accepter.set_verify_cert_store(cert_store.build())?;
+    ssl_accepter.set_client_ca_list(openssl::x509::X509Name::load_client_ca_file(&general_config.client_ca_bundle)?);
+    ssl_accepter.set_verify(verify_mode);
+
+    return Ok(());
 }
 
-fn create_ssl_server(ssl_cert: &str, ssl_key: &str) -> Result<openssl::ssl::SslAcceptor, std::io::Error> {
-    match openssl::ssl::SslAcceptor::mozilla_intermediate(openssl::ssl::SslMethod::tls_server()) {
+fn parse_tls_version(version: &str) -> Result<Option<openssl::ssl::SslVersion>, std::io::Error> {
+    match version {
+        "" => { return Ok(None); },
+        "TLSv1" => { return Ok(Some(openssl::ssl::SslVersion::TLS1)); },
+        "TLSv1.1" => { return Ok(Some(openssl::ssl::SslVersion::TLS1_1)); },
+        "TLSv1.2" => { return Ok(Some(openssl::ssl::SslVersion::TLS1_2)); },
+        "TLSv1.3" => { return Ok(Some(openssl::ssl::SslVersion::TLS1_3)); },
+        _ => {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("unsupported TLS version '{}'", version)));
+        }
+    }
+}
+
+/// picks the first protocol of the configured list the client offers, the server preference wins
+pub fn select_alpn_protocol<'a>(protocols: &[String], client_protocols: &'a [u8]) -> Option<&'a [u8]> {
+    for protocol in protocols.iter() {
+        let mut idx: usize = 0;
+
+        while idx < client_protocols.len() {
+            let len = client_protocols[idx] as usize;
+
+            if idx + 1 + len > client_protocols.len() {
+                break;
+            }
+
+            if &client_protocols[idx + 1..idx + 1 + len] == protocol.as_bytes() {
+                return Some(&client_protocols[idx + 1..idx + 1 + len]);
+            }
+
+            idx = idx + 1 + len;
+        }
+    }
+
+    return None;
+}
+
+fn set_tls_protocols(ssl_accepter: &mut openssl::ssl::SslAcceptorBuilder, general_config: &configdb::General, min_version: Option<openssl::ssl::SslVersion>, max_version: Option<openssl::ssl::SslVersion>) -> Result<(), openssl::error::ErrorStack> {
+    if min_version.is_some() {
+        ssl_accepter.set_min_proto_version(min_version)?;
+    }
+
+    if max_version.is_some() {
+        ssl_accepter.set_max_proto_version(max_version)?;
+    }
+
+    if !general_config.tls_ciphers.is_empty() {
+        ssl_accepter.set_cipher_list(&general_config.tls_ciphers)?;
+    }
+
+    if !general_config.tls_ciphersuites.is_empty() {
+        ssl_accepter.set_ciphersuites(&general_config.tls_ciphersuites)?;
+    }
+
+    if !general_config.tls_groups.is_empty() {
+        ssl_accepter.set_groups_list(&general_config.tls_groups)?;
+    }
+
+    return Ok(());
+}
+
+fn create_tls_policy_builder(general_config: &configdb::General) -> Result<openssl::ssl::SslAcceptorBuilder, std::io::Error> {
+    let ssl_accepter = match general_config.tls_preset {
+        configdb::TlsPreset::Modern => {
+            openssl::ssl::SslAcceptor::mozilla_modern_v5(openssl::ssl::SslMethod::tls_server())
+        },
+        configdb::TlsPreset::Intermediate => {
+            openssl::ssl::SslAcceptor::mozilla_intermediate_v5(openssl::ssl::SslMethod::tls_server())
+        },
+        configdb::TlsPreset::Legacy => {
+            // the pre-2020 intermediate profile, reopened down to TLS 1.0 for clients that cannot be upgraded
+            match openssl::ssl::SslAcceptor::mozilla_intermediate(openssl::ssl::SslMethod::tls_server()) {
+                Ok(mut ssl_accepter) => {
+                    ssl_accepter.set_security_level(0);
+
+                    match ssl_accepter.set_min_proto_version(Some(openssl::ssl::SslVersion::TLS1)) {
+                        Ok(_) => { Ok(ssl_accepter) },
+                        Err(err) => { Err(err) }
+                    }
+                },
+                Err(err) => { Err(err) }
+            }
+        }
+    };
+
+    let mut ssl_accepter = match ssl_accepter {
+        Ok(ssl_accepter) => { ssl_accepter },
+        Err(err) => {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+        }
+    };
+
+    let min_version = match parse_tls_version(&general_config.tls_min_version) {
+        Ok(min_version) => { min_version },
+        Err(err) => {
+            return Err(err);
+        }
+    };
+
+    let max_version = match parse_tls_version(&general_config.tls_max_version) {
+        Ok(max_version) => { max_version },
+        Err(err) => {
+            return Err(err);
+        }
+    };
+
+    if let Err(err) = set_tls_protocols(&mut ssl_accepter, general_config, min_version, max_version) {
+        return Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+    }
+
+    if general_config.tls_disable_session_tickets {
+        ssl_accepter.set_options(openssl::ssl::SslOptions::NO_TICKET);
+    }
+
+    if !general_config.alpn_protocols.is_empty() {
+        let alpn_protocols = general_config.alpn_protocols.clone();
+
+        ssl_accepter.set_alpn_select_callback(move |_, client_protocols| {
+            match select_alpn_protocol(&alpn_protocols, client_protocols) {
+                Some(protocol) => {
+                    return Ok(protocol);
+                },
+                None => {
+                    return Err(openssl::ssl::AlpnError::NOACK);
+                }
+            }
+        });
+    }
+
+    return Ok(ssl_accepter);
+}
+
+fn create_ssl_builder(general_config: &configdb::General, ssl_cert: &str, ssl_key: &str) -> Result<openssl::ssl::SslAcceptorBuilder, std::io::Error> {
+    match create_tls_policy_builder(general_config) {
         Ok(mut ssl_accepter) => {
             if let Err(err) = ssl_accepter.set_private_key_file(ssl_key, openssl::ssl::SslFiletype::PEM) {
                 eprintln!("SSL error: {}", err.to_string());
@@ -79,25 +333,217 @@ fn create_ssl_server(ssl_cert: &str, ssl_key: &str) -> Result<openssl::ssl::SslA
                 return Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));    
             }
 
-            return Ok(ssl_accepter.build());
+            if let Err(err) = set_client_verification(&mut ssl_accepter, general_config) {
+                eprintln!("SSL error: {}", err.to_string());
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+            }
+
+            return Ok(ssl_accepter);
         },
         Err(err) => {
             eprintln!("SSL error: {}", err.to_string());
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()));
+            return Err(err);
         }
     }
 }
 
-async fn create_http_server(address: String) -> Result<tokio::net::TcpListener, std::io::Error> {
-    match tokio::net::TcpListener::bind(&address).await {
-        Ok(listener) => {
-            return Ok(listener);
+/// builds the context of a single certificate of the certificate store, the SNI callback switches the handshake to it
+pub fn create_ssl_context(general_config: &configdb::General, ssl_cert: &str, ssl_key: &str) -> Result<openssl::ssl::SslContext, std::io::Error> {
+    match create_ssl_builder(general_config, ssl_cert, ssl_key) {
+        Ok(ssl_accepter) => {
+            return Ok(ssl_accepter.build().into_context());
         },
         Err(err) => {
-            eprintln!("failed to bind the address {}, error: {}", address, err.to_string());
             return Err(err);
         }
-    };
+    }
+}
+
+fn create_ssl_server(listener_config: &configdb::Listener, general_config: &configdb::General) -> Result<openssl::ssl::SslAcceptor, std::io::Error> {
+    match create_ssl_builder(general_config, &general_config.ssl_certificate, &general_config.ssl_certificate_key) {
+        Ok(mut ssl_accepter) => {
+            // the certificate given by the general config is the fallback when no entry of the certificate store matches
+            let listener_config = listener_config.clone();
+            ssl_accepter.set_servername_callback(move |ssl, _alert| {
+                if let Some(servername) = ssl.servername(openssl::ssl::NameType::HOST_NAME) {
+                    if let Some(ssl_context) = cert_store::find_certificate(&listener_config, servername) {
+                        if let Err(err) = ssl.set_ssl_context(&ssl_context) {
+                            eprintln!("SSL error: {}", err.to_string());
+                            return Err(openssl::ssl::SniError::ALERT_FATAL);
+                        }
+                    }
+                }
+
+                return Ok(());
+            });
+
+            return Ok(ssl_accepter.build());
+        },
+        Err(err) => {
+            return Err(err);
+        }
+    }
+}
+
+/// "address:port", the IPv6 address in brackets
+pub fn format_address(address: &str, port: u16) -> String {
+    match address.contains(':') && !address.starts_with('[') {
+        true => { return format!("[{}]:{}", address, port); },
+        false => { return format!("{}:{}", address, port); }
+    }
+}
+
+pub fn listener_address(listener_config: &configdb::Listener) -> String {
+    return format_address(&listener_config.listen_address, listener_config.listen_port);
+}
+
+pub fn is_https_proxy(listener_config: &configdb::Listener) -> bool {
+    return listener_config.https && matches!(listener_config.mode, configdb::ListenerMode::Proxy);
+}
+
+/// the general config as seen by the clients of a listener, its own settings replace the general ones
+pub fn create_listener_config(general_config: &configdb::General, listener_config: &configdb::Listener) -> configdb::General {
+    let mut result = general_config.clone();
+
+    if listener_config.maximum_connections > 0 {
+        result.maximum_connections = listener_config.maximum_connections;
+    }
+
+    if let Some(ingress) = &listener_config.ingress {
+        result.ingress = ingress.clone();
+    }
+
+    if let Some(tls) = &listener_config.tls {
+        let overrides = [
+            (&tls.ssl_certificate, &mut result.ssl_certificate),
+            (&tls.ssl_certificate_key, &mut result.ssl_certificate_key),
+            (&tls.tls_min_version, &mut result.tls_min_version),
+            (&tls.tls_max_version, &mut result.tls_max_version),
+            (&tls.tls_ciphers, &mut result.tls_ciphers),
+            (&tls.tls_ciphersuites, &mut result.tls_ciphersuites),
+            (&tls.tls_groups, &mut result.tls_groups),
+            (&tls.client_ca_bundle, &mut result.client_ca_bundle),
+            (&tls.client_crl, &mut result.client_crl),
+        ];
+
+        for (value, general_value) in overrides {
+            if !value.is_empty() {
+                *general_value = value.clone();
+            }
+        }
+
+        if let Some(tls_preset) = &tls.tls_preset {
+            result.tls_preset = tls_preset.clone();
+        }
+
+        if let Some(tls_disable_session_tickets) = tls.tls_disable_session_tickets {
+            result.tls_disable_session_tickets = tls_disable_session_tickets;
+        }
+
+        if let Some(tls_session_ticket_rotation) = tls.tls_session_ticket_rotation {
+            result.tls_session_ticket_rotation = tls_session_ticket_rotation;
+        }
+
+        if let Some(client_certificate) = &tls.client_certificate {
+            result.client_certificate = client_certificate.clone();
+        }
+
+        if let Some(alpn_protocols) = &tls.alpn_protocols {
+            result.alpn_protocols = alpn_protocols.clone();
+        }
+    }
+
+    return result;
+}
+
+/// rebuilds the SSL layer of every HTTPS listener from the files referenced by the config and swaps them in, new
+/// handshakes use the new acceptors while the sessions already established keep the one they were accepted with
+pub fn reload_ssl_server(general_config: &configdb::General) -> Result<(), std::io::Error> {
+    let mut ssl_accepters: std::collections::HashMap<String, openssl::ssl::SslAcceptor> = std::collections::HashMap::new();
+
+    for listener_config in general_config.listeners.iter().filter(|listener_config| is_https_proxy(listener_config)) {
+        match create_ssl_server(listener_config, &create_listener_config(general_config, listener_config)) {
+            Ok(ssl_accepter) => {
+                ssl_accepters.insert(listener_address(listener_config), ssl_accepter);
+            },
+            Err(err) => {
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("listener {}, {}", listener_address(listener_config), err.to_string())));
+            }
+        }
+    }
+
+    match SSL_ACCEPTORS.lock() {
+        Ok(mut locked_value) => {
+            *locked_value = ssl_accepters;
+        },
+        Err(err) => {
+            eprintln!("internal error, failed to lock SSL_ACCEPTORS, error: {}; aborting", err.to_string());
+            std::process::abort();
+        }
+    }
+
+    return Ok(());
+}
+
+/// rebuilds the SSL layer of a single listener, its session ticket keys are generated anew
+fn reload_listener_ssl_server(listener_config: &configdb::Listener, general_config: &configdb::General) -> Result<(), std::io::Error> {
+    let ssl_accepter = create_ssl_server(listener_config, general_config)?;
+
+    match SSL_ACCEPTORS.lock() {
+        Ok(mut locked_value) => {
+            locked_value.insert(listener_address(listener_config), ssl_accepter);
+        },
+        Err(err) => {
+            eprintln!("internal error, failed to lock SSL_ACCEPTORS, error: {}; aborting", err.to_string());
+            std::process::abort();
+        }
+    }
+
+    return Ok(());
+}
+
+fn get_ssl_server(listener_config: &configdb::Listener) -> Option<openssl::ssl::SslAcceptor> {
+    match SSL_ACCEPTORS.lock() {
+        Ok(locked_value) => {
+            return locked_value.get(&listener_address(listener_config)).cloned();
+        },
+        Err(err) => {
+            eprintln!("internal error, failed to lock SSL_ACCEPTORS, error: {}; aborting", err.to_string());
+            std::process::abort();
+        }
+    }
+}
+
+/// the socket address of a listener, resolved when its address is a name
+pub fn resolve_address(address: &str) -> Result<std::net::SocketAddr, std::io::Error> {
+    match std::net::ToSocketAddrs::to_socket_addrs(address)?.next() {
+        Some(socket_address) => { return Ok(socket_address); },
+        None => { return Err(std::io::Error::new(std::io::ErrorKind::Other, format!("failed to resolve the address {}", address))); }
+    }
+}
+
+/// binds through socket2, an IPv6 listener accepts IPv4 clients too unless it is IPv6 only
+pub fn create_socket(listener_config: &configdb::Listener, port: u16, socket_type: socket2::Type) -> Result<socket2::Socket, std::io::Error> {
+    let socket_address = resolve_address(&format_address(&listener_config.listen_address, port))?;
+    let socket = socket2::Socket::new(socket2::Domain::for_address(socket_address), socket_type, None)?;
+
+    if socket_address.is_ipv6() {
+        socket.set_only_v6(listener_config.ipv6_only)?;
+    }
+
+    if socket_type == socket2::Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+
+    socket.set_nonblocking(true)?;
+    socket.bind(&socket_address.into())?;
+    return Ok(socket);
+}
+
+fn create_http_server(listener_config: &configdb::Listener) -> Result<tokio::net::TcpListener, std::io::Error> {
+    let socket = create_socket(listener_config, listener_config.listen_port, socket2::Type::STREAM)?;
+    socket.listen(1024)?;
+    return tokio::net::TcpListener::from_std(socket.into());
 }
 
 pub async fn start() {
@@ -119,19 +565,159 @@ pub async fn start() {
         }
     };
 
-    let listener = match create_http_server(format!("{}:{}", general_config.listen_address, general_config.listen_port)).await {
-        Ok(listener) => { listener },
+    if general_config.listeners.is_empty() {
+        eprintln!("no listener is configured in {}, nothing to serve", configdb::GENERAL_CONFIG_FILENAME);
+        return;
+    }
+
+    let mut listeners: Vec<(tokio::net::TcpListener, configdb::Listener, configdb::General)> = Vec::new();
+    for listener_config in general_config.listeners.iter() {
+        match create_http_server(listener_config) {
+            Ok(listener) => {
+                listeners.push((listener, listener_config.clone(), create_listener_config(&general_config, listener_config)));
+            },
+            Err(err) => {
+                eprintln!("failed to bind the address {}, error: {}", listener_address(listener_config), err.to_string());
+                return;
+            }
+        }
+    }
+
+    acme::initialize();
+
+    if general_config.listeners.iter().any(is_https_proxy) {
+        cert_store::initialize(&general_config);
+
+        if let Err(err) = reload_ssl_server(&general_config) {
+            eprintln!("failed to create a SSL layer, error: {}", err.to_string());
+            return;
+        }
+
+        let watch_general_config = general_config.clone();
+        std::thread::spawn(move || {
+            cert_store::folder_watch(watch_general_config);
+        });
+
+        // every SSL layer generates its own session ticket keys, rebuilding it periodically rotates them
+        for (_, listener_config, listener_general_config) in listeners.iter().filter(|(_, listener_config, _)| is_https_proxy(listener_config)) {
+            if listener_general_config.tls_session_ticket_rotation > 0 {
+                let rotation_listener_config = listener_config.clone();
+                let rotation_general_config = listener_general_config.clone();
+
+                tokio::spawn(async move {
+                    loop {
+                        tokio::time::sleep(std::time::Duration::from_secs(rotation_general_config.tls_session_ticket_rotation)).await;
+
+                        if let Err(err) = reload_listener_ssl_server(&rotation_listener_config, &rotation_general_config) {
+                            eprintln!("failed to rotate the session ticket keys of {}, error: {}", listener_address(&rotation_listener_config), err.to_string());
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    let mut servers: Vec<tokio::task::JoinHandle<()>> = Vec::new();
+    for (listener, listener_config, listener_general_config) in listeners.into_iter() {
+        match listener_config.mode {
+            configdb::ListenerMode::Proxy => {
+                if listener_general_config.http3 && listener_config.https {
+                    tokio::spawn(http3::start(listener_config.clone(), listener_general_config.clone()));
+                }
+
+                servers.push(tokio::spawn(serve(listener, listener_config, listener_general_config)));
+            },
+            configdb::ListenerMode::RedirectToHttps => {
+                servers.push(tokio::spawn(https_redirect::serve(listener, listener_config, listener_general_config)));
+            }
+        }
+    }
+
+    for server in servers.into_iter() {
+        let _ = server.await;
+    }
+}
+
+/// reads the PROXY protocol header and completes the TLS handshake of an HTTPS listener before handing the client
+/// over, in the task of the connection so a slow client does not hold up the listener
+async fn accept_client(mut conn: tokio::net::TcpStream, connaddr: std::net::SocketAddr, listener_config: configdb::Listener, general_config: configdb::General) {
+    // the load balancer's header comes before the TLS handshake
+    let connaddr = match proxy_protocol::accept(&mut conn, client_ip::to_canonical(connaddr), &general_config).await {
+        Some(connaddr) => { connaddr },
+        None => { return; }
+    };
+
+    if !listener_config.https {
+        client::handler(TcpClient::Http(conn), connaddr, None, None, listener_config, general_config).await;
+        return;
+    }
+
+    // fetched per connection so a reloaded certificate is used from the next handshake on
+    let listener_ssl = match get_ssl_server(&listener_config) {
+        Some(listener_ssl) => {
+            listener_ssl
+        },
+        None => {
+            eprintln!("failed to create a SSL layer, error: no SSL acceptor loaded for {}; dropping the connection", listener_address(&listener_config));
+            return;
+        }
+    };
+
+    // TLS-ALPN-01 validation requests are answered with the challenge certificate of the ACME client
+    let mut ssl_context = listener_ssl.context().to_owned();
+    let mut tls_fingerprint: Option<client_hello::TlsFingerprint> = None;
+    if let Some(client_hello) = client_hello::peek(&conn).await {
+        tls_fingerprint = Some(client_hello.fingerprint());
+
+        if client_hello.offers_alpn_protocol(acme::ACME_TLS_ALPN_PROTOCOL) {
+            if let Some(challenge_context) = client_hello.servername.and_then(acme::get_tls_alpn01_challenge) {
+                ssl_context = challenge_context;
+            }
+        }
+    }
+
+    let mut ssl_stream = match openssl::ssl::Ssl::new(&ssl_context) {
+        Ok(ssl) => {
+            match tokio_openssl::SslStream::new(ssl, conn) {
+                Ok(ssl_stream) => { ssl_stream },
+                Err(err) => {
+                    eprintln!("SSL error: {}", err.to_string());
+                    return;
+                }
+            }
+        },
         Err(err) => {
-            eprintln!("failed to bind the address {}:{}, error: {}", general_config.listen_address, general_config.listen_port, err.to_string());
+            eprintln!("SSL error: {}", err.to_string());
             return;
         }
     };
 
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, tokio_openssl::SslStream::accept(std::pin::Pin::new(&mut ssl_stream))).await {
+        Ok(Ok(_)) => {
+            // the validation of the ACME server ends with the handshake
+            if ssl_stream.ssl().selected_alpn_protocol() == Some(acme::ACME_TLS_ALPN_PROTOCOL) {
+                return;
+            }
+
+            let client_identity = get_client_identity(ssl_stream.ssl());
+            client::handler(TcpClient::Https(ssl_stream), connaddr, client_identity, tls_fingerprint, listener_config, general_config).await;
+        },
+        Ok(Err(err)) => {
+            eprintln!("SSL error: {}", err.to_string());
+        },
+        Err(_) => {
+            println!("client {} did not complete the TLS handshake in time, closing the connection", connaddr);
+        }
+    }
+}
+
+/// accepts the clients of a listener, through TLS when it is an HTTPS one
+async fn serve(listener: tokio::net::TcpListener, listener_config: configdb::Listener, general_config: configdb::General) {
     let conn_list: std::sync::Arc<std::sync::Mutex<(usize, Vec<tokio::task::JoinHandle<()>>)>> = std::sync::Arc::new(std::sync::Mutex::new((0, Vec::new())));
     let conn_list_cleaner_param = std::sync::Arc::clone(&conn_list);
     let conn_list_cleaner = tokio::spawn(async move {
         loop {
-            std::thread::sleep(std::time::Duration::from_millis(500));
+            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
 
             match conn_list_cleaner_param.lock() {
                 Ok(mut locked_value) => {
@@ -157,94 +743,55 @@ pub async fn start() {
     });
 
     loop {
-        let listener_ssl: Option<openssl::ssl::SslAcceptor> = match general_config.https {
-            true => {
-                match create_ssl_server(&general_config.ssl_certificate, &general_config.ssl_certificate_key) {
-                    Ok(ssl_accepter) => {
-                        Some(ssl_accepter)
+        match listener.accept().await {
+            Ok((conn, connaddr)) => {
+                match std::sync::Arc::clone(&conn_list).lock() {
+                    Ok(mut locked_value) => {
+                        if locked_value.0 >= general_config.maximum_connections { // check the number of connections if it reaches the limit
+                            println!("refusing to accept {} due limit of number of connections reached", connaddr.to_string());
+                            continue;
+                        }
+
+                        locked_value.0 = locked_value.0 + 1; // increment the number of connections
+                        locked_value.1.push(tokio::spawn(accept_client(conn, connaddr, listener_config.clone(), general_config.clone())));
                     },
                     Err(err) => {
-                        eprintln!("failed to create a SSL layer, error: {}", err.to_string());
-                        return;
+                        eprintln!("internal error, failed to lock the variable 'conn_list', error: {}; aborting!", err.to_string());
+                        std::process::abort();
                     }
                 }
             },
-            false => {
-                None
+            Err(err) => {
+                eprintln!("failed to accept a client, error: {}", err.to_string());
+                break;
             }
-        };
+        }
+    }
 
-        let conn_tuple: Option<(TcpClient, std::net::SocketAddr)> = match listener_ssl {
-            Some(listener_ssl) => {
-                match listener.accept().await {
-                    Ok(conn) => {
-                        match openssl::ssl::Ssl::new(listener_ssl.clone().context()) {
-                            Ok(ssl) => {
-                                match tokio_openssl::SslStream::new(ssl, conn.0) {
-                                    Ok(mut ssl_stream) => {
-                                        match tokio_openssl::SslStream::accept(std::pin::Pin::new(&mut ssl_stream)).await {
-                                            Ok(_) => {
-                                                Some((TcpClient::Https(ssl_stream), conn.1))
-                                            },
-                                            Err(err) => {
-                                                eprintln!("SSL error: {}", err.to_string());
-                                                None
-                                            }        
-                                        }
-                                    },
-                                    Err(err) => {
-                                        eprintln!("SSL error: {}", err.to_string());
-                                        break;
-                                    }
-                                }
-                            },
-                            Err(err) => {
-                                eprintln!("SSL error: {}", err.to_string());
-                                break;
-                            }
-                        }
-                    },
-                    Err(err) => {
-                        eprintln!("failed to accept a client, error: {}", err.to_string());
-                        break;
-                    }
-                }  
-            },
-            None => {
-                match listener.accept().await {
-                    Ok(conn) => {
-                        Some((TcpClient::Http(conn.0), conn.1))
-                    },
-                    Err(err) => {
-                        eprintln!("failed to accept a client, error: {}", err.to_string());
-                        break;
-                    }
-                }    
-            }
-        };
+    conn_list_cleaner.abort();
+}
 
-        if let Some(conn_tuple) = conn_tuple {
-            let conn = conn_tuple.0;
-            let connaddr = conn_tuple.1;
-            let general_config = general_config.clone();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            match std::sync::Arc::clone(&conn_list).lock() {
-                Ok(mut locked_value) => {
-                    if locked_value.0 >= general_config.maximum_connections { // check the number of connections if it reaches the limit
-                        println!("refusing to accept {} due limit of number of connections reached", connaddr.to_string());
-                        continue;
-                    }
+    #[test]
+    fn listener_tls_settings_override_the_general_ones() {
+        let general_config = configdb::General { tls_groups: String::from("X25519:P-256"), tls_disable_session_tickets: true, tls_session_ticket_rotation: 3600, ..Default::default() };
 
-                    locked_value.0 = locked_value.0 + 1; // increment the number of connections
-                    locked_value.1.push(tokio::spawn(async move { client::handler(conn, connaddr, general_config).await }));
-                },
-                Err(err) => {
-                    eprintln!("internal error, failed to lock the variable 'conn_list', error: {}; aborting!", err.to_string());
-                    std::process::abort();
-                }
-            }
-        }
-    }
+        let listener_config = configdb::Listener {
+            tls: Some(configdb::ListenerTls { tls_groups: String::from("P-384"), tls_disable_session_tickets: Some(false), tls_session_ticket_rotation: Some(600), ..Default::default() }),
+            ..Default::default()
+        };
+        let listener_general_config = create_listener_config(&general_config, &listener_config);
+        assert_eq!(listener_general_config.tls_groups, "P-384");
+        assert!(!listener_general_config.tls_disable_session_tickets);
+        assert_eq!(listener_general_config.tls_session_ticket_rotation, 600);
 
-    conn_list_cleaner.abort();
+        let listener_config = configdb::Listener { tls: Some(configdb::ListenerTls::default()), ..Default::default() };
+        let listener_general_config = create_listener_config(&general_config, &listener_config);
+        assert_eq!(listener_general_config.tls_groups, "X25519:P-256");
+        assert!(listener_general_config.tls_disable_session_tickets);
+        assert_eq!(listener_general_config.tls_session_ticket_rotation, 3600);
+    }
 }
@@ -0,0 +1,620 @@
+use crate::configdb;
+use crate::detector;
+use crate::http1;
+use crate::http2;
+use crate::location_rule;
+use crate::server;
+
+const WEBSOCKET_DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+const WEBSOCKET_DEFAULT_MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+const CONTROL_FRAME_MAX_PAYLOAD: usize = 125;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_INVALID_PAYLOAD: u16 = 1007;
+const CLOSE_POLICY_VIOLATION: u16 = 1008;
+const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+
+/// the limits and detectors applied to the messages of an upgraded connection, taken from the location rule of
+/// the handshake and the general configuration
+pub struct WebSocketPolicy {
+    max_frame_size: usize,
+    max_message_size: usize,
+    messages_per_second: usize,
+    detectors: Vec<String>,
+    denied_patterns: Vec<String>,
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+    raw: Vec<u8>,
+}
+
+/// a client frame breaking the protocol or the policy, the connection is closed with the code
+struct Violation {
+    code: u16,
+    reason: String,
+}
+
+impl Violation {
+    fn new<Reason: AsRef<str>>(code: u16, reason: Reason) -> Violation {
+        return Violation { code, reason: reason.as_ref().to_string() };
+    }
+}
+
+struct FrameParser {
+    storage: Vec<u8>,
+    max_frame_size: usize,
+}
+
+impl FrameParser {
+    /// the next complete frame received from the client, client frames are always masked
+    fn next_frame(&mut self) -> Result<Option<Frame>, Violation> {
+        if self.storage.len() < 2 {
+            return Ok(None);
+        }
+
+        let fin = self.storage[0] & 0x80 != 0;
+        let opcode = self.storage[0] & 0x0f;
+
+        // no extension is negotiated, the reserved bits stay clear
+        if self.storage[0] & 0x70 != 0 {
+            return Err(Violation::new(CLOSE_PROTOCOL_ERROR, "reserved bits set"));
+        }
+
+        if self.storage[1] & 0x80 == 0 {
+            return Err(Violation::new(CLOSE_PROTOCOL_ERROR, "unmasked client frame"));
+        }
+
+        let (length, mut idx): (u64, usize) = match self.storage[1] & 0x7f {
+            126 => {
+                if self.storage.len() < 4 {
+                    return Ok(None);
+                }
+
+                (u16::from_be_bytes([self.storage[2], self.storage[3]]) as u64, 4)
+            },
+            127 => {
+                if self.storage.len() < 10 {
+                    return Ok(None);
+                }
+
+                let mut length = [0_u8; 8];
+                length.copy_from_slice(&self.storage[2..10]);
+                (u64::from_be_bytes(length), 10)
+            },
+            length => { (length as u64, 2) }
+        };
+
+        if length > self.max_frame_size as u64 {
+            return Err(Violation::new(CLOSE_MESSAGE_TOO_BIG, format!("frame of {} bytes exceeds the limit of {} bytes", length, self.max_frame_size)));
+        }
+
+        let length = length as usize;
+        if self.storage.len() < idx + 4 + length {
+            return Ok(None);
+        }
+
+        let mut mask = [0_u8; 4];
+        mask.copy_from_slice(&self.storage[idx..idx + 4]);
+        idx += 4;
+
+        let payload: Vec<u8> = self.storage[idx..idx + length].iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]).collect();
+        let raw: Vec<u8> = self.storage.drain(..idx + length).collect();
+
+        return Ok(Some(Frame { fin, opcode, payload, raw }));
+    }
+}
+
+struct MessageInspector {
+    policy: WebSocketPolicy,
+    opcode: Option<u8>,
+    message_size: usize,
+    text: Vec<u8>,
+    held_frames: Vec<u8>,
+    window_start: std::time::Instant,
+    window_messages: usize,
+}
+
+impl MessageInspector {
+    fn count_message(&mut self) -> Result<(), Violation> {
+        if self.policy.messages_per_second == 0 {
+            return Ok(());
+        }
+
+        if self.window_start.elapsed() >= std::time::Duration::from_secs(1) {
+            self.window_start = std::time::Instant::now();
+            self.window_messages = 0;
+        }
+
+        self.window_messages += 1;
+
+        if self.window_messages > self.policy.messages_per_second {
+            return Err(Violation::new(CLOSE_POLICY_VIOLATION, format!("more than {} messages per second", self.policy.messages_per_second)));
+        }
+
+        return Ok(());
+    }
+
+    /// checks a client frame and returns the bytes to pass to the edge server; the frames of a text message are
+    /// held back until the whole message passed the detectors
+    fn inspect(&mut self, frame: Frame) -> Result<Vec<u8>, Violation> {
+        if frame.opcode >= OPCODE_CLOSE {
+            // 0xb to 0xf are reserved for control frames not defined yet
+            if frame.opcode != OPCODE_CLOSE && frame.opcode != OPCODE_PING && frame.opcode != OPCODE_PONG {
+                return Err(Violation::new(CLOSE_PROTOCOL_ERROR, format!("unexpected frame with opcode {}", frame.opcode)));
+            }
+
+            if !frame.fin || frame.payload.len() > CONTROL_FRAME_MAX_PAYLOAD {
+                return Err(Violation::new(CLOSE_PROTOCOL_ERROR, "malformed control frame"));
+            }
+
+            return Ok(frame.raw);
+        }
+
+        match (frame.opcode, self.opcode) {
+            (OPCODE_CONTINUATION, Some(_)) => { },
+            (OPCODE_TEXT, None) | (OPCODE_BINARY, None) => {
+                self.count_message()?;
+                self.opcode = Some(frame.opcode);
+            },
+            _ => {
+                return Err(Violation::new(CLOSE_PROTOCOL_ERROR, format!("unexpected frame with opcode {}", frame.opcode)));
+            }
+        }
+
+        self.message_size += frame.payload.len();
+
+        if self.message_size > self.policy.max_message_size {
+            return Err(Violation::new(CLOSE_MESSAGE_TOO_BIG, format!("message exceeds the limit of {} bytes", self.policy.max_message_size)));
+        }
+
+        let result = match self.opcode {
+            Some(OPCODE_TEXT) => {
+                self.text.extend_from_slice(&frame.payload);
+                self.held_frames.extend_from_slice(&frame.raw);
+
+                if !frame.fin {
+                    return Ok(Vec::new());
+                }
+
+                let text = match std::str::from_utf8(&self.text) {
+                    Ok(text) => { text },
+                    Err(_) => {
+                        return Err(Violation::new(CLOSE_INVALID_PAYLOAD, "text message is not valid UTF-8"));
+                    }
+                };
+
+                if let Some(detection) = detector::detect(&self.policy.detectors, &self.policy.denied_patterns, text) {
+                    return Err(Violation::new(CLOSE_POLICY_VIOLATION, format!("text message triggered {}", detection)));
+                }
+
+                self.text.clear();
+                std::mem::take(&mut self.held_frames)
+            },
+            _ => { frame.raw }
+        };
+
+        if frame.fin {
+            self.opcode = None;
+            self.message_size = 0;
+        }
+
+        return Ok(result);
+    }
+}
+
+/// a close frame with its status code, frames sent to the edge server must be masked; a zero key leaves the
+/// payload as is
+fn create_close_frame(code: u16, reason: &str, masked: bool) -> Vec<u8> {
+    let mut reason = reason.as_bytes().to_vec();
+    reason.truncate(CONTROL_FRAME_MAX_PAYLOAD - 2);
+
+    let mut result = vec![0x80 | OPCODE_CLOSE, (reason.len() + 2) as u8];
+    if masked {
+        result[1] |= 0x80;
+        result.extend_from_slice(&[0, 0, 0, 0]);
+    }
+
+    result.extend_from_slice(&code.to_be_bytes());
+    result.extend_from_slice(&reason);
+
+    return result;
+}
+
+pub fn is_upgrade(request: &http1::Http) -> bool {
+    let upgrade = match http1::find_property(&request.properties, "upgrade") {
+        Some(upgrade) => { upgrade.to_ascii_lowercase().contains("websocket") },
+        None => { false }
+    };
+
+    let connection = match http1::find_property(&request.properties, "connection") {
+        Some(connection) => { connection.to_ascii_lowercase().contains("upgrade") },
+        None => { false }
+    };
+
+    return upgrade && connection;
+}
+
+/// validates the Origin of an upgrade request against the allow-list of its location rule, or the general one
+/// when the rule has none; returns the policy for the messages, None when the upgrade is refused
+pub fn evaluate_upgrade(request: &http1::Http, connaddr: &str, general_config: &configdb::General) -> Option<WebSocketPolicy> {
+    let location_rule = location_rule::get_location_rule(&request.method, &request.location).unwrap_or_default();
+
+    let allowed_origins = match location_rule.websocket_allowed_origins.is_empty() {
+        true => { &general_config.websocket_allowed_origins },
+        false => { &location_rule.websocket_allowed_origins }
+    };
+
+    if !allowed_origins.is_empty() {
+        let origin = http1::find_property(&request.properties, "origin").map(|origin| origin.trim()).unwrap_or_default();
+
+        if !allowed_origins.iter().any(|allowed_origin| allowed_origin.eq_ignore_ascii_case(origin)) {
+            println!("refusing the WebSocket upgrade from {}, origin '{}' is not allowed", connaddr, origin);
+            return None;
+        }
+    }
+
+    let max_frame_size = match general_config.websocket_max_frame_size {
+        0 => { WEBSOCKET_DEFAULT_MAX_FRAME_SIZE },
+        max_frame_size => { max_frame_size }
+    };
+
+    let max_message_size = match (location_rule.websocket_max_message_size, general_config.websocket_max_message_size) {
+        (0, 0) => { WEBSOCKET_DEFAULT_MAX_MESSAGE_SIZE },
+        (0, max_message_size) => { max_message_size },
+        (max_message_size, _) => { max_message_size }
+    };
+
+    return Some(WebSocketPolicy {
+        max_frame_size,
+        max_message_size,
+        messages_per_second: location_rule.websocket_messages_per_second,
+        detectors: location_rule.websocket_detectors,
+        denied_patterns: location_rule.websocket_denied_patterns,
+    });
+}
+
+/// compressed messages cannot be inspected, the extension offers of the client are not passed to the edge
+fn strip_extension_offers(request_head: &[u8]) -> Vec<u8> {
+    return http1::remove_header(request_head, "sec-websocket-extensions");
+}
+
+/// passes a response refusing the upgrade to the client, the connection closes after it
+async fn forward_refusal(conn: &mut server::TcpClient, edge_conn: &mut server::TcpClient, edge_response: &http1::HttpResponse, pending: Vec<u8>) -> Result<(), std::io::Error> {
+    let mut response_head = format!("HTTP/1.1 {} {}\r\n", edge_response.status, edge_response.reason);
+
+    for (name, value) in edge_response.headers.iter() {
+        if ["connection", "keep-alive", "transfer-encoding", "content-length"].contains(&name.to_ascii_lowercase().as_str()) {
+            continue;
+        }
+
+        response_head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    response_head.push_str("Connection: close\r\n\r\n");
+    conn.write_all(response_head.as_bytes()).await?;
+
+    let mut body = http2::EdgeResponseBody::new(edge_response, "GET", pending)?;
+    while let Some(data) = body.next_block(edge_conn).await? {
+        conn.write_all(&data).await?;
+    }
+
+    return Ok(());
+}
+
+async fn close_with_violation(conn: &mut server::TcpClient, edge_conn: &mut server::TcpClient, violation: &Violation, connaddr: &str) {
+    println!("closing the WebSocket connection with {}, {}", connaddr, &violation.reason);
+
+    let _ = conn.write_all(&create_close_frame(violation.code, &violation.reason, false)).await;
+    let _ = edge_conn.write_all(&create_close_frame(CLOSE_GOING_AWAY, "", true)).await;
+}
+
+async fn relay(mut conn: server::TcpClient, mut edge_conn: server::TcpClient, client_pending: Vec<u8>, policy: WebSocketPolicy, connaddr: &str, edgeaddr: &str) {
+    let mut conn_mtu_block = [0_u8; 1500];
+    let mut edge_mtu_block = [0_u8; 1500];
+
+    let mut parser = FrameParser { storage: client_pending, max_frame_size: policy.max_frame_size };
+    let mut inspector = MessageInspector {
+        policy,
+        opcode: None,
+        message_size: 0,
+        text: Vec::new(),
+        held_frames: Vec::new(),
+        window_start: std::time::Instant::now(),
+        window_messages: 0,
+    };
+
+    loop {
+        // frames received along with the handshake are inspected before reading more
+        loop {
+            match parser.next_frame() {
+                Ok(Some(frame)) => {
+                    match inspector.inspect(frame) {
+                        Ok(block) => {
+                            if block.is_empty() {
+                                continue;
+                            }
+
+                            if let Err(err) = edge_conn.write_all(&block).await {
+                                eprintln!("failed to move data from client {} to edge server {}, error: {}; closing the connection", connaddr, edgeaddr, err.to_string());
+                                return;
+                            }
+                        },
+                        Err(violation) => {
+                            close_with_violation(&mut conn, &mut edge_conn, &violation, connaddr).await;
+                            return;
+                        }
+                    }
+                },
+                Ok(None) => {
+                    break;
+                },
+                Err(violation) => {
+                    close_with_violation(&mut conn, &mut edge_conn, &violation, connaddr).await;
+                    return;
+                }
+            }
+        }
+
+        tokio::select! {
+            conn_read = conn.read(&mut conn_mtu_block) => {
+                match conn_read {
+                    Ok(0) => {
+                        println!("client {} closed the connection", connaddr);
+                        return;
+                    },
+                    Ok(len) => {
+                        parser.storage.extend_from_slice(&conn_mtu_block[..len]);
+                    },
+                    Err(err) => {
+                        eprintln!("failed to read from {}, error: {}; closing the connection", connaddr, err.to_string());
+                        return;
+                    }
+                }
+            }
+            edge_read = edge_conn.read(&mut edge_mtu_block) => {
+                match edge_read {
+                    Ok(0) => {
+                        println!("edge {} closed the connection", edgeaddr);
+                        return;
+                    },
+                    Ok(len) => {
+                        if let Err(err) = conn.write_all(&edge_mtu_block[..len]).await {
+                            eprintln!("failed to move data from edge {} to client {}, error: {}; closing the connection", edgeaddr, connaddr, err.to_string());
+                            return;
+                        }
+                    },
+                    Err(err) => {
+                        eprintln!("failed to read from {}, error: {}; closing the connection", edgeaddr, err.to_string());
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// completes the upgrade handshake through the edge server and relays the frames of the connection, the messages
+/// of the client are inspected against the policy; client_pending holds the bytes received past the request head
+pub async fn handler(mut conn: server::TcpClient, mut edge_conn: server::TcpClient, request_head: &[u8], client_pending: Vec<u8>, policy: WebSocketPolicy, connaddr: &str, edgeaddr: &str) {
+    if let Err(err) = edge_conn.write_all(&strip_extension_offers(request_head)).await {
+        eprintln!("failed to move data from client {} to edge server {}, error: {}; closing the connection", connaddr, edgeaddr, err.to_string());
+        return;
+    }
+
+    let (edge_response, pending) = match http2::read_response_head(&mut edge_conn).await {
+        Ok(edge_response) => { edge_response },
+        Err(err) => {
+            eprintln!("failed to read the WebSocket handshake from edge server {}, error: {}; closing the connection", edgeaddr, err.to_string());
+            return;
+        }
+    };
+
+    if edge_response.status != 101 {
+        println!("edge server {} refused the WebSocket upgrade from {} with status {}", edgeaddr, connaddr, edge_response.status);
+
+        if let Err(err) = forward_refusal(&mut conn, &mut edge_conn, &edge_response, pending).await {
+            eprintln!("failed to move data from edge {} to client {}, error: {}; closing the connection", edgeaddr, connaddr, err.to_string());
+        }
+
+        return;
+    }
+
+    let mut response_head = format!("HTTP/1.1 {} {}\r\n", edge_response.status, edge_response.reason);
+    for (name, value) in edge_response.headers.iter() {
+        response_head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response_head.push_str("\r\n");
+
+    let mut response = response_head.into_bytes();
+    response.extend_from_slice(&pending);
+
+    if let Err(err) = conn.write_all(&response).await {
+        eprintln!("failed to move data from edge {} to client {}, error: {}; closing the connection", edgeaddr, connaddr, err.to_string());
+        return;
+    }
+
+    println!("the connection with {} upgraded to WebSocket", connaddr);
+    relay(conn, edge_conn, client_pending, policy, connaddr, edgeaddr).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    fn policy(max_message_size: usize, messages_per_second: usize) -> WebSocketPolicy {
+        return WebSocketPolicy { max_frame_size: 1024, max_message_size, messages_per_second, detectors: vec![detector::SQL_INJECTION.to_string()], denied_patterns: Vec::new() };
+    }
+
+    fn inspector(policy: WebSocketPolicy) -> MessageInspector {
+        return MessageInspector { policy, opcode: None, message_size: 0, text: Vec::new(), held_frames: Vec::new(), window_start: std::time::Instant::now(), window_messages: 0 };
+    }
+
+    fn parser(max_frame_size: usize) -> FrameParser {
+        return FrameParser { storage: Vec::new(), max_frame_size };
+    }
+
+    /// a masked client frame with the length encoded in the shortest form
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut result = vec![(fin as u8) << 7 | opcode];
+
+        if payload.len() < 126 {
+            result.push(0x80 | payload.len() as u8);
+        } else if payload.len() <= u16::MAX as usize {
+            result.push(0x80 | 126);
+            result.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        } else {
+            result.push(0x80 | 127);
+            result.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        }
+
+        result.extend_from_slice(&MASK);
+        result.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ MASK[i % 4]));
+        return result;
+    }
+
+    fn parse(frame_parser: &mut FrameParser, block: &[u8]) -> Result<Option<Frame>, Violation> {
+        frame_parser.storage.extend_from_slice(block);
+        return frame_parser.next_frame();
+    }
+
+    fn violation_code<Value>(result: Result<Value, Violation>) -> Option<u16> {
+        return result.err().map(|violation| violation.code);
+    }
+
+    #[test]
+    fn parses_the_7_16_and_64_bit_lengths() {
+        for length in [5_usize, 126, 300, 70000] {
+            let mut frame_parser = parser(128 * 1024);
+            let payload = vec![b'a'; length];
+            let block = client_frame(true, OPCODE_BINARY, &payload);
+
+            let frame = parse(&mut frame_parser, &block).ok().flatten().unwrap();
+            assert_eq!(frame.payload, payload);
+            assert_eq!(frame.raw, block);
+            assert!(frame_parser.storage.is_empty());
+        }
+    }
+
+    #[test]
+    fn waits_for_a_frame_split_across_reads() {
+        let mut frame_parser = parser(1024);
+        let block = client_frame(true, OPCODE_TEXT, &[b'x'; 200]);
+
+        // the split falls inside the extended length, then inside the mask, then inside the payload
+        assert!(parse(&mut frame_parser, &block[..3]).ok().unwrap().is_none());
+        assert!(parse(&mut frame_parser, &block[3..6]).ok().unwrap().is_none());
+        assert!(parse(&mut frame_parser, &block[6..100]).ok().unwrap().is_none());
+
+        let mut next_block = block[100..].to_vec();
+        next_block.extend(client_frame(true, OPCODE_PING, b""));
+
+        assert_eq!(parse(&mut frame_parser, &next_block).ok().flatten().map(|frame| frame.payload.len()), Some(200));
+        assert_eq!(frame_parser.next_frame().ok().flatten().map(|frame| frame.opcode), Some(OPCODE_PING));
+    }
+
+    #[test]
+    fn refuses_unmasked_frames_and_reserved_bits() {
+        let mut block = client_frame(true, OPCODE_TEXT, b"hi");
+        block[1] &= 0x7f;
+        assert_eq!(violation_code(parse(&mut parser(1024), &block)), Some(CLOSE_PROTOCOL_ERROR));
+
+        let mut block = client_frame(true, OPCODE_TEXT, b"hi");
+        block[0] |= 0x40;
+        assert_eq!(violation_code(parse(&mut parser(1024), &block)), Some(CLOSE_PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn refuses_an_oversized_frame_from_its_header() {
+        let block = client_frame(true, OPCODE_BINARY, &[0; 2000]);
+        assert_eq!(violation_code(parse(&mut parser(1024), &block[..4])), Some(CLOSE_MESSAGE_TOO_BIG));
+    }
+
+    #[test]
+    fn refuses_an_oversized_message_made_of_small_frames() {
+        let mut message_inspector = inspector(policy(100, 0));
+
+        assert!(message_inspector.inspect(Frame { fin: false, opcode: OPCODE_BINARY, payload: vec![0; 60], raw: Vec::new() }).is_ok());
+        assert_eq!(violation_code(message_inspector.inspect(Frame { fin: true, opcode: OPCODE_CONTINUATION, payload: vec![0; 60], raw: Vec::new() })), Some(CLOSE_MESSAGE_TOO_BIG));
+    }
+
+    #[test]
+    fn detects_an_attack_split_across_the_fragments_of_a_text_message() {
+        let mut frame_parser = parser(1024);
+        let mut message_inspector = inspector(policy(1024, 0));
+
+        let first = parse(&mut frame_parser, &client_frame(false, OPCODE_TEXT, b"name=x' uni")).ok().flatten().unwrap();
+        assert_eq!(message_inspector.inspect(first).ok(), Some(Vec::new()));
+
+        // a ping between the fragments passes while the text is held back
+        let ping = client_frame(true, OPCODE_PING, b"");
+        let frame = parse(&mut frame_parser, &ping).ok().flatten().unwrap();
+        assert_eq!(message_inspector.inspect(frame).ok(), Some(ping));
+
+        let last = parse(&mut frame_parser, &client_frame(true, OPCODE_CONTINUATION, b"on select password from users")).ok().flatten().unwrap();
+        assert_eq!(violation_code(message_inspector.inspect(last)), Some(CLOSE_POLICY_VIOLATION));
+    }
+
+    #[test]
+    fn releases_a_clean_fragmented_text_message_whole() {
+        let mut frame_parser = parser(1024);
+        let mut message_inspector = inspector(policy(1024, 0));
+        let first_block = client_frame(false, OPCODE_TEXT, b"hello ");
+        let last_block = client_frame(true, OPCODE_CONTINUATION, b"world");
+
+        let first = parse(&mut frame_parser, &first_block).ok().flatten().unwrap();
+        assert_eq!(message_inspector.inspect(first).ok(), Some(Vec::new()));
+
+        let last = parse(&mut frame_parser, &last_block).ok().flatten().unwrap();
+        assert_eq!(message_inspector.inspect(last).ok(), Some([first_block, last_block].concat()));
+    }
+
+    #[test]
+    fn refuses_a_text_message_that_is_not_utf8() {
+        let mut message_inspector = inspector(policy(1024, 0));
+        let frame = Frame { fin: true, opcode: OPCODE_TEXT, payload: vec![b'a', 0xc3, 0x28], raw: Vec::new() };
+
+        assert_eq!(violation_code(message_inspector.inspect(frame)), Some(CLOSE_INVALID_PAYLOAD));
+    }
+
+    #[test]
+    fn refuses_reserved_opcodes_and_malformed_control_frames() {
+        for opcode in [0x3, 0x7, 0xb, 0xf] {
+            let mut message_inspector = inspector(policy(1024, 0));
+            assert_eq!(violation_code(message_inspector.inspect(Frame { fin: true, opcode, payload: Vec::new(), raw: Vec::new() })), Some(CLOSE_PROTOCOL_ERROR));
+        }
+
+        let mut message_inspector = inspector(policy(1024, 0));
+        assert_eq!(violation_code(message_inspector.inspect(Frame { fin: false, opcode: OPCODE_PING, payload: Vec::new(), raw: Vec::new() })), Some(CLOSE_PROTOCOL_ERROR));
+        assert_eq!(violation_code(message_inspector.inspect(Frame { fin: true, opcode: OPCODE_PONG, payload: vec![0; 126], raw: Vec::new() })), Some(CLOSE_PROTOCOL_ERROR));
+        assert_eq!(violation_code(message_inspector.inspect(Frame { fin: true, opcode: OPCODE_CONTINUATION, payload: Vec::new(), raw: Vec::new() })), Some(CLOSE_PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn limits_the_messages_per_second() {
+        let mut message_inspector = inspector(policy(1024, 2));
+
+        for _ in 0..2 {
+            assert!(message_inspector.inspect(Frame { fin: true, opcode: OPCODE_BINARY, payload: vec![0], raw: Vec::new() }).is_ok());
+        }
+
+        // control frames are not messages
+        assert!(message_inspector.inspect(Frame { fin: true, opcode: OPCODE_PING, payload: Vec::new(), raw: Vec::new() }).is_ok());
+        assert_eq!(violation_code(message_inspector.inspect(Frame { fin: true, opcode: OPCODE_BINARY, payload: vec![0], raw: Vec::new() })), Some(CLOSE_POLICY_VIOLATION));
+
+        message_inspector.window_start -= std::time::Duration::from_secs(1);
+        assert!(message_inspector.inspect(Frame { fin: true, opcode: OPCODE_BINARY, payload: vec![0], raw: Vec::new() }).is_ok());
+    }
+}